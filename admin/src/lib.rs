@@ -53,7 +53,12 @@ pub fn run() -> Result<(), String> {
 	options.sync_wal = !cli.shared().no_sync;
 	options.sync_data = !cli.shared().no_sync;
 	options.stats = cli.shared().with_stats;
-	println!("Options {:?}, {:?}", cli, options);
+	if let SubCommand::Metrics(_) = cli.subcommand {
+		// Keep stdout for the metrics output.
+		eprintln!("Options {:?}, {:?}", cli, options);
+	} else {
+		println!("Options {:?}, {:?}", cli, options);
+	}
 	match cli.subcommand {
 		SubCommand::Stats(stat) => {
 			let db = parity_db::Db::open_read_only(&options)
@@ -65,6 +70,13 @@ pub fn run() -> Result<(), String> {
 				db.collect_stats(&mut out, stat.column.clone());
 			}
 		},
+		SubCommand::Metrics(_) => {
+			let db = parity_db::Db::open_read_only(&options)
+				.map_err(|e| format!("Invalid db: {:?}", e))?;
+			let mut out = std::io::stdout();
+			db.write_metrics(&mut out)
+				.map_err(|e| format!("Error writing metrics: {:?}", e))?;
+		},
 		SubCommand::Migrate(args) => {
 			use parity_db::Options;
 			let dest_meta = Options::load_metadata(&args.dest_meta)
@@ -181,6 +193,8 @@ pub struct Cli {
 pub enum SubCommand {
 	/// Show stats.
 	Stats(Stats),
+	/// Print metrics in OpenMetrics text format.
+	Metrics(Metrics),
 	/// Migrate db (update version or change column options).
 	Migrate(Migrate),
 	/// Run db until all logs are flushed.
@@ -197,6 +211,9 @@ impl Cli {
 			SubCommand::Stats(stats) => {
 				&stats.shared
 			},
+			SubCommand::Metrics(metrics) => {
+				&metrics.shared
+			},
 			SubCommand::Migrate(stats) => {
 				&stats.shared
 			},
@@ -228,6 +245,13 @@ pub struct Stats {
 	pub clear: bool,
 }
 
+/// Print metrics in OpenMetrics text format.
+#[derive(Debug, StructOpt)]
+pub struct Metrics {
+	#[structopt(flatten)]
+	pub shared: Shared,
+}
+
 /// Migrate db (update version or change column options).
 #[derive(Debug, StructOpt)]
 pub struct Migrate {
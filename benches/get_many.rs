@@ -0,0 +1,88 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+// Compares `Db::get_many` with a `Db::get` call per key. Run with `cargo bench --bench get_many`.
+// With `-- --cold`, the page cache is dropped before each measurement. This requires root on Linux.
+
+use std::time::{Duration, Instant};
+use parity_db::{Db, Options, CompressionType};
+use rand::{Rng, SeedableRng};
+
+const KEYS: u32 = 200_000;
+const ROUNDS: usize = 20;
+
+fn key(i: u32) -> [u8; 4] {
+	i.to_le_bytes()
+}
+
+fn drop_page_cache() {
+	std::process::Command::new("sync").status().unwrap();
+	std::fs::write("/proc/sys/vm/drop_caches", "1").expect("Dropping the page cache requires root");
+}
+
+fn main() {
+	let cold = std::env::args().any(|a| a == "--cold");
+	let tmp = tempfile::tempdir().unwrap();
+	let mut options = Options::with_columns(tmp.path(), 2);
+	options.columns[1].compression = CompressionType::Lz4;
+	let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+	{
+		let db = Db::open_or_create(&options).unwrap();
+		for batch in 0 .. KEYS / 10_000 {
+			let commit = (batch * 10_000 .. (batch + 1) * 10_000).flat_map(|i| {
+				let len = 32 + (i % 512) as usize;
+				// Half random, so that values compress to about half their size.
+				let mut value = vec![0u8; len];
+				rng.fill(&mut value[.. len / 2]);
+				[(0, key(i), Some(value.clone())), (1, key(i), Some(value))]
+			}).collect::<Vec<_>>();
+			db.commit(commit).unwrap();
+		}
+		db.flush().unwrap();
+	}
+	let db = Db::open(&options).unwrap();
+	for col in [0, 1] {
+		for batch in [16, 128, 512, 2048] {
+			let mut get = Duration::ZERO;
+			let mut get_many = Duration::ZERO;
+			for _ in 0 .. ROUNDS {
+				let keys: Vec<[u8; 4]> = (0 .. batch).map(|_| key(rng.gen_range(0 .. KEYS))).collect();
+				let query: Vec<(u8, &[u8])> = keys.iter().map(|k| (col, &k[..])).collect();
+				if cold {
+					drop_page_cache();
+				}
+				let start = Instant::now();
+				let values: Vec<_> = keys.iter().map(|k| db.get(col, k).unwrap()).collect();
+				get += start.elapsed();
+				if cold {
+					drop_page_cache();
+				}
+				let start = Instant::now();
+				assert_eq!(db.get_many(&query).unwrap(), values);
+				get_many += start.elapsed();
+			}
+			let keys = (batch * ROUNDS) as u32;
+			println!(
+				"column {} ({}), {} keys: get {:?}/key, get_many {:?}/key",
+				col,
+				if col == 0 { "uncompressed" } else { "lz4" },
+				batch,
+				get / keys,
+				get_many / keys,
+			);
+		}
+	}
+}
@@ -0,0 +1,499 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Ordered key index for btree columns.
+//!
+//! Keys of a btree column are kept in a B-tree. Tree nodes are stored as regular column values
+//! under keys derived from the node id, so node updates go through the same log, overlays and
+//! value tables as any other value. Node 0 holds the root id and the next free node id.
+//! Values themselves are stored under the hashed key, same as for the hash columns.
+
+use std::ops::Bound;
+use crate::error::{Error, Result};
+
+/// Minimum degree. Each node except root holds from `MIN_DEGREE - 1` to `2 * MIN_DEGREE - 1` keys.
+const MIN_DEGREE: usize = 16;
+const MAX_KEYS: usize = 2 * MIN_DEGREE - 1;
+const META_NODE: u64 = 0;
+/// Maximum key length for btree columns.
+pub const MAX_KEY_LEN: usize = 256;
+
+pub trait NodeSource {
+	fn read(&mut self, id: u64) -> Result<Option<Vec<u8>>>;
+}
+
+pub trait NodeStore: NodeSource {
+	fn write(&mut self, id: u64, data: Vec<u8>) -> Result<()>;
+	fn remove(&mut self, id: u64) -> Result<()>;
+}
+
+#[derive(Default, Clone, Debug, PartialEq, Eq)]
+struct Node {
+	keys: Vec<Vec<u8>>,
+	children: Vec<u64>,
+}
+
+impl Node {
+	fn is_leaf(&self) -> bool {
+		self.children.is_empty()
+	}
+
+	fn encode(&self) -> Vec<u8> {
+		let mut data = Vec::new();
+		data.extend_from_slice(&(self.keys.len() as u16).to_le_bytes());
+		data.push(if self.is_leaf() { 1 } else { 0 });
+		for key in &self.keys {
+			data.extend_from_slice(&(key.len() as u16).to_le_bytes());
+			data.extend_from_slice(key);
+		}
+		for child in &self.children {
+			data.extend_from_slice(&child.to_le_bytes());
+		}
+		data
+	}
+
+	fn decode(data: &[u8]) -> Result<Node> {
+		fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+			if data.len() < len {
+				return Err(Error::Corruption("Truncated btree node".into()));
+			}
+			let (head, tail) = data.split_at(len);
+			*data = tail;
+			Ok(head)
+		}
+		let mut data = data;
+		let mut buf = [0u8; 8];
+		buf[0..2].copy_from_slice(take(&mut data, 2)?);
+		let num_keys = u16::from_le_bytes([buf[0], buf[1]]) as usize;
+		let leaf = take(&mut data, 1)?[0] == 1;
+		let mut node = Node::default();
+		for _ in 0 .. num_keys {
+			let len = take(&mut data, 2)?;
+			let len = u16::from_le_bytes([len[0], len[1]]) as usize;
+			node.keys.push(take(&mut data, len)?.to_vec());
+		}
+		if !leaf {
+			for _ in 0 .. num_keys + 1 {
+				buf.copy_from_slice(take(&mut data, 8)?);
+				node.children.push(u64::from_le_bytes(buf));
+			}
+		}
+		Ok(node)
+	}
+}
+
+pub struct BTree<'a, S> {
+	store: &'a mut S,
+	root: u64,
+	next_id: u64,
+	dirty: bool,
+}
+
+impl<'a, S: NodeSource> BTree<'a, S> {
+	pub fn open(store: &'a mut S) -> Result<Self> {
+		let (root, next_id) = match store.read(META_NODE)? {
+			Some(data) if data.len() == 16 => {
+				let mut buf = [0u8; 8];
+				buf.copy_from_slice(&data[0..8]);
+				let root = u64::from_le_bytes(buf);
+				buf.copy_from_slice(&data[8..16]);
+				(root, u64::from_le_bytes(buf))
+			},
+			Some(_) => return Err(Error::Corruption("Bad btree metadata".into())),
+			None => (0, META_NODE + 1),
+		};
+		Ok(BTree { store, root, next_id, dirty: false })
+	}
+
+	/// Ids of all nodes that were allocated, including the metadata node. Some may be free.
+	pub fn node_ids(&self) -> std::ops::Range<u64> {
+		META_NODE .. self.next_id
+	}
+
+	fn read_node(&mut self, id: u64) -> Result<Node> {
+		match self.store.read(id)? {
+			Some(data) => Node::decode(&data),
+			None => Err(Error::Corruption(format!("Missing btree node {}", id))),
+		}
+	}
+
+	/// Find the smallest key that is within the lower bound.
+	pub fn seek_next(&mut self, lower: Bound<&[u8]>) -> Result<Option<Vec<u8>>> {
+		let mut candidate = None;
+		let mut id = self.root;
+		while id != 0 {
+			let mut node = self.read_node(id)?;
+			let n = node.keys.partition_point(|k| match lower {
+				Bound::Included(b) => k.as_slice() < b,
+				Bound::Excluded(b) => k.as_slice() <= b,
+				Bound::Unbounded => false,
+			});
+			if n < node.keys.len() {
+				candidate = Some(node.keys.swap_remove(n));
+			}
+			id = node.children.get(n).cloned().unwrap_or(0);
+		}
+		Ok(candidate)
+	}
+
+	/// Find the largest key that is within the upper bound.
+	pub fn seek_prev(&mut self, upper: Bound<&[u8]>) -> Result<Option<Vec<u8>>> {
+		let mut candidate = None;
+		let mut id = self.root;
+		while id != 0 {
+			let mut node = self.read_node(id)?;
+			let n = node.keys.partition_point(|k| match upper {
+				Bound::Included(b) => k.as_slice() <= b,
+				Bound::Excluded(b) => k.as_slice() < b,
+				Bound::Unbounded => true,
+			});
+			if n > 0 {
+				candidate = Some(node.keys.swap_remove(n - 1));
+			}
+			id = node.children.get(n).cloned().unwrap_or(0);
+		}
+		Ok(candidate)
+	}
+}
+
+impl<'a, S: NodeStore> BTree<'a, S> {
+	fn write_node(&mut self, id: u64, node: &Node) -> Result<()> {
+		self.store.write(id, node.encode())
+	}
+
+	fn alloc(&mut self) -> u64 {
+		let id = self.next_id;
+		self.next_id += 1;
+		self.dirty = true;
+		id
+	}
+
+	fn free(&mut self, id: u64) -> Result<()> {
+		self.store.remove(id)
+	}
+
+	/// Write tree metadata if it was changed.
+	pub fn close(self) -> Result<()> {
+		if self.dirty {
+			let mut data = Vec::with_capacity(16);
+			data.extend_from_slice(&self.root.to_le_bytes());
+			data.extend_from_slice(&self.next_id.to_le_bytes());
+			self.store.write(META_NODE, data)?;
+		}
+		Ok(())
+	}
+
+	pub fn insert(&mut self, key: &[u8]) -> Result<()> {
+		if self.root == 0 {
+			let id = self.alloc();
+			self.write_node(id, &Node { keys: vec![key.to_vec()], children: Vec::new() })?;
+			self.root = id;
+			return Ok(());
+		}
+		let mut id = self.root;
+		let mut node = self.read_node(id)?;
+		if node.keys.len() == MAX_KEYS {
+			let root = self.alloc();
+			let mut new_root = Node { keys: Vec::new(), children: vec![id] };
+			self.split_child(&mut new_root, 0, node)?;
+			self.write_node(root, &new_root)?;
+			self.root = root;
+			id = root;
+			node = new_root;
+		}
+		loop {
+			let mut i = match node.keys.binary_search_by(|k| k.as_slice().cmp(key)) {
+				Ok(_) => return Ok(()),
+				Err(i) => i,
+			};
+			if node.is_leaf() {
+				node.keys.insert(i, key.to_vec());
+				return self.write_node(id, &node);
+			}
+			let child = self.read_node(node.children[i])?;
+			let child = if child.keys.len() == MAX_KEYS {
+				self.split_child(&mut node, i, child)?;
+				self.write_node(id, &node)?;
+				match node.keys[i].as_slice().cmp(key) {
+					std::cmp::Ordering::Equal => return Ok(()),
+					std::cmp::Ordering::Less => i += 1,
+					std::cmp::Ordering::Greater => (),
+				}
+				self.read_node(node.children[i])?
+			} else {
+				child
+			};
+			id = node.children[i];
+			node = child;
+		}
+	}
+
+	// Split full child `i` of the `parent`. Writes both halves, but not the parent.
+	fn split_child(&mut self, parent: &mut Node, i: usize, mut child: Node) -> Result<()> {
+		let right_id = self.alloc();
+		let right = Node {
+			keys: child.keys.split_off(MIN_DEGREE),
+			children: if child.is_leaf() { Vec::new() } else { child.children.split_off(MIN_DEGREE) },
+		};
+		let median = child.keys.pop().expect("Full node has MAX_KEYS keys");
+		parent.keys.insert(i, median);
+		parent.children.insert(i + 1, right_id);
+		self.write_node(parent.children[i], &child)?;
+		self.write_node(right_id, &right)?;
+		Ok(())
+	}
+
+	pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+		if self.root == 0 {
+			return Ok(());
+		}
+		let mut key = key.to_vec();
+		let mut id = self.root;
+		let mut node = self.read_node(id)?;
+		// Every node we descend into has at least `MIN_DEGREE` keys, so that a key can be removed
+		// from it without any further rebalancing up the tree.
+		loop {
+			match node.keys.binary_search(&key) {
+				Ok(i) => {
+					if node.is_leaf() {
+						node.keys.remove(i);
+						self.write_node(id, &node)?;
+						break;
+					}
+					let left_id = node.children[i];
+					let left = self.read_node(left_id)?;
+					if left.keys.len() >= MIN_DEGREE {
+						let pred = self.last_key(&left)?;
+						node.keys[i] = pred.clone();
+						self.write_node(id, &node)?;
+						key = pred;
+						id = left_id;
+						node = left;
+						continue;
+					}
+					let right_id = node.children[i + 1];
+					let right = self.read_node(right_id)?;
+					if right.keys.len() >= MIN_DEGREE {
+						let succ = self.first_key(&right)?;
+						node.keys[i] = succ.clone();
+						self.write_node(id, &node)?;
+						key = succ;
+						id = right_id;
+						node = right;
+						continue;
+					}
+					let merged = self.merge(&mut node, i, left, right)?;
+					self.write_node(id, &node)?;
+					id = left_id;
+					node = merged;
+				},
+				Err(i) => {
+					if node.is_leaf() {
+						break;
+					}
+					let (child_id, child) = self.fill_child(&mut node, i)?;
+					self.write_node(id, &node)?;
+					id = child_id;
+					node = child;
+				},
+			}
+		}
+		// Root may be left empty after a merge.
+		let root = self.read_node(self.root)?;
+		if root.keys.is_empty() {
+			self.free(self.root)?;
+			self.root = root.children.first().cloned().unwrap_or(0);
+			self.dirty = true;
+		}
+		Ok(())
+	}
+
+	// Make sure child `i` has at least `MIN_DEGREE` keys, borrowing from or merging with a sibling.
+	// Writes the children, but not the parent.
+	fn fill_child(&mut self, parent: &mut Node, i: usize) -> Result<(u64, Node)> {
+		let child_id = parent.children[i];
+		let mut child = self.read_node(child_id)?;
+		if child.keys.len() >= MIN_DEGREE {
+			return Ok((child_id, child));
+		}
+		let left = if i > 0 { Some(self.read_node(parent.children[i - 1])?) } else { None };
+		if let Some(mut left) = left.filter(|l| l.keys.len() >= MIN_DEGREE) {
+			let separator = std::mem::replace(&mut parent.keys[i - 1], left.keys.pop().expect("Checked above"));
+			child.keys.insert(0, separator);
+			if let Some(c) = left.children.pop() {
+				child.children.insert(0, c);
+			}
+			self.write_node(parent.children[i - 1], &left)?;
+			self.write_node(child_id, &child)?;
+			return Ok((child_id, child));
+		}
+		if i + 1 < parent.children.len() {
+			let right_id = parent.children[i + 1];
+			let mut right = self.read_node(right_id)?;
+			if right.keys.len() >= MIN_DEGREE {
+				let separator = std::mem::replace(&mut parent.keys[i], right.keys.remove(0));
+				child.keys.push(separator);
+				if !right.is_leaf() {
+					child.children.push(right.children.remove(0));
+				}
+				self.write_node(right_id, &right)?;
+				self.write_node(child_id, &child)?;
+				return Ok((child_id, child));
+			}
+			let merged = self.merge(parent, i, child, right)?;
+			return Ok((child_id, merged));
+		}
+		let left_id = parent.children[i - 1];
+		let left = self.read_node(left_id)?;
+		let merged = self.merge(parent, i - 1, left, child)?;
+		Ok((left_id, merged))
+	}
+
+	// Merge children `i` and `i + 1` together with the separating key into child `i`.
+	fn merge(&mut self, parent: &mut Node, i: usize, mut left: Node, mut right: Node) -> Result<Node> {
+		left.keys.push(parent.keys.remove(i));
+		left.keys.append(&mut right.keys);
+		left.children.append(&mut right.children);
+		let right_id = parent.children.remove(i + 1);
+		self.free(right_id)?;
+		self.write_node(parent.children[i], &left)?;
+		Ok(left)
+	}
+
+	fn last_key(&mut self, node: &Node) -> Result<Vec<u8>> {
+		let mut child = node.children.last().cloned();
+		let mut key = node.keys.last().cloned();
+		while let Some(id) = child {
+			let node = self.read_node(id)?;
+			child = node.children.last().cloned();
+			key = node.keys.last().cloned();
+		}
+		key.ok_or_else(|| Error::Corruption("Empty btree node".into()))
+	}
+
+	fn first_key(&mut self, node: &Node) -> Result<Vec<u8>> {
+		let mut child = node.children.first().cloned();
+		let mut key = node.keys.first().cloned();
+		while let Some(id) = child {
+			let node = self.read_node(id)?;
+			child = node.children.first().cloned();
+			key = node.keys.first().cloned();
+		}
+		key.ok_or_else(|| Error::Corruption("Empty btree node".into()))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::{BTreeSet, HashMap};
+	use std::ops::Bound;
+	use super::{BTree, NodeSource, NodeStore, Node, MIN_DEGREE};
+	use crate::error::Result;
+
+	#[derive(Default)]
+	struct MemStore(HashMap<u64, Vec<u8>>);
+
+	impl NodeSource for MemStore {
+		fn read(&mut self, id: u64) -> Result<Option<Vec<u8>>> {
+			Ok(self.0.get(&id).cloned())
+		}
+	}
+
+	impl NodeStore for MemStore {
+		fn write(&mut self, id: u64, data: Vec<u8>) -> Result<()> {
+			self.0.insert(id, data);
+			Ok(())
+		}
+
+		fn remove(&mut self, id: u64) -> Result<()> {
+			assert!(self.0.remove(&id).is_some());
+			Ok(())
+		}
+	}
+
+	fn key(i: u32) -> Vec<u8> {
+		// Spread keys around the key space.
+		(i.wrapping_mul(2654435761)).to_be_bytes().to_vec()
+	}
+
+	fn collect(store: &mut MemStore) -> Vec<Vec<u8>> {
+		let mut tree = BTree::open(store).unwrap();
+		let mut result = Vec::new();
+		let mut last: Option<Vec<u8>> = None;
+		while let Some(k) = tree.seek_next(last.as_ref().map_or(Bound::Unbounded, |k| Bound::Excluded(k.as_slice()))).unwrap() {
+			result.push(k.clone());
+			last = Some(k);
+		}
+		result
+	}
+
+	#[test]
+	fn node_encoding() {
+		let node = Node { keys: vec![vec![1, 2], vec![], vec![3]], children: vec![5, 6, 7, 8] };
+		assert_eq!(Node::decode(&node.encode()).unwrap(), node);
+		let leaf = Node { keys: vec![vec![1, 2]], children: vec![] };
+		assert_eq!(Node::decode(&leaf.encode()).unwrap(), leaf);
+		assert!(Node::decode(&node.encode()[..10]).is_err());
+	}
+
+	#[test]
+	fn insert_remove() {
+		let mut store = MemStore::default();
+		let mut expected = BTreeSet::new();
+		let count = (MIN_DEGREE * MIN_DEGREE * 8) as u32;
+		for i in 0 .. count {
+			let mut tree = BTree::open(&mut store).unwrap();
+			tree.insert(&key(i)).unwrap();
+			tree.insert(&key(i / 2)).unwrap();
+			tree.close().unwrap();
+			expected.insert(key(i));
+		}
+		assert_eq!(collect(&mut store), expected.iter().cloned().collect::<Vec<_>>());
+
+		{
+			let mut tree = BTree::open(&mut store).unwrap();
+			let (first, last) = (expected.iter().next().unwrap(), expected.iter().next_back().unwrap());
+			assert_eq!(tree.seek_next(Bound::Unbounded).unwrap().as_ref(), Some(first));
+			assert_eq!(tree.seek_prev(Bound::Unbounded).unwrap().as_ref(), Some(last));
+			assert_eq!(tree.seek_next(Bound::Excluded(last)).unwrap(), None);
+			assert_eq!(tree.seek_prev(Bound::Excluded(first)).unwrap(), None);
+			let probe = key(count / 3);
+			assert_eq!(tree.seek_next(Bound::Included(&probe)).unwrap(), Some(probe.clone()));
+			assert_eq!(
+				tree.seek_prev(Bound::Excluded(&probe)).unwrap().as_ref(),
+				expected.range(..probe.clone()).next_back(),
+			);
+		}
+
+		for i in (0 .. count).filter(|i| i % 3 != 0) {
+			let mut tree = BTree::open(&mut store).unwrap();
+			tree.remove(&key(i)).unwrap();
+			tree.remove(&key(count + i)).unwrap();
+			tree.close().unwrap();
+			expected.remove(&key(i));
+		}
+		assert_eq!(collect(&mut store), expected.iter().cloned().collect::<Vec<_>>());
+
+		for i in 0 .. count {
+			let mut tree = BTree::open(&mut store).unwrap();
+			tree.remove(&key(i)).unwrap();
+			tree.close().unwrap();
+		}
+		assert!(collect(&mut store).is_empty());
+		// Only the metadata node is left.
+		assert_eq!(store.0.len(), 1);
+	}
+}
@@ -0,0 +1,218 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+// Cache of decoded column values, enabled with `ColumnOptions::cache_bytes`.
+//
+// Values are looked up by the original key, so cache hits skip key hashing as well as table
+// reads. Entries are invalidated by the hashed key when a commit touching the key is queued or
+// logged. A value read from the tables is only inserted if nothing was invalidated since the
+// lookup, so a concurrent commit can't leave a stale value behind.
+//
+// Eviction uses the CLOCK algorithm: hits only set the entry reference bit under a shared lock,
+// and the clock hand evicts the first entry that was not referenced since the hand last passed it.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use parking_lot::RwLock;
+use crate::{column::ColId, table::{Key, Value}};
+
+// Approximate memory used by an entry in addition to the key and value.
+const ENTRY_OVERHEAD: usize = 128;
+
+pub enum Lookup {
+	Disabled,
+	Hit(Value),
+	// Cache epoch at the time of the lookup, passed to `ValueCache::insert`.
+	Miss(u64),
+}
+
+struct Entry {
+	key: Vec<u8>,
+	value: Value,
+	// Position in the clock.
+	slot: usize,
+	referenced: AtomicBool,
+}
+
+impl Entry {
+	fn size(&self) -> usize {
+		self.key.len() + self.value.len() + ENTRY_OVERHEAD
+	}
+}
+
+#[derive(Default)]
+struct CacheState {
+	bytes: usize,
+	entries: HashMap<Key, Entry>,
+	hashes: HashMap<Vec<u8>, Key>,
+	// Hashed keys in clock order. Slots of removed entries are reused.
+	clock: Vec<Option<Key>>,
+	free: Vec<usize>,
+	hand: usize,
+	// Incremented on each invalidation.
+	epoch: u64,
+}
+
+impl CacheState {
+	fn insert(&mut self, max_bytes: usize, key: &[u8], hash: Key, value: &[u8]) {
+		let size = key.len() + value.len() + ENTRY_OVERHEAD;
+		if size > max_bytes {
+			return;
+		}
+		while self.bytes + size > max_bytes {
+			self.evict();
+		}
+		let slot = match self.free.pop() {
+			Some(slot) => slot,
+			None => {
+				self.clock.push(None);
+				self.clock.len() - 1
+			},
+		};
+		self.clock[slot] = Some(hash);
+		self.bytes += size;
+		self.hashes.insert(key.to_vec(), hash);
+		self.entries.insert(hash, Entry { key: key.to_vec(), value: value.to_vec(), slot, referenced: AtomicBool::new(false) });
+	}
+
+	// Must not be called on an empty cache.
+	fn evict(&mut self) {
+		loop {
+			let slot = self.hand;
+			self.hand = (self.hand + 1) % self.clock.len();
+			if let Some(hash) = self.clock[slot] {
+				let entry = self.entries.get_mut(&hash).expect("Clock slots point to cached entries");
+				// Entries read since the last pass get another round.
+				if !std::mem::replace(entry.referenced.get_mut(), false) {
+					self.remove(&hash);
+					return;
+				}
+			}
+		}
+	}
+
+	fn remove(&mut self, hash: &Key) {
+		if let Some(entry) = self.entries.remove(hash) {
+			self.bytes -= entry.size();
+			self.hashes.remove(&entry.key);
+			self.clock[entry.slot] = None;
+			self.free.push(entry.slot);
+		}
+	}
+}
+
+pub struct ValueCache {
+	col: ColId,
+	max_bytes: usize,
+	state: RwLock<CacheState>,
+}
+
+impl ValueCache {
+	pub fn new(col: ColId, max_bytes: usize) -> ValueCache {
+		ValueCache {
+			col,
+			max_bytes,
+			state: Default::default(),
+		}
+	}
+
+	pub fn get(&self, key: &[u8]) -> Lookup {
+		let state = self.state.read();
+		match state.hashes.get(key).and_then(|hash| state.entries.get(hash)) {
+			Some(entry) => {
+				entry.referenced.store(true, Ordering::Relaxed);
+				Lookup::Hit(entry.value.clone())
+			},
+			None => Lookup::Miss(state.epoch),
+		}
+	}
+
+	pub fn insert(&self, epoch: u64, key: &[u8], hash: Key, value: &[u8]) {
+		let mut state = self.state.write();
+		if epoch != state.epoch || state.entries.contains_key(&hash) {
+			return;
+		}
+		state.insert(self.max_bytes, key, hash, value);
+	}
+
+	pub fn invalidate(&self, hash: &Key) {
+		let mut state = self.state.write();
+		state.epoch += 1;
+		state.remove(hash);
+	}
+
+	pub fn clear(&self) {
+		let mut state = self.state.write();
+		let epoch = state.epoch + 1;
+		*state = CacheState { epoch, ..Default::default() };
+		log::debug!(target: "parity-db", "{}: Cleared value cache", self.col);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn hash(k: u8) -> Key {
+		[k; 32]
+	}
+
+	fn hit(cache: &ValueCache, key: &[u8]) -> Option<Value> {
+		match cache.get(key) {
+			Lookup::Hit(value) => Some(value),
+			_ => None,
+		}
+	}
+
+	#[test]
+	fn test_eviction() {
+		let cache = ValueCache::new(0, 3 * (ENTRY_OVERHEAD + 2));
+		cache.insert(0, b"a", hash(1), &[1]);
+		cache.insert(0, b"b", hash(2), &[2]);
+		cache.insert(0, b"c", hash(3), &[3]);
+		assert_eq!(hit(&cache, b"a"), Some(vec![1]));
+		// Evicts "b", the first entry that was not read.
+		cache.insert(0, b"d", hash(4), &[4]);
+		assert_eq!(hit(&cache, b"b"), None);
+		assert_eq!(hit(&cache, b"a"), Some(vec![1]));
+		assert_eq!(hit(&cache, b"d"), Some(vec![4]));
+		assert_eq!(cache.state.read().bytes, 3 * (ENTRY_OVERHEAD + 2));
+		// Too large to be cached.
+		cache.insert(0, b"e", hash(5), &[0; 4 * ENTRY_OVERHEAD]);
+		assert_eq!(hit(&cache, b"e"), None);
+	}
+
+	#[test]
+	fn test_invalidate() {
+		let cache = ValueCache::new(0, 1024);
+		cache.insert(0, b"a", hash(1), &[1]);
+		let epoch = match cache.get(b"b") {
+			Lookup::Miss(epoch) => epoch,
+			_ => panic!("Unexpected hit"),
+		};
+		cache.invalidate(&hash(1));
+		assert_eq!(hit(&cache, b"a"), None);
+		// Values read before the invalidation are not inserted.
+		cache.insert(epoch, b"b", hash(2), &[2]);
+		assert_eq!(hit(&cache, b"b"), None);
+		let epoch = cache.state.read().epoch;
+		cache.insert(epoch, b"b", hash(2), &[2]);
+		assert_eq!(hit(&cache, b"b"), Some(vec![2]));
+		cache.clear();
+		assert_eq!(hit(&cache, b"b"), None);
+		assert_eq!(cache.state.read().bytes, 0);
+	}
+}
@@ -19,18 +19,31 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use parking_lot::RwLock;
 use crate::{
 	error::{Error, Result},
-	table::{TableId as ValueTableId, ValueTable, Key, Value},
-	log::{Log, LogOverlays, LogReader, LogWriter, LogAction},
+	table::{TableId as ValueTableId, ValueTable, ValueStream, Key, Value},
+	log::{Log, LogQuery, LogReader, LogWriter, LogAction},
+	btree::{BTree, NodeSource, NodeStore},
+	transaction::{Condition, Operation},
 	display::hex,
 	index::{IndexTable, TableId as IndexTableId, PlanOutcome, Address},
 	options::{Options, ColumnOptions, Metadata},
-	stats::ColumnStats,
+	stats::{ColumnStats, ColumnStatsSnapshot, Latency, LatencyHistogram, PipelineStats},
 	db::check::CheckDisplay,
 };
 use crate::compress::Compress;
+use crate::cache::{Lookup, ValueCache};
+use crate::filter::Filter;
 
 const START_BITS: u8 = 16;
-const MAX_REBALANCE_BATCH: usize = 8192;
+// Value reads in `get_many` are split between up to this many threads, so that reads that miss
+// the page cache are waited for concurrently.
+const MAX_READ_THREADS: usize = 4;
+// Minimum number of value reads per thread in `get_many`.
+const MIN_THREAD_READS: usize = 128;
+// Starting with this version, compressed values are prefixed with their uncompressed size.
+const SIZE_PREFIX_VERSION: u32 = 6;
+const SIZE_PREFIX_LEN: usize = 4;
+/// Number of chunks the key space is split into for iteration.
+pub const ITER_CHUNKS: u64 = 1 << START_BITS;
 
 pub type ColId = u8;
 pub type Salt = [u8; 32];
@@ -53,10 +66,14 @@ pub struct Column {
 	uniform_keys: bool,
 	collect_stats: bool,
 	ref_counted: bool,
+	btree_index: bool,
 	salt: Salt,
 	stats: ColumnStats,
 	compression: Compress,
 	db_version: u32,
+	rebalance_batch: usize,
+	cache: Option<ValueCache>,
+	filter: Option<Filter>,
 }
 
 pub struct IterState {
@@ -64,6 +81,8 @@ pub struct IterState {
 	pub key: Key,
 	pub rc: u32,
 	pub value: Vec<u8>,
+	/// The entry is a node of the btree index and not a value.
+	pub btree_node: bool,
 }
 
 enum IterStateOrCorrupted {
@@ -71,45 +90,346 @@ enum IterStateOrCorrupted {
 	Corrupted(crate::index::Entry, Option<Error>),
 }
 
+// Read access to the btree nodes of a column.
+struct TreeReader<'a, Q> {
+	column: &'a Column,
+	log: &'a Q,
+}
+
+impl<'a, Q: LogQuery> NodeSource for TreeReader<'a, Q> {
+	fn read(&mut self, id: u64) -> Result<Option<Vec<u8>>> {
+		Ok(self.column.get_value(&self.column.btree_node_key(id), self.log)?.map(|(_, _, v)| v))
+	}
+}
+
+// Writes btree node changes to the log.
+struct TreeWriter<'a, 'b> {
+	column: &'a Column,
+	log: &'a mut LogWriter<'b>,
+	reindex: bool,
+}
+
+impl<'a, 'b> TreeWriter<'a, 'b> {
+	fn write_node(&mut self, id: u64, op: Operation) -> Result<()> {
+		let key = self.column.btree_node_key(id);
+		// Nodes are not counted as values in the column stats.
+		if let PlanOutcome::NeedReindex = self.column.write_plan_inner(&key, &op, self.log, false)? {
+			self.reindex = true;
+		}
+		Ok(())
+	}
+}
+
+impl<'a, 'b> NodeSource for TreeWriter<'a, 'b> {
+	fn read(&mut self, id: u64) -> Result<Option<Vec<u8>>> {
+		Ok(self.column.get_value(&self.column.btree_node_key(id), &*self.log)?.map(|(_, _, v)| v))
+	}
+}
+
+impl<'a, 'b> NodeStore for TreeWriter<'a, 'b> {
+	fn write(&mut self, id: u64, data: Vec<u8>) -> Result<()> {
+		self.write_node(id, Operation::Set(data))
+	}
+
+	fn remove(&mut self, id: u64) -> Result<()> {
+		self.write_node(id, Operation::Delete)
+	}
+}
+
 impl Column {
-	pub fn get(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<Option<Value>> {
+	pub fn get(&self, key: &Key, log: &impl LogQuery) -> Result<Option<Value>> {
+		Ok(self.get_with_rc(key, log)?.map(|(value, _)| value))
+	}
+
+	/// Query the value along with its reference count. The count is always 1 for columns that
+	/// are not reference counted.
+	pub fn get_with_rc(&self, key: &Key, log: &impl LogQuery) -> Result<Option<(Value, u32)>> {
+		match self.get_value(key, log)? {
+			Some((tier, rc, value)) => {
+				if self.collect_stats {
+					self.stats.query_hit(tier);
+				}
+				Ok(Some((value, rc)))
+			},
+			None => {
+				if self.collect_stats {
+					self.stats.query_miss();
+				}
+				Ok(None)
+			},
+		}
+	}
+
+	/// Look up a value in the value cache by the original key.
+	pub fn cache_lookup(&self, key: &[u8]) -> Lookup {
+		let lookup = match &self.cache {
+			Some(cache) => cache.get(key),
+			None => return Lookup::Disabled,
+		};
+		if self.collect_stats {
+			match lookup {
+				Lookup::Hit(_) => self.stats.cache_hit(),
+				_ => self.stats.cache_miss(),
+			}
+		}
+		lookup
+	}
+
+	/// Cache a value read after a `Lookup::Miss`.
+	pub fn cache_insert(&self, epoch: u64, key: &[u8], hash: Key, value: &[u8]) {
+		if let Some(cache) = &self.cache {
+			cache.insert(epoch, key, hash, value);
+		}
+	}
+
+	pub fn cache_invalidate(&self, hash: &Key) {
+		if let Some(cache) = &self.cache {
+			cache.invalidate(hash);
+		}
+	}
+
+	pub fn is_cached(&self) -> bool {
+		self.cache.is_some()
+	}
+
+	fn may_contain(&self, key: &Key) -> bool {
+		self.filter.as_ref().is_none_or(|filter| filter.may_contain(key))
+	}
+
+	fn filter_path(&self, col: ColId) -> std::path::PathBuf {
+		self.path.join(format!("filter_{:02}", col))
+	}
+
+	/// Load the saved key filter or rebuild it from the index tables. Must be called once the
+	/// logs are replayed.
+	pub fn init_filter(&self, log: &Log) -> Result<()> {
+		let filter = match &self.filter {
+			Some(filter) => filter,
+			None => return Ok(()),
+		};
 		let tables = self.tables.read();
-		if let Some((tier, value)) = self.get_in_index(key, &tables.index, &*tables, log)? {
-			if self.collect_stats {
-				self.stats.query_hit(tier);
+		let reindex = self.reindex.read();
+		if !filter.load(&self.filter_path(tables.index.id.col()))? {
+			let mut keys: u64 = 0;
+			for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+				for chunk in 0 .. index.id.total_chunks() {
+					for entry in index.entries(chunk, log.overlays()).iter().filter(|e| !e.is_empty()) {
+						filter.insert(&index.recover_key_prefix(chunk, *entry));
+						keys += 1;
+					}
+				}
 			}
-			return Ok(Some(value));
+			log::debug!(target: "parity-db", "{}: Built key filter with {} entries", tables.index.id, keys);
 		}
-		for r in &self.reindex.read().queue {
-			if let Some((tier, value)) = self.get_in_index(key, &r, &*tables, log)? {
-				if self.collect_stats {
-					self.stats.query_hit(tier);
+		filter.set_ready();
+		Ok(())
+	}
+
+	pub fn save_filter(&self) -> Result<()> {
+		if let Some(filter) = &self.filter {
+			filter.save(&self.filter_path(self.tables.read().index.id.col()))?;
+		}
+		Ok(())
+	}
+
+	/// Check a commit precondition against the current state of the column.
+	pub fn check_condition(&self, key: &Key, condition: &Condition, log: &impl LogQuery) -> Result<bool> {
+		let current = self.get_value(key, log)?;
+		Ok(match condition {
+			Condition::Missing => current.is_none(),
+			Condition::Equals(expected) => matches!(current, Some((_, _, value)) if value == *expected),
+			Condition::RefCount(expected) => current.map_or(0, |(_, rc, _)| rc) == *expected,
+		})
+	}
+
+	fn get_value(&self, key: &Key, log: &impl LogQuery) -> Result<Option<(u8, u32, Value)>> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		self.get_in_tables(key, &tables, &reindex, log)
+	}
+
+	fn get_in_tables(&self, key: &Key, tables: &Tables, reindex: &Reindex, log: &impl LogQuery) -> Result<Option<(u8, u32, Value)>> {
+		if !self.may_contain(key) {
+			return Ok(None);
+		}
+		if let Some(r) = self.get_in_index(key, &tables.index, tables, log)? {
+			return Ok(Some(r));
+		}
+		for r in &reindex.queue {
+			if let Some(r) = self.get_in_index(key, r, tables, log)? {
+				return Ok(Some(r));
+			}
+		}
+		Ok(None)
+	}
+
+	/// Query multiple keys at once. Locks are only taken once. Values are read in the order of
+	/// their location in the value tables. Large batches are read by a few threads.
+	pub fn get_many(&self, keys: &[Key], log: &(impl LogQuery + Sync)) -> Result<Vec<Option<Value>>> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		// (tier, offset, key index) for the first index entry of each key.
+		let mut reads = Vec::with_capacity(keys.len());
+		for (i, key) in keys.iter().enumerate().filter(|(_, key)| self.may_contain(key)) {
+			let (entry, _) = tables.index.get(key, 0, log);
+			if !entry.is_empty() {
+				let address = entry.address(tables.index.id.index_bits());
+				reads.push((address.size_tier(), address.offset(), i));
+			}
+		}
+		reads.sort_unstable();
+		// Key index, tier, reference count and value.
+		type ReadValue = (usize, u8, u32, Value);
+		let read = |reads: &[(u8, u64, usize)]| -> Result<Vec<ReadValue>> {
+			let mut values = Vec::with_capacity(reads.len());
+			for (tier, offset, i) in reads {
+				let table = &tables.value[*tier as usize];
+				if let Some((value, rc, compressed)) = table.get_with_rc(&keys[*i], *offset, log)? {
+					let value = if compressed { self.decompress(&value, table.id, *offset)? } else { value };
+					values.push((*i, *tier, rc, value));
 				}
-				return Ok(Some(value));
 			}
+			Ok(values)
+		};
+		let threads = (reads.len() / MIN_THREAD_READS).clamp(1, MAX_READ_THREADS);
+		let read_values = if threads > 1 {
+			// Each thread reads a contiguous range of the sorted locations.
+			let batch = reads.len().div_ceil(threads);
+			let read = &read;
+			std::thread::scope(|scope| {
+				let handles: Vec<_> = reads.chunks(batch).map(|batch| scope.spawn(move || read(batch))).collect();
+				let mut values = Vec::with_capacity(reads.len());
+				for handle in handles {
+					values.extend(handle.join().expect("Value read thread panicked")?);
+				}
+				Ok::<_, Error>(values)
+			})?
+		} else {
+			read(&reads)?
+		};
+
+		let mut values = vec![None; keys.len()];
+		for (i, tier, rc, value) in read_values {
+			values[i] = Some((tier, rc, value));
 		}
-		if self.collect_stats {
-			self.stats.query_miss();
+		let mut result = Vec::with_capacity(keys.len());
+		for (key, value) in keys.iter().zip(values) {
+			// Collisions and tables that are being reindexed are handled with a full lookup.
+			let value = match value {
+				Some(value) => Some(value),
+				None => self.get_in_tables(key, &tables, &reindex, log)?,
+			};
+			if self.collect_stats {
+				match &value {
+					Some((tier, _, _)) => self.stats.query_hit(*tier),
+					None => self.stats.query_miss(),
+				}
+			}
+			result.push(value.map(|(_, _, v)| v));
+		}
+		Ok(result)
+	}
+
+	/// Query the uncompressed value size without reading the value.
+	pub fn get_size(&self, key: &Key, log: &impl LogQuery) -> Result<Option<u32>> {
+		self.find_value(key, log, |table, offset| {
+			Ok(self.value_size(table, key, offset, log)?.map(|(_, size)| size))
+		})
+	}
+
+	/// Check if the key exists. Only the index and the value entry header are read.
+	pub fn contains(&self, key: &Key, log: &impl LogQuery) -> Result<bool> {
+		Ok(self.find_value(key, log, |table, offset| {
+			Ok(table.has_key_at(offset, key, log)?.then_some(()))
+		})?.is_some())
+	}
+
+	/// Query a byte range of the value. The range is truncated to the value size.
+	/// For uncompressed values only the parts that cover the range are read.
+	pub fn get_range(&self, key: &Key, range: std::ops::Range<usize>, log: &impl LogQuery) -> Result<Option<Value>> {
+		self.find_value(key, log, |table, offset| {
+			Ok(match table.get_range(key, offset, log, range.clone())? {
+				Some((value, true)) => {
+					let value = self.decompress(&value, table.id, offset)?;
+					let end = range.end.min(value.len());
+					Some(value[range.start.min(end)..end].to_vec())
+				},
+				Some((value, false)) => Some(value),
+				None => None,
+			})
+		})
+	}
+
+	/// Locate the value. Return the value table tier, entry index and if the value is compressed.
+	pub fn value_location(&self, key: &Key, log: &impl LogQuery) -> Result<Option<(u8, u64, bool)>> {
+		self.find_value(key, log, |table, offset| {
+			Ok(table.size(key, offset, log, &mut [])?.map(|(_, compressed)| (table.id.size_tier(), offset, compressed)))
+		})
+	}
+
+	/// Read a single part of an uncompressed value. See `ValueTable::read_part`.
+	pub fn read_part(&self, tier: u8, index: u64, key: Option<&Key>, log: &impl LogQuery, dest: &mut Vec<u8>) -> Result<Option<u64>> {
+		self.tables.read().value[tier as usize].read_part(key, index, log, dest)
+	}
+
+	// Query value tables for each index entry that matches the key, until `f` returns a result.
+	fn find_value<R>(
+		&self,
+		key: &Key,
+		log: &impl LogQuery,
+		f: impl Fn(&ValueTable, u64) -> Result<Option<R>>,
+	) -> Result<Option<R>> {
+		if !self.may_contain(key) {
+			return Ok(None);
+		}
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			let (mut entry, mut sub_index) = index.get(key, 0, log);
+			while !entry.is_empty() {
+				let address = entry.address(index.id.index_bits());
+				if let Some(r) = f(&tables.value[address.size_tier() as usize], address.offset())? {
+					return Ok(Some(r));
+				}
+				let (next_entry, next_index) = index.get(key, sub_index + 1, log);
+				entry = next_entry;
+				sub_index = next_index;
+			}
 		}
 		Ok(None)
 	}
 
-	pub fn get_size(&self, key: &Key, log: &RwLock<LogOverlays>) -> Result<Option<u32>> {
-		self.get(key, log).map(|v| v.map(|v| v.len() as u32))
+	// Return stored and uncompressed size of the value.
+	fn value_size(&self, table: &ValueTable, key: &Key, offset: u64, log: &impl LogQuery) -> Result<Option<(u32, u32)>> {
+		let mut head = [0u8; SIZE_PREFIX_LEN];
+		match table.size(key, offset, log, &mut head)? {
+			Some((size, false)) => Ok(Some((size, size))),
+			Some((size, true)) if self.db_version >= SIZE_PREFIX_VERSION => {
+				Ok(Some((size, u32::from_le_bytes(head))))
+			},
+			Some((size, true)) => {
+				// Older databases don't store the uncompressed size. This is very costly.
+				let compressed = table.get(key, offset, log)?
+					.ok_or_else(|| Error::Corruption(format!("Missing value in table {} at {}", table.id, offset)))?.0;
+				Ok(Some((size, self.decompress(&compressed, table.id, offset)?.len() as u32)))
+			},
+			None => Ok(None),
+		}
 	}
 
-	fn get_in_index(&self, key: &Key, index: &IndexTable, tables: &Tables, log: &RwLock<LogOverlays>) -> Result<Option<(u8, Value)>> {
+	fn get_in_index(&self, key: &Key, index: &IndexTable, tables: &Tables, log: &impl LogQuery) -> Result<Option<(u8, u32, Value)>> {
 		let (mut entry, mut sub_index) = index.get(key, 0, log);
 		while !entry.is_empty() {
 			let size_tier = entry.address(index.id.index_bits()).size_tier() as usize;
-			match tables.value[size_tier].get(key, entry.address(index.id.index_bits()).offset(), log)? {
-				Some((value, compressed)) => {
+			let offset = entry.address(index.id.index_bits()).offset();
+			match tables.value[size_tier].get_with_rc(key, offset, log)? {
+				Some((value, rc, compressed)) => {
 					let value = if compressed {
-						self.decompress(&value)
+						self.decompress(&value, tables.value[size_tier].id, offset)?
 					} else {
 						value
 					};
-					return Ok(Some((size_tier as u8, value)));
+					return Ok(Some((size_tier as u8, rc, value)));
 				}
 				None =>  {
 					let (next_entry, next_index) = index.get(key, sub_index + 1, log);
@@ -123,12 +443,20 @@ impl Column {
 
 	/// Compress if needed and return the target tier to use.
 	fn compress(&self, key: &Key, value: &[u8], tables: &Tables) -> (Option<Vec<u8>>, usize) {
-		Self::compress_internal(&self.compression, key, value, tables)
+		Self::compress_internal(&self.compression, key, value, tables, self.db_version)
 	}
 
-	fn compress_internal(compression: &Compress, key: &Key, value: &[u8], tables: &Tables) -> (Option<Vec<u8>>, usize) {
+	fn compress_internal(compression: &Compress, key: &Key, value: &[u8], tables: &Tables, db_version: u32) -> (Option<Vec<u8>>, usize) {
 		let (len, result) = if value.len() > compression.treshold as usize {
-			let cvalue = compression.compress(value);
+			let cvalue = if db_version >= SIZE_PREFIX_VERSION {
+				let compressed = compression.compress(value);
+				let mut cvalue = Vec::with_capacity(SIZE_PREFIX_LEN + compressed.len());
+				cvalue.extend_from_slice(&(value.len() as u32).to_le_bytes());
+				cvalue.extend_from_slice(&compressed);
+				cvalue
+			} else {
+				compression.compress(value)
+			};
 			if cvalue.len() < value.len() {
 				(cvalue.len(), Some(cvalue))
 			} else {
@@ -137,33 +465,45 @@ impl Column {
 		} else {
 			(value.len(), None)
 		};
-		let target_tier = tables.value.iter().position(|t| len <= t.value_size() as usize);
-		let target_tier = match target_tier {
-			Some(tier) => tier as usize,
+		(result, Self::target_tier(key, len, tables))
+	}
+
+	fn target_tier(key: &Key, len: usize, tables: &Tables) -> usize {
+		match tables.value.iter().position(|t| len <= t.value_size() as usize) {
+			Some(tier) => tier,
 			None => {
 				log::trace!(target: "parity-db", "Using blob {}", hex(key));
 				tables.value.len() - 1
 			}
-		};
-
-		(result, target_tier)
+		}
 	}
 
-	fn decompress(&self, buf: &[u8]) -> Vec<u8> {
-		self.compression.decompress(buf)
+	// Decompress the value stored in `table` at `offset`. The location is only used for error reporting.
+	fn decompress(&self, buf: &[u8], table: ValueTableId, offset: u64) -> Result<Vec<u8>> {
+		let data = if self.db_version >= SIZE_PREFIX_VERSION {
+			buf.get(SIZE_PREFIX_LEN..)
+		} else {
+			Some(buf)
+		};
+		data.ok_or_else(|| "Missing size prefix".to_string())
+			.and_then(|data| self.compression.decompress(data))
+			.map_err(|e| Error::Corruption(format!("Bad compressed value in table {} at {}: {}", table, offset, e)))
 	}
 
 	pub fn open(col: ColId, options: &Options, metadata: &Metadata) -> Result<Column> {
 		let (index, reindexing, stats) = Self::open_index(&options.path, col)?;
 		let collect_stats = options.stats;
+		let rebalance_batch = options.pipeline.max_rebalance_batch;
 		let path = &options.path;
 		let arc_path = std::sync::Arc::new(path.clone());
 		let options = &metadata.columns[col as usize];
 		let db_version = metadata.version;
+		let read_latency = if collect_stats { Some(stats.read_latency()) } else { None };
 		let tables = Tables {
 			index,
 			value: (0.. options.sizes.len() + 1)
-				.map(|i| Self::open_table(arc_path.clone(), col, i as u8, &options, db_version)).collect::<Result<_>>()?
+				.map(|i| Self::open_table(arc_path.clone(), col, i as u8, options, db_version, read_latency.clone()))
+				.collect::<Result<_>>()?
 		};
 
 		Ok(Column {
@@ -176,11 +516,15 @@ impl Column {
 			preimage: options.preimage,
 			uniform_keys: options.uniform,
 			ref_counted: options.ref_counted,
+			btree_index: options.btree_index,
 			collect_stats,
 			salt: metadata.salt.clone(),
 			stats,
 			compression: Compress::new(options.compression, options.compression_treshold),
 			db_version,
+			rebalance_batch,
+			cache: (options.cache_bytes != 0).then(|| ValueCache::new(col, options.cache_bytes)),
+			filter: (options.filter_bytes != 0).then(|| Filter::new(options.filter_bytes)),
 		})
 	}
 
@@ -194,6 +538,60 @@ impl Column {
 		k
 	}
 
+	pub fn is_btree(&self) -> bool {
+		self.btree_index
+	}
+
+	// Btree nodes are stored as values under keys derived from the salt and node id.
+	fn btree_node_key(&self, id: u64) -> Key {
+		let mut data = [0u8; 32 + 5 + 8];
+		data[0..32].copy_from_slice(&self.salt);
+		data[32..37].copy_from_slice(b"btree");
+		data[37..].copy_from_slice(&id.to_le_bytes());
+		let mut k = Key::default();
+		k.copy_from_slice(blake2_rfc::blake2b::blake2b(32, &[], &data).as_bytes());
+		k
+	}
+
+	// Keys of all allocated btree nodes. Empty for hash columns.
+	fn btree_node_keys(&self, log: &impl LogQuery) -> Result<std::collections::HashSet<Key>> {
+		if !self.btree_index {
+			return Ok(Default::default());
+		}
+		let mut reader = TreeReader { column: self, log };
+		let tree = BTree::open(&mut reader)?;
+		Ok(tree.node_ids().map(|id| self.btree_node_key(id)).collect())
+	}
+
+	/// Find the smallest key in the btree that is within the lower bound.
+	pub fn btree_next(&self, lower: std::ops::Bound<&[u8]>, log: &impl LogQuery) -> Result<Option<Vec<u8>>> {
+		let mut reader = TreeReader { column: self, log };
+		BTree::open(&mut reader)?.seek_next(lower)
+	}
+
+	/// Find the largest key in the btree that is within the upper bound.
+	pub fn btree_prev(&self, upper: std::ops::Bound<&[u8]>, log: &impl LogQuery) -> Result<Option<Vec<u8>>> {
+		let mut reader = TreeReader { column: self, log };
+		BTree::open(&mut reader)?.seek_prev(upper)
+	}
+
+	/// Write the value under the hashed key and update the btree with the original key.
+	pub fn write_btree_plan(&self, key: &[u8], op: &Operation, log: &mut LogWriter) -> Result<PlanOutcome> {
+		let outcome = self.write_plan(&self.hash(key), op, log)?;
+		let mut writer = TreeWriter { column: self, log, reindex: false };
+		let mut tree = BTree::open(&mut writer)?;
+		if op.value().is_some() {
+			tree.insert(key)?;
+		} else {
+			tree.remove(key)?;
+		}
+		tree.close()?;
+		if writer.reindex {
+			return Ok(PlanOutcome::NeedReindex);
+		}
+		Ok(outcome)
+	}
+
 	pub fn flush(&self) -> Result<()> {
 		let tables = self.tables.read();
 		tables.index.flush()?;
@@ -231,10 +629,11 @@ impl Column {
 		tier: u8,
 		options: &ColumnOptions,
 		db_version: u32,
+		read_latency: Option<std::sync::Arc<LatencyHistogram>>,
 	) -> Result<ValueTable> {
 		let id = ValueTableId::new(col, tier);
 		let entry_size = options.sizes.get(tier as usize).cloned();
-		ValueTable::open(path, id, entry_size, options, db_version)
+		ValueTable::open(path, id, entry_size, options, db_version, read_latency)
 	}
 
 	fn trigger_reindex(
@@ -259,13 +658,73 @@ impl Column {
 		reindex.queue.push_back(old_table);
 	}
 
+	/// Write a streamed value. Streamed values are not compressed.
+	pub fn write_stream_plan(&self, key: &Key, value: &mut ValueStream, log: &mut LogWriter) -> Result<PlanOutcome> {
+		let tables = self.tables.upgradable_read();
+		let reindex = self.reindex.upgradable_read();
+		let len = value.len();
+		let target_tier = Self::target_tier(key, len, &tables);
+		if let Some((table, sub_index, existing_tier, existing_address)) = Self::search_all_indexes(key, &tables, &reindex, log)? {
+			if self.preimage {
+				return Ok(PlanOutcome::Skipped);
+			}
+			let existing_tier = existing_tier as usize;
+			if self.collect_stats {
+				let (cur_size, uncompressed) = self.value_size(&tables.value[existing_tier], key, existing_address.offset(), log)?
+					.unwrap_or((0, 0));
+				self.stats.replace_val(cur_size, uncompressed, len as u32, len as u32);
+			}
+			if existing_tier == target_tier {
+				log::trace!(target: "parity-db", "{}: Replacing streamed {}", tables.index.id, hex(key));
+				tables.value[target_tier].write_replace_stream_plan(existing_address.offset(), key, value, log)?;
+				return Ok(PlanOutcome::Written);
+			}
+			log::trace!(target: "parity-db", "{}: Replacing streamed in a new table {}", tables.index.id, hex(key));
+			tables.value[existing_tier].write_remove_plan(existing_address.offset(), log)?;
+			let new_offset = tables.value[target_tier].write_insert_stream_plan(key, value, log)?;
+			let new_address = Address::new(new_offset, target_tier as u8);
+			let sub_index = if table.id == tables.index.id { Some(sub_index) } else { None };
+			return self.write_index_plan(&tables.index, key, new_address, sub_index, log);
+		}
+		log::trace!(target: "parity-db", "{}: Inserting streamed {}, size = {}", tables.index.id, hex(key), len);
+		let offset = tables.value[target_tier].write_insert_stream_plan(key, value, log)?;
+		let address = Address::new(offset, target_tier as u8);
+		if self.collect_stats {
+			self.stats.insert_val(len as u32, len as u32);
+		}
+		match self.write_index_plan(&tables.index, key, address, None, log)? {
+			PlanOutcome::NeedReindex => {
+				log::debug!(target: "parity-db", "{}: Index chunk full {}", tables.index.id, hex(key));
+				Self::trigger_reindex(tables, reindex, self.path.as_path());
+				self.write_reindex_plan(key, address, log)?;
+				Ok(PlanOutcome::NeedReindex)
+			}
+			_ => Ok(PlanOutcome::Written),
+		}
+	}
+
+	// All index insertions go through here to keep the key filter up to date.
+	fn write_index_plan(
+		&self,
+		index: &IndexTable,
+		key: &Key,
+		address: Address,
+		sub_index: Option<usize>,
+		log: &mut LogWriter,
+	) -> Result<PlanOutcome> {
+		if let Some(filter) = &self.filter {
+			filter.insert(key);
+		}
+		index.write_insert_plan(key, address, sub_index, log)
+	}
+
 	pub fn write_reindex_plan(&self, key: &Key, address: Address, log: &mut LogWriter) -> Result<PlanOutcome> {
 		let tables = self.tables.upgradable_read();
 		let reindex = self.reindex.upgradable_read();
 		if Self::search_index(key, &tables.index, &*tables, log)?.is_some() {
 			return Ok(PlanOutcome::Skipped);
 		}
-		match tables.index.write_insert_plan(key, address, None, log)? {
+		match self.write_index_plan(&tables.index, key, address, None, log)? {
 			PlanOutcome::NeedReindex => {
 				log::debug!(target: "parity-db", "{}: Index chunk full {}", tables.index.id, hex(key));
 				Self::trigger_reindex(tables, reindex, self.path.as_path());
@@ -318,12 +777,16 @@ impl Column {
 			Ok(None)
 	}
 
-	pub fn write_plan(&self, key: &Key, value: &Option<Value>, log: &mut LogWriter) -> Result<PlanOutcome> {
+	pub fn write_plan(&self, key: &Key, op: &Operation, log: &mut LogWriter) -> Result<PlanOutcome> {
+		self.write_plan_inner(key, op, log, self.collect_stats)
+	}
+
+	fn write_plan_inner(&self, key: &Key, op: &Operation, log: &mut LogWriter, collect_stats: bool) -> Result<PlanOutcome> {
 		//TODO: return sub-chunk position in index.get
 		let tables = self.tables.upgradable_read();
 		let reindex = self.reindex.upgradable_read();
 		let existing = Self::search_all_indexes(key, &*tables, &*reindex, log)?;
-		if let &Some(ref val) = value {
+		if let Operation::Set(val) = op {
 			if let Some((table, sub_index, existing_tier, existing_address)) = existing {
 				let existing_tier = existing_tier as usize;
 				if self.ref_counted {
@@ -340,19 +803,10 @@ impl Column {
 					.map(|cval| (cval.as_slice(), true))
 					.unwrap_or((val.as_slice(), false));
 
-				if self.collect_stats {
-					let (cur_size, compressed) = tables.value[existing_tier].size(&key, existing_address.offset(), log)?
-						.unwrap_or((0, false));
-					if compressed {
-						// This is very costly.
-						let compressed = tables.value[existing_tier].get(&key, existing_address.offset(), log)?
-							.expect("Same query as size").0;
-						let uncompressed = self.decompress(compressed.as_slice());
-
-						self.stats.replace_val(cur_size, uncompressed.len() as u32, val.len() as u32, cval.len() as u32);
-					} else {
-						self.stats.replace_val(cur_size, cur_size, val.len() as u32, cval.len() as u32);
-					}
+				if collect_stats {
+					let (cur_size, uncompressed) = self.value_size(&tables.value[existing_tier], key, existing_address.offset(), log)?
+						.unwrap_or((0, 0));
+					self.stats.replace_val(cur_size, uncompressed, val.len() as u32, cval.len() as u32);
 				}
 				if existing_tier == target_tier {
 					log::trace!(target: "parity-db", "{}: Replacing {}", tables.index.id, hex(key));
@@ -365,7 +819,7 @@ impl Column {
 					let new_address = Address::new(new_offset, target_tier as u8);
 					// If it was found in an older index we just insert a new entry. Reindex won't overwrite it.
 					let sub_index = if table.id == tables.index.id { Some(sub_index) } else { None };
-					return tables.index.write_insert_plan(key, new_address, sub_index, log);
+					return self.write_index_plan(&tables.index, key, new_address, sub_index, log);
 				}
 			} else {
 				let (cval, target_tier) = self.compress(&key, &val, &*tables);
@@ -376,38 +830,35 @@ impl Column {
 				log::trace!(target: "parity-db", "{}: Inserting new index {}, size = {}", tables.index.id, hex(key), cval.len());
 				let offset = tables.value[target_tier].write_insert_plan(key, &cval, log, compressed)?;
 				let address = Address::new(offset, target_tier as u8);
-				match tables.index.write_insert_plan(key, address, None, log)? {
+				match self.write_index_plan(&tables.index, key, address, None, log)? {
 					PlanOutcome::NeedReindex => {
 						log::debug!(target: "parity-db", "{}: Index chunk full {}", tables.index.id, hex(key));
 						Self::trigger_reindex(tables, reindex, self.path.as_path());
-						self.write_plan(key, value, log)?;
+						self.write_plan_inner(key, op, log, collect_stats)?;
 						return Ok(PlanOutcome::NeedReindex);
 					}
 					_ => {
-						if self.collect_stats {
+						if collect_stats {
 							self.stats.insert_val(val.len() as u32, cval.len() as u32);
 						}
 						return Ok(PlanOutcome::Written);
 					}
 				}
 			}
+		} else if let Operation::Reference = op {
+			if let Some((_, _, existing_tier, existing_address)) = existing {
+				log::trace!(target: "parity-db", "{}: Increment ref {}", tables.index.id, hex(key));
+				tables.value[existing_tier as usize].write_inc_ref(existing_address.offset(), log)?;
+				return Ok(PlanOutcome::Written);
+			}
+			log::trace!(target: "parity-db", "{}: Reference missed {}", tables.index.id, hex(key));
 		} else {
 			if let Some((table, sub_index, existing_tier, existing_address)) = existing {
 				// Deletion
 				let existing_tier = existing_tier as usize;
-				let cur_size = if self.collect_stats {
-					let (cur_size, compressed) = tables.value[existing_tier].size(&key, existing_address.offset(), log)?
-						.unwrap_or((0, false));
-					Some(if compressed {
-						// This is very costly.
-						let compressed = tables.value[existing_tier].get(&key, existing_address.offset(), log)?
-							.expect("Same query as size").0;
-						let uncompressed = self.decompress(compressed.as_slice());
-
-						(cur_size, uncompressed.len() as u32)
-					} else {
-						(cur_size, cur_size)
-					})
+				let cur_size = if collect_stats {
+					Some(self.value_size(&tables.value[existing_tier], key, existing_address.offset(), log)?
+						.unwrap_or((0, 0)))
 				} else {
 					None
 				};
@@ -429,7 +880,7 @@ impl Column {
 				return Ok(PlanOutcome::Written);
 			}
 			log::trace!(target: "parity-db", "{}: Deletion missed {}", tables.index.id, hex(key));
-			if self.collect_stats {
+			if collect_stats {
 				self.stats.remove_miss();
 			}
 		}
@@ -518,12 +969,75 @@ impl Column {
 		self.stats.write_summary(writer, tables.index.id.col());
 	}
 
+	pub fn record_latency(&self, latency: Latency, elapsed: std::time::Duration) {
+		if self.collect_stats {
+			self.stats.latency(latency, elapsed);
+		}
+	}
+
+	pub fn stats(&self, pipeline: PipelineStats) -> ColumnStatsSnapshot {
+		self.stats.snapshot(pipeline)
+	}
+
+	#[cfg(feature = "metrics")]
+	pub fn metrics(&self, pipeline: PipelineStats) -> crate::metrics::ColumnMetrics {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		let (reindex_progress, reindex_chunks) = reindex.queue.front().map_or((0, 0), |source|
+			(reindex.progress.load(Ordering::Relaxed), source.id.total_chunks())
+		);
+		crate::metrics::ColumnMetrics {
+			col: tables.index.id.col(),
+			stats: self.stats.snapshot(pipeline),
+			index_bits: tables.index.id.index_bits(),
+			reindex_tables: reindex.queue.len(),
+			reindex_progress,
+			reindex_chunks,
+			tables: tables.value.iter().filter_map(|t| t.metrics()).collect(),
+		}
+	}
+
 	pub fn clear_stats(&self) {
 		let tables = self.tables.read();
 		let empty_stats = ColumnStats::empty();
 		tables.index.write_stats(&empty_stats);
 	}
 
+	/// Remove all keys and values. Index and value table files are deleted and replaced with
+	/// empty ones, so this does not depend on the number of entries in the column. The column is
+	/// emptied before any file is deleted. If deleting fails, the first error is returned and the
+	/// remaining files are deleted when the clear is replayed from the log.
+	pub fn clear(&self) -> Result<()> {
+		let mut tables = self.tables.write();
+		let mut reindex = self.reindex.write();
+		let col = tables.index.id.col();
+		let index = std::mem::replace(
+			&mut tables.index,
+			IndexTable::create_new(&self.path, IndexTableId::new(col, START_BITS)),
+		);
+		let dropped: Vec<IndexTable> = std::iter::once(index).chain(reindex.queue.drain(..)).collect();
+		reindex.progress.store(0, Ordering::Relaxed);
+		self.stats.clear();
+		if let Some(cache) = &self.cache {
+			cache.clear();
+		}
+		if let Some(filter) = &self.filter {
+			filter.clear();
+		}
+		let mut result = Ok(());
+		for table in tables.value.iter() {
+			result = result.and(table.clear());
+		}
+		for index in dropped {
+			result = result.and(index.drop_file());
+		}
+		match &result {
+			Ok(()) => log::info!(target: "parity-db", "Cleared column {}", col),
+			Err(e) => log::warn!(target: "parity-db", "Error clearing column {}: {}", col, e),
+		}
+		result
+	}
+
 	pub fn iter_while(&self, log: &Log, mut f: impl FnMut(IterState) -> bool) -> Result<()> {
 		let action = |state | match state {
 			IterStateOrCorrupted::Item(item) => Ok(f(item)),
@@ -539,6 +1053,7 @@ impl Column {
 		start_chunk: u64,
 		skip_preimage_indexes: bool,
 	) -> Result<()> {
+		let btree_nodes = self.btree_node_keys(log.overlays())?;
 		let tables = self.tables.read();
 		let source = &tables.index;
 
@@ -547,17 +1062,28 @@ impl Column {
 			// We have to assume hashing scheme however.
 			for table in &tables.value[..tables.value.len() - 1] {
 				log::debug!( target: "parity-db", "{}: Iterating table {}", source.id, table.id);
+				let mut error = None;
 				table.iter_while(&*log.overlays(), |index, rc, value, compressed| {
 					let value = if compressed {
-						self.decompress(&value)
+						match self.decompress(&value, table.id, index) {
+							Ok(value) => value,
+							Err(e) => {
+								error = Some(e);
+								return false;
+							},
+						}
 					} else {
 						value
 					};
 					let key = blake2_rfc::blake2b::blake2b(32, &[], &value);
 					let key = self.hash(key.as_bytes());
-					let state = IterStateOrCorrupted::Item(IterState { chunk_index: index, key, rc, value });
+					let btree_node = btree_nodes.contains(&key);
+					let state = IterStateOrCorrupted::Item(IterState { chunk_index: index, key, rc, value, btree_node });
 					f(state).unwrap_or(false)
 				})?;
+				if let Some(e) = error {
+					return Err(e);
+				}
 				log::debug!( target: "parity-db", "{}: Done Iterating table {}", source.id, table.id);
 			}
 		}
@@ -597,7 +1123,7 @@ impl Column {
 				let mut key = source.recover_key_prefix(c, *entry);
 				key[6..].copy_from_slice(&pk);
 				let value = if compressed {
-						self.decompress(&value)
+					self.decompress(&value, tables.value[size_tier as usize].id, offset)?
 				} else {
 					value
 				};
@@ -610,7 +1136,8 @@ impl Column {
 					hex(&key),
 					hex(&pk),
 				);
-				let state = IterStateOrCorrupted::Item(IterState { chunk_index: c, key, rc, value });
+				let btree_node = btree_nodes.contains(&key);
+				let state = IterStateOrCorrupted::Item(IterState { chunk_index: c, key, rc, value, btree_node });
 				if !f(state)? {
 					return Ok(())
 				}
@@ -619,6 +1146,93 @@ impl Column {
 		Ok(())
 	}
 
+	/// Collect all entries that fall into the given iteration chunk. The key space is split into
+	/// `ITER_CHUNKS` chunks, independently of the current index size, so that a chunk position
+	/// stays valid while the index is being reindexed. Entries are collected from the index
+	/// and from all tables that are still being reindexed.
+	pub fn iter_chunk(&self, chunk: u64, log: &Log) -> Vec<Result<(Key, Value, u32)>> {
+		let tables = self.tables.read();
+		let reindex = self.reindex.read();
+		// Hold the overlay lock so that index and value lookups observe the same log state.
+		let overlays = log.overlays().read();
+		let overlays = &*overlays;
+		let mut result = Vec::new();
+		let sources = std::iter::once(&tables.index).chain(reindex.queue.iter().rev());
+		for (n, source) in sources.enumerate() {
+			let shift = source.id.index_bits() - START_BITS;
+			for c in chunk << shift .. (chunk + 1) << shift {
+				for entry in source.entries(c, overlays).iter() {
+					if entry.is_empty() {
+						continue;
+					}
+					let address = entry.address(source.id.index_bits());
+					let value = tables.value[address.size_tier() as usize].get_with_meta(address.offset(), overlays);
+					let (value, rc, pk, compressed) = match value {
+						Ok(Some(v)) => v,
+						// Entries in the tables that are being reindexed may point to removed values.
+						Ok(None) if n > 0 => continue,
+						Ok(None) => {
+							result.push(Err(Error::Corruption("Missing indexed value".into())));
+							continue;
+						},
+						Err(e) => {
+							result.push(Err(e));
+							continue;
+						},
+					};
+					let mut key = source.recover_key_prefix(c, *entry);
+					key[6..].copy_from_slice(&pk);
+					if n > 0 {
+						// Skip entries that have been moved or replaced in a newer index.
+						match self.search_address(&key, &tables, &reindex, overlays) {
+							Some((id, a)) if id == source.id && a == address => (),
+							_ => continue,
+						}
+					}
+					let value = if compressed {
+						match self.decompress(&value, tables.value[address.size_tier() as usize].id, address.offset()) {
+							Ok(value) => value,
+							Err(e) => {
+								result.push(Err(e));
+								continue;
+							},
+						}
+					} else {
+						value
+					};
+					result.push(Ok((key, value, rc)));
+				}
+			}
+		}
+		result
+	}
+
+	// Find the index table and the value address for the key, following the same lookup order as `get`.
+	fn search_address(
+		&self,
+		key: &Key,
+		tables: &Tables,
+		reindex: &Reindex,
+		log: &impl LogQuery,
+	) -> Option<(IndexTableId, Address)> {
+		for index in std::iter::once(&tables.index).chain(reindex.queue.iter()) {
+			let (mut entry, mut sub_index) = index.get(key, 0, log);
+			while !entry.is_empty() {
+				let address = entry.address(index.id.index_bits());
+				let pk = tables.value[address.size_tier() as usize].partial_key_at(address.offset(), log);
+				if let Ok(Some(pk)) = pk {
+					if pk[..] == key[6..] {
+						return Some((index.id, address));
+					}
+				}
+				let (next_entry, next_index) = index.get(key, sub_index + 1, log);
+				entry = next_entry;
+				sub_index = next_index;
+			}
+		}
+		None
+	}
+
 	pub(crate) fn check_from_index(&self, log: &Log, check_param: &crate::CheckOptions, col: ColId) -> Result<()> {
 		let start_chunk = check_param.from.unwrap_or(0);
 		let end_chunk = check_param.bound;
@@ -628,7 +1242,7 @@ impl Column {
 		log::info!(target: "parity-db", "Starting full index iteration at {:?}", start_time);
 		log::info!(target: "parity-db", "for {} chunks of column {}", self.tables.read().index.id.total_chunks(), col);
 		self.iter_while_inner(log, |state| match state {
-			IterStateOrCorrupted::Item(IterState { chunk_index, key, rc, value }) => {
+			IterStateOrCorrupted::Item(IterState { chunk_index, key, rc, value, btree_node }) => {
 				if Some(chunk_index) == end_chunk {
 					return Ok(false);
 				}
@@ -636,6 +1250,7 @@ impl Column {
 					log::info!(target: "parity-db", "Chunk iteration at {}", chunk_index);
 				}
 
+				let kind = if btree_node { "Btree node" } else { "Value" };
 				match check_param.display {
 					CheckDisplay::Full => {
 						log::info!("Index key: {:x?}\n \
@@ -643,12 +1258,12 @@ impl Column {
 							&key,
 							rc,
 						);
-						log::info!("Value: {}", hex(&value));
+						log::info!("{}: {}", kind, hex(&value));
 					},
 					CheckDisplay::Short(t) => {
 						log::info!("Index key: {:x?}", &key);
-						log::info!("Rc: {}, Value len: {}", rc, value.len());
-						log::info!("Value: {}", hex(&value[..std::cmp::min(t as usize, value.len())]));
+						log::info!("Rc: {}, {} len: {}", rc, kind, value.len());
+						log::info!("{}: {}", kind, hex(&value[..std::cmp::min(t as usize, value.len())]));
 					},
 					CheckDisplay::None => (),
 				}
@@ -678,7 +1293,7 @@ impl Column {
 					log::debug!(target: "parity-db", "{}: Reindexing at {}/{}", tables.index.id, source_index, source.id.total_chunks());
 				}
 				log::debug!(target: "parity-db", "{}: Continue reindex at {}/{}", tables.index.id, source_index, source.id.total_chunks());
-				while source_index < source.id.total_chunks() && plan.len() < MAX_REBALANCE_BATCH {
+				while source_index < source.id.total_chunks() && plan.len() < self.rebalance_batch {
 					log::trace!(target: "parity-db", "{}: Reindexing {}", source.id, source_index);
 					let entries = source.entries(source_index, &*log.overlays());
 					for entry in entries.iter() {
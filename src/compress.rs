@@ -17,6 +17,8 @@
 
 //! Compression utility and types.
 
+use crate::error::{Error, Result};
+
 /// Different compression type
 /// allowend and their u8 representation.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -48,13 +50,15 @@ enum Compressor {
 	Snappy(snappy::Snappy),
 }
 
-impl From<u8> for CompressionType {
-	fn from(comp_type: u8) -> Self {
+impl TryFrom<u8> for CompressionType {
+	type Error = Error;
+
+	fn try_from(comp_type: u8) -> Result<Self> {
 		match comp_type {
-			a if a == CompressionType::NoCompression as u8 => CompressionType::NoCompression,
-			a if a == CompressionType::Lz4 as u8 => CompressionType::Lz4,
-			a if a == CompressionType::Snappy as u8 => CompressionType::Snappy,
-			_ => panic!("Unkwown compression."),
+			a if a == CompressionType::NoCompression as u8 => Ok(CompressionType::NoCompression),
+			a if a == CompressionType::Lz4 as u8 => Ok(CompressionType::Lz4),
+			a if a == CompressionType::Snappy as u8 => Ok(CompressionType::Snappy),
+			_ => Err(Error::InvalidConfiguration(format!("Unknown compression type {}", comp_type))),
 		}
 	}
 }
@@ -94,9 +98,10 @@ impl Compress {
 		}
 	}
 
-	pub(crate) fn decompress(&self, buf: &[u8]) -> Vec<u8> {
+	/// Fails with the reason if the data is not valid for the compression type.
+	pub(crate) fn decompress(&self, buf: &[u8]) -> std::result::Result<Vec<u8>, String> {
 		match &self.inner {
-			Compressor::NoCompression(inner) => inner.decompress(buf),
+			Compressor::NoCompression(inner) => Ok(inner.decompress(buf)),
 			Compressor::Lz4(inner) => inner.decompress(buf),
 			Compressor::Snappy(inner) => inner.decompress(buf),
 			#[allow(unreachable_patterns)]
@@ -130,9 +135,9 @@ mod lz4 {
 				.unwrap()
 		}
 
-		pub(super) fn decompress(&self, buf: &[u8]) -> Vec<u8> {
+		pub(super) fn decompress(&self, buf: &[u8]) -> Result<Vec<u8>, String> {
 			lz4::block::decompress(buf, None)
-				.unwrap()
+				.map_err(|e| format!("Bad lz4 data: {}", e))
 		}
 	}
 }
@@ -156,11 +161,12 @@ mod snappy {
 			buf
 		}
 
-		pub(super) fn decompress(&self, value: &[u8]) -> Vec<u8> {
+		pub(super) fn decompress(&self, value: &[u8]) -> Result<Vec<u8>, String> {
 			let mut buf = Vec::with_capacity(value.len());
 			let mut decoder = snap::read::FrameDecoder::new(value);
-			decoder.read_to_end(&mut buf).expect("Compression corrupted.");
-			buf
+			decoder.read_to_end(&mut buf)
+				.map_err(|e| format!("Bad snappy data: {}", e))?;
+			Ok(buf)
 		}
 	}
 }
@@ -182,8 +188,19 @@ mod tests {
 			let compress = Compress::new(compression_type, 0);
 			let v = compress.compress(&original[..]);
 			assert!(v.len() <= 100);
-			let round_tripped = compress.decompress( &v[..]);
+			let round_tripped = compress.decompress( &v[..]).unwrap();
 			assert_eq!(original, round_tripped);
 		}
 	}
+
+	#[test]
+	fn test_corrupted_data() {
+		for compression_type in [CompressionType::Snappy, CompressionType::Lz4] {
+			let compress = Compress::new(compression_type, 0);
+			let mut v = compress.compress(&[42; 100]);
+			v.truncate(v.len() / 2);
+			assert!(compress.decompress(&v).is_err());
+		}
+		assert!(matches!(CompressionType::try_from(3), Err(Error::InvalidConfiguration(_))));
+	}
 }
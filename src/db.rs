@@ -32,31 +32,35 @@
 
 use std::sync::{Arc, atomic::{AtomicBool, AtomicU64, Ordering}};
 use std::convert::TryInto;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::Bound;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 use parking_lot::{RwLock, Mutex, Condvar};
 use fs2::FileExt;
 use crate::{
-	table::Key,
+	table::{Key, ValueStream},
 	error::{Error, Result},
-	column::{ColId, Column, IterState},
+	column::{ColId, Column, IterState, ITER_CHUNKS},
 	log::{Log, LogAction},
+	btree::MAX_KEY_LEN,
+	transaction::{Condition, Operation, Transaction},
 	index::PlanOutcome,
-	options::{Metadata, Options},
+	display::hex,
+	options::{ColumnOptions, Metadata, Options},
+	subscription::{Change, ChangeFilter, ChangeSet, Subscribers},
+	stats::{ColumnStatsSnapshot, Latency, PipelineStats},
+	cache::Lookup,
 };
 
-// These are in memory, so we use usize
-const MAX_COMMIT_QUEUE_BYTES: usize = 16 * 1024 * 1024;
-// These are disk-backed, so we use u64
-const MAX_LOG_QUEUE_BYTES: i64 = 128 * 1024 * 1024;
-const MIN_LOG_SIZE: u64 = 64 * 1024 * 1024;
-const KEEP_LOGS: usize = 16;
 
 /// Value is just a vector of bytes. Value sizes up to 4Gb are allowed.
 pub type Value = Vec<u8>;
 
 
 // Commit data passed to `commit`
-#[derive(Default)]
 struct Commit {
 	// Commit ID. This is not the same as log record id, as some records
 	// are originated within the DB. E.g. reindex.
@@ -65,10 +69,62 @@ struct Commit {
 	// removal (keys)
 	bytes: usize,
 	// Operations.
-	changeset: Vec<(ColId, Key, Option<Value>)>,
+	changeset: CommitChangeSet,
+	// Flush the log as soon as the commit is written.
+	sync: bool,
+	// Set when the commit conditions fail.
+	rejected: Option<Arc<AtomicBool>>,
+	// Time the commit was queued.
+	queued: Instant,
+}
+
+#[derive(Default)]
+struct CommitChangeSet {
+	// Operations on hashed keys.
+	indexed: Vec<(ColId, Key, Operation)>,
+	// Operations on btree columns, with original keys.
+	btree_indexed: Vec<(ColId, Vec<u8>, Operation)>,
+	// Preconditions on hashed keys.
+	conditions: Vec<(ColId, Key, Condition)>,
+	// Streamed values on hashed keys.
+	streams: Vec<(ColId, Key, ValueStream)>,
+	// Columns to clear.
+	cleared: Vec<ColId>,
+	// Changes with original keys, collected when there are subscribers.
+	changes: Option<Vec<Change>>,
+}
+
+impl CommitChangeSet {
+	// Modified columns.
+	fn columns(&self) -> Vec<ColId> {
+		let mut columns: Vec<ColId> = self.indexed.iter().map(|(c, _, _)| *c)
+			.chain(self.btree_indexed.iter().map(|(c, _, _)| *c))
+			.chain(self.streams.iter().map(|(c, _, _)| *c))
+			.chain(self.cleared.iter().cloned())
+			.collect();
+		columns.sort_unstable();
+		columns.dedup();
+		columns
+	}
+
+	// Hashed keys written by the commit.
+	fn keys<'a>(&'a self, columns: &'a [Column]) -> impl Iterator<Item = (ColId, Key)> + 'a {
+		self.indexed.iter().map(|(c, k, _)| (*c, *k))
+			.chain(self.btree_indexed.iter().map(move |(c, k, _)| (*c, columns[*c as usize].hash(k))))
+			.chain(self.streams.iter().map(|(c, k, _)| (*c, *k)))
+	}
+}
+
+// Queued commit with conditions, streamed values or column clears that is not visible yet.
+struct PendingCommit {
+	// Keys that are checked or written by the commit. Commits that write any of them wait
+	// until the commit is processed.
+	keys: HashSet<(ColId, Key)>,
+	// Cleared columns. Commits to these columns wait until the clear is enacted.
+	cleared: Vec<ColId>,
 }
 
-// Pending commits. This may not grow beyond `MAX_COMMIT_QUEUE_BYTES` bytes.
+// Pending commits. This may not grow beyond `PipelineOptions::max_commit_queue_bytes` bytes.
 #[derive(Default)]
 struct CommitQueue {
 	// Log record.
@@ -77,6 +133,109 @@ struct CommitQueue {
 	bytes: usize,
 	// FIFO queue.
 	commits: VecDeque<Commit>,
+	// Async commits waiting for the queue to shrink.
+	wakers: Wakers<()>,
+	// Queued commits with conditions, streamed values or column clears, by commit id.
+	pending: BTreeMap<u64, PendingCommit>,
+}
+
+// Progress of the queued commits through the background workers.
+#[derive(Default)]
+struct CommitProgress {
+	// Last commit written to the log.
+	logged: u64,
+	// Last commit written to disk.
+	durable: u64,
+	// Last commit enacted into the tables.
+	enacted: u64,
+	// Commits that are not enacted yet.
+	records: VecDeque<LoggedCommit>,
+	// Async waiters, woken once the commit reaches the stage.
+	wakers: Wakers<(CommitStage, u64)>,
+}
+
+// Wakers of pending futures. Each future registers at most one waker, in a slot that
+// is assigned on the first registration and released when the future is dropped.
+struct Wakers<T> {
+	next_slot: u64,
+	wakers: HashMap<u64, (T, Waker)>,
+}
+
+impl<T> Default for Wakers<T> {
+	fn default() -> Self {
+		Wakers { next_slot: 0, wakers: Default::default() }
+	}
+}
+
+impl<T> Wakers<T> {
+	fn register(&mut self, slot: &mut Option<u64>, data: T, waker: &Waker) {
+		let slot = *slot.get_or_insert_with(|| {
+			self.next_slot += 1;
+			self.next_slot
+		});
+		match self.wakers.get_mut(&slot) {
+			Some(entry) => {
+				entry.0 = data;
+				if !entry.1.will_wake(waker) {
+					entry.1 = waker.clone();
+				}
+			},
+			None => {
+				self.wakers.insert(slot, (data, waker.clone()));
+			},
+		}
+	}
+
+	fn remove(&mut self, slot: u64) {
+		self.wakers.remove(&slot);
+	}
+
+	fn wake_all(&mut self) {
+		for (_, (_, waker)) in self.wakers.drain() {
+			waker.wake();
+		}
+	}
+
+	// Wake and remove the wakers that `ready` returns `true` for.
+	fn wake_if(&mut self, mut ready: impl FnMut(&T) -> bool) {
+		self.wakers.retain(|_, (data, waker)| {
+			if ready(data) {
+				waker.wake_by_ref();
+				false
+			} else {
+				true
+			}
+		});
+	}
+}
+
+impl CommitProgress {
+	fn reached(&self, stage: CommitStage) -> u64 {
+		match stage {
+			CommitStage::Logged => self.logged,
+			CommitStage::Durable => self.durable,
+			CommitStage::Enacted => self.enacted,
+		}
+	}
+}
+
+// Commit written to the log. Rejected commits don't write a record and use record 0, so that
+// they are enacted in order with the commits before them.
+struct LoggedCommit {
+	record_id: u64,
+	id: u64,
+	// Columns modified by the commit. Only collected with `Options::stats`.
+	columns: Vec<ColId>,
+	logged: Instant,
+	// Set once the log record is known to be flushed.
+	durable: Option<Instant>,
+}
+
+#[derive(Clone, Copy)]
+enum CommitStage {
+	Logged,
+	Durable,
+	Enacted,
 }
 
 #[derive(Default)]
@@ -100,6 +259,131 @@ impl std::hash::Hasher for IdentityKeyHash {
 	fn finish(&self) -> u64 { self.0 }
 }
 
+// Overlay of most recent values in the commit queue for a single column.
+#[derive(Default)]
+struct CommitOverlay {
+	// Key -> (RecordId, Value).
+	indexed: HashMap<Key, (u64, Option<Value>), IdentityBuildHasher>,
+	// Original key -> (RecordId, Value). Only used for btree columns.
+	btree_indexed: BTreeMap<Vec<u8>, (u64, Option<Value>)>,
+	// Values overwritten while there are live snapshots. Key -> [(RecordId, Previous value)], sorted by RecordId.
+	undo: BTreeMap<Key, Vec<(u64, Option<Value>)>>,
+	// Same as `undo`, but for original keys of btree columns.
+	btree_undo: BTreeMap<Vec<u8>, Vec<(u64, Option<Value>)>>,
+	// Keys of the undo entries by RecordId, so that released snapshots only check the affected entries.
+	undo_ids: BTreeMap<u64, Vec<Key>>,
+	// Same as `undo_ids`, for `btree_undo`.
+	btree_undo_ids: BTreeMap<u64, Vec<Vec<u8>>>,
+	// Pending reference count changes for ref counted columns. Key -> [(RecordId, Change)].
+	rc_changes: HashMap<Key, Vec<(u64, RcChange)>, IdentityBuildHasher>,
+}
+
+impl CommitOverlay {
+	// Value of the key as seen by the snapshot, or the latest value if there's no snapshot.
+	fn get(&self, key: &Key, snapshot: Option<u64>) -> Option<&Option<Value>> {
+		if let Some(snapshot) = snapshot {
+			if let Some(value) = self.undo.get(key).and_then(|u| snapshot_value(u, snapshot)) {
+				return Some(value);
+			}
+		}
+		self.indexed.get(key).filter(|(id, _)| snapshot.is_none_or(|s| *id <= s)).map(|(_, v)| v)
+	}
+
+	// Checks if the value overwritten by commit `id` must be saved for `snapshot`, the newest
+	// live snapshot that does not see the commit.
+	fn undo_needed(&self, key: &Key, id: u64, snapshot: u64) -> bool {
+		!self.undo.get(key).is_some_and(|u| u.iter().any(|(i, _)| *i > snapshot && *i <= id))
+	}
+
+	fn save_undo(&mut self, key: Key, btree_key: Option<Vec<u8>>, id: u64, value: Option<Value>) {
+		if let Some(btree_key) = btree_key {
+			insert_undo(self.btree_undo.entry(btree_key.clone()).or_default(), id, value.clone());
+			self.btree_undo_ids.entry(id).or_default().push(btree_key);
+		}
+		insert_undo(self.undo.entry(key).or_default(), id, value);
+		self.undo_ids.entry(id).or_default().push(key);
+	}
+
+	// Remove undo entries with ids in `range` that are no longer visible to the `live` snapshots.
+	fn prune_undo(&mut self, range: (Bound<u64>, Bound<u64>), live: &[u64]) {
+		prune_undo_range(&mut self.undo, &mut self.undo_ids, range, live);
+		prune_undo_range(&mut self.btree_undo, &mut self.btree_undo_ids, range, live);
+	}
+
+	fn clear_undo(&mut self) {
+		self.undo.clear();
+		self.btree_undo.clear();
+		self.undo_ids.clear();
+		self.btree_undo_ids.clear();
+	}
+}
+
+#[derive(Clone, Copy)]
+enum RcChange {
+	Insert,
+	Reference,
+	Dereference,
+}
+
+// Apply a pending reference count change the same way the log worker does.
+fn apply_rc_change(current: Option<(Value, u32)>, change: RcChange, inserted: Option<&Value>) -> Option<(Value, u32)> {
+	match (current, change) {
+		(Some((value, rc)), RcChange::Insert) | (Some((value, rc)), RcChange::Reference) => Some((value, rc + 1)),
+		(None, RcChange::Insert) => inserted.map(|value| (value.clone(), 1)),
+		(None, _) => None,
+		(Some((value, rc)), RcChange::Dereference) => if rc > 1 { Some((value, rc - 1)) } else { None },
+	}
+}
+
+// Value as seen by the snapshot, if it was overwritten after the snapshot was taken.
+fn snapshot_value(undo: &[(u64, Option<Value>)], snapshot: u64) -> Option<&Option<Value>> {
+	undo.iter().find(|(id, _)| *id > snapshot).map(|(_, v)| v)
+}
+
+// Keep only undo entries that are still visible to some of the `live` snapshots.
+fn prune_undo(undo: &mut Vec<(u64, Option<Value>)>, live: &[u64]) {
+	let mut prev = 0;
+	undo.retain(|(id, _)| {
+		let keep = live.iter().any(|s| *s >= prev && s < id);
+		prev = *id;
+		keep
+	});
+}
+
+// Value saved for the snapshots: (Column, Key, Original btree key, Value).
+type UndoValue = (ColId, Key, Option<Vec<u8>>, Option<Value>);
+
+fn insert_undo(undo: &mut Vec<(u64, Option<Value>)>, id: u64, value: Option<Value>) {
+	let pos = undo.partition_point(|(i, _)| *i < id);
+	undo.insert(pos, (id, value));
+}
+
+fn prune_undo_range<K: Ord + Clone>(
+	undo: &mut BTreeMap<K, Vec<(u64, Option<Value>)>>,
+	undo_ids: &mut BTreeMap<u64, Vec<K>>,
+	range: (Bound<u64>, Bound<u64>),
+	live: &[u64],
+) {
+	let ids: Vec<u64> = undo_ids.range(range).map(|(id, _)| *id).collect();
+	for id in ids {
+		let mut keys = undo_ids.remove(&id).unwrap_or_default();
+		keys.retain(|key| match undo.get_mut(key) {
+			Some(u) => {
+				prune_undo(u, live);
+				let keep = u.iter().any(|(i, _)| *i == id);
+				if u.is_empty() {
+					undo.remove(key);
+				}
+				keep
+			},
+			None => false,
+		});
+		if !keys.is_empty() {
+			undo_ids.insert(id, keys);
+		}
+	}
+}
+
 struct DbInner {
 	columns: Vec<Column>,
 	options: Options,
@@ -112,8 +396,15 @@ struct DbInner {
 	log_work: Mutex<bool>,
 	commit_worker_cv: Condvar,
 	commit_work: Mutex<bool>,
-	// Overlay of most recent values int the commit queue. ColumnId -> (Key -> (RecordId, Value)).
-	commit_overlay: RwLock<Vec<HashMap<Key, (u64, Option<Value>), IdentityBuildHasher>>>,
+	// Overlay of most recent values int the commit queue, per column.
+	commit_overlay: RwLock<Vec<CommitOverlay>>,
+	// Live snapshots. RecordId -> Number of handles.
+	snapshots: Mutex<BTreeMap<u64, usize>>,
+	// Held by the log worker while the values overwritten by a commit are saved and the commit
+	// is made visible, so that no snapshot is taken in between.
+	snapshot_lock: Mutex<()>,
+	commit_progress: Mutex<CommitProgress>,
+	commit_progress_cv: Condvar,
 	log_cv: Condvar,
 	log_queue_bytes: Mutex<i64>, // This may underflow occasionally, but is bound for 0 eventually
 	flush_worker_cv: Condvar,
@@ -122,8 +413,12 @@ struct DbInner {
 	cleanup_work: Mutex<bool>,
 	last_enacted: AtomicU64,
 	next_reindex: AtomicU64,
+	// Logged column clear commits that are not enacted yet, with the cleared columns.
+	pending_clears: Mutex<BTreeMap<u64, Vec<ColId>>>,
+	flush_requested: AtomicBool,
 	bg_err: Mutex<Option<Arc<Error>>>,
-	_lock_file: std::fs::File,
+	subscribers: Mutex<Subscribers>,
+	lock_file: std::fs::File,
 }
 
 impl DbInner {
@@ -136,6 +431,7 @@ impl DbInner {
 		let lock_file = std::fs::OpenOptions::new().create(true).read(true).write(true).open(lock_path.as_path())?;
 		lock_file.try_lock_exclusive().map_err(|e| Error::Locked(e))?;
 
+		crate::migration::DropColumn::resume(&options.path)?;
 		let metadata = options.load_and_validate_metadata(create)?;
 		let mut columns = Vec::with_capacity(metadata.columns.len());
 		let mut commit_overlay = Vec::with_capacity(metadata.columns.len());
@@ -143,9 +439,7 @@ impl DbInner {
 		let last_enacted = log.replay_record_id().unwrap_or(2) - 1;
 		for c in 0 .. metadata.columns.len() {
 			columns.push(Column::open(c as ColId, &options, &metadata)?);
-			commit_overlay.push(
-				HashMap::with_hasher(std::hash::BuildHasherDefault::<IdentityKeyHash>::default())
-			);
+			commit_overlay.push(CommitOverlay::default());
 		}
 		log::debug!(target: "parity-db", "Opened db {:?}, metadata={:?}", options, metadata);
 		Ok(DbInner {
@@ -161,6 +455,10 @@ impl DbInner {
 			commit_worker_cv: Condvar::new(),
 			commit_work: Mutex::new(false),
 			commit_overlay: RwLock::new(commit_overlay),
+			snapshots: Mutex::new(Default::default()),
+			snapshot_lock: Mutex::new(()),
+			commit_progress: Mutex::new(Default::default()),
+			commit_progress_cv: Condvar::new(),
 			log_queue_bytes: Mutex::new(0),
 			log_cv: Condvar::new(),
 			flush_worker_cv: Condvar::new(),
@@ -168,9 +466,12 @@ impl DbInner {
 			cleanup_worker_cv: Condvar::new(),
 			cleanup_work: Mutex::new(false),
 			next_reindex: AtomicU64::new(1),
+			pending_clears: Mutex::new(Default::default()),
+			flush_requested: AtomicBool::new(false),
 			last_enacted: AtomicU64::new(last_enacted),
 			bg_err: Mutex::new(None),
-			_lock_file: lock_file,
+			subscribers: Mutex::new(Default::default()),
+			lock_file,
 		})
 	}
 
@@ -198,16 +499,98 @@ impl DbInner {
 		self.cleanup_worker_cv.notify_one();
 	}
 
-	fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
-		let key = self.columns[col as usize].hash(key);
+	fn get(&self, col: ColId, key: &[u8], snapshot: Option<u64>) -> Result<Option<Value>> {
+		let start = Instant::now();
+		let value = self.query(col, key, snapshot);
+		self.columns[col as usize].record_latency(Latency::Get, start.elapsed());
+		value
+	}
+
+	fn query(&self, col: ColId, key: &[u8], snapshot: Option<u64>) -> Result<Option<Value>> {
+		let column = &self.columns[col as usize];
+		// The cache only holds the latest values.
+		let lookup = if snapshot.is_none() { column.cache_lookup(key) } else { Lookup::Disabled };
+		let epoch = match lookup {
+			Lookup::Hit(value) => return Ok(Some(value)),
+			Lookup::Miss(epoch) => Some(epoch),
+			Lookup::Disabled => None,
+		};
+		let hash = column.hash(key);
 		let overlay = self.commit_overlay.read();
-		// Check commit overlay first
-		if let Some(v) = overlay.get(col as usize).and_then(|o| o.get(&key).map(|(_, v)| v.clone())) {
-			return Ok(v);
+		// Check commit overlay
+		if let Some(v) = overlay[col as usize].get(&hash, snapshot) {
+			return Ok(v.clone());
 		}
 		// Go into tables and log overlay.
 		let log = self.log.overlays();
-		self.columns[col as usize].get(&key, log)
+		let value = column.get(&hash, log)?;
+		if let (Some(epoch), Some(value)) = (epoch, &value) {
+			column.cache_insert(epoch, key, hash, value);
+		}
+		Ok(value)
+	}
+
+	fn get_with_rc(&self, col: ColId, key: &[u8]) -> Result<Option<(Value, u32)>> {
+		let key = self.columns[col as usize].hash(key);
+		let overlay = self.commit_overlay.read();
+		let overlay = &overlay[col as usize];
+		if !self.metadata.columns[col as usize].ref_counted {
+			if let Some((_, value)) = overlay.indexed.get(&key) {
+				return Ok(value.clone().map(|v| (v, 1)));
+			}
+			return self.columns[col as usize].get_with_rc(&key, self.log.overlays());
+		}
+		// The overlay lock is held, so pending changes are not applied to the log concurrently.
+		let mut current = self.columns[col as usize].get_with_rc(&key, self.log.overlays())?;
+		if let Some(changes) = overlay.rc_changes.get(&key) {
+			let inserted = overlay.indexed.get(&key).and_then(|(_, v)| v.as_ref());
+			for (_, change) in changes {
+				current = apply_rc_change(current, *change, inserted);
+			}
+		}
+		Ok(current)
+	}
+
+	fn get_many(&self, keys: &[(ColId, &[u8])]) -> Result<Vec<Option<Value>>> {
+		let mut result = vec![None; keys.len()];
+		// Positions in `result` with cache epochs, and hashed keys.
+		type ColumnLookups = (Vec<(usize, Option<u64>)>, Vec<Key>);
+		// Keys that are not in the value cache or the commit overlay, per column.
+		let mut lookups: BTreeMap<ColId, ColumnLookups> = BTreeMap::new();
+		let overlay = self.commit_overlay.read();
+		for (i, (col, key)) in keys.iter().enumerate() {
+			let column = self.columns.get(*col as usize)
+				.ok_or_else(|| Error::InvalidInput(format!("Column {} does not exist", col)))?;
+			let epoch = match column.cache_lookup(key) {
+				Lookup::Hit(value) => {
+					result[i] = Some(value);
+					continue;
+				},
+				Lookup::Miss(epoch) => Some(epoch),
+				Lookup::Disabled => None,
+			};
+			let hash = column.hash(key);
+			match overlay[*col as usize].indexed.get(&hash) {
+				Some((_, value)) => result[i] = value.clone(),
+				None => {
+					let lookup = lookups.entry(*col).or_default();
+					lookup.0.push((i, epoch));
+					lookup.1.push(hash);
+				}
+			}
+		}
+		let log = self.log.overlays().read();
+		for (col, (positions, hashes)) in lookups {
+			let column = &self.columns[col as usize];
+			let values = column.get_many(&hashes, &*log)?;
+			for (((i, epoch), hash), value) in positions.into_iter().zip(hashes).zip(values) {
+				if let (Some(epoch), Some(value)) = (epoch, &value) {
+					column.cache_insert(epoch, keys[i].1, hash, value);
+				}
+				result[i] = value;
+			}
+		}
+		Ok(result)
 	}
 
 	fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
@@ -215,7 +598,7 @@ impl DbInner {
 		let overlay = self.commit_overlay.read();
 		// Check commit overlay first
 		if let Some(l) = overlay.get(col as usize).and_then(
-			|o| o.get(&key).map(|(_, v)| v.as_ref().map(|v| v.len() as u32))
+			|o| o.indexed.get(&key).map(|(_, v)| v.as_ref().map(|v| v.len() as u32))
 		) {
 			return Ok(l);
 		}
@@ -224,170 +607,832 @@ impl DbInner {
 		self.columns[col as usize].get_size(&key, log)
 	}
 
-	// Commit simply adds the the data to the queue and to the overlay and
-	// exits as early as possible.
-	fn commit<I, K>(&self, tx: I) -> Result<()>
-	where
-		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
-		K: AsRef<[u8]>,
-	{
-		let commit: Vec<_> = tx.into_iter().map(
-			|(c, k, v)| (c, self.columns[c as usize].hash(k.as_ref()), v)
-		).collect();
+	fn contains(&self, col: ColId, key: &[u8]) -> Result<bool> {
+		let key = self.columns[col as usize].hash(key);
+		let overlay = self.commit_overlay.read();
+		// Check commit overlay first
+		if let Some((_, v)) = overlay.get(col as usize).and_then(|o| o.indexed.get(&key)) {
+			return Ok(v.is_some());
+		}
+		// Go into tables and log overlay.
+		let log = self.log.overlays();
+		self.columns[col as usize].contains(&key, log)
+	}
 
-		self.commit_raw(commit)
+	fn get_range(&self, col: ColId, key: &[u8], offset: u32, len: u32) -> Result<Option<Value>> {
+		let key = self.columns[col as usize].hash(key);
+		let start = offset as usize;
+		let end = offset.saturating_add(len) as usize;
+		let overlay = self.commit_overlay.read();
+		// Check commit overlay first
+		if let Some(v) = overlay.get(col as usize).and_then(|o| o.indexed.get(&key).map(|(_, v)| v.as_ref())) {
+			return Ok(v.map(|v| {
+				let end = end.min(v.len());
+				v[start.min(end)..end].to_vec()
+			}));
+		}
+		// Go into tables and log overlay.
+		let log = self.log.overlays();
+		self.columns[col as usize].get_range(&key, start..end, log)
 	}
 
-	fn commit_raw(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
-		{
-			let mut queue = self.commit_queue.lock();
-			if queue.bytes > MAX_COMMIT_QUEUE_BYTES {
-				log::debug!(target: "parity-db", "Waiting, qb={}", queue.bytes);
-				self.commit_queue_full_cv.wait(&mut queue);
-			}
-			{
-				let bg_err = self.bg_err.lock();
-				if let Some(err) = &*bg_err {
-					return Err(Error::Background(err.clone()));
+	fn get_reader(&self, col: ColId, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+		let snapshot = Snapshot { db: self, id: self.snapshot() };
+		let key = self.columns[col as usize].hash(key);
+		// The overlay lock must be released before the snapshot is dropped.
+		let found = {
+			let overlay = self.commit_overlay.read();
+			let overlay = &overlay[col as usize];
+			if let Some(value) = overlay.get(&key, Some(snapshot.id)) {
+				value.clone().map(|value| (value, 0, 0))
+			} else {
+				let log = self.log.overlays();
+				let column = &self.columns[col as usize];
+				match column.value_location(&key, log)? {
+					Some((tier, index, false)) => {
+						let mut buf = Vec::new();
+						column.read_part(tier, index, Some(&key), log, &mut buf)?.map(|next| (buf, tier, next))
+					},
+					// Compressed values are decompressed as a whole.
+					Some((_, _, true)) => column.get(&key, log)?.map(|value| (value, 0, 0)),
+					None => None,
 				}
 			}
+		};
+		Ok(found.map(|(buf, tier, next)| ValueReader {
+			snapshot,
+			col,
+			key,
+			tier,
+			next,
+			loaded: buf.len(),
+			buf,
+			pos: 0,
+		}))
+	}
 
-			let mut overlay = self.commit_overlay.write();
+	// Load the next part of the value into the reader buffer.
+	fn read_next_part(&self, reader: &mut ValueReader) -> Result<()> {
+		let overlay = self.commit_overlay.read();
+		let col = reader.col as usize;
+		if let Some(value) = overlay[col].undo.get(&reader.key).and_then(|u| snapshot_value(u, reader.snapshot.id)) {
+			// The value was modified while reading. Continue with the copy saved for the snapshot.
+			let value = value.as_ref().ok_or_else(|| Error::Corruption("Missing value undo".into()))?;
+			reader.buf = value[reader.loaded.min(value.len())..].to_vec();
+			reader.loaded = value.len();
+			reader.next = 0;
+		} else {
+			reader.buf.clear();
+			reader.next = self.columns[col].read_part(reader.tier, reader.next, None, self.log.overlays(), &mut reader.buf)?
+				.ok_or_else(|| Error::Corruption("Missing value part".into()))?;
+			reader.loaded += reader.buf.len();
+		}
+		reader.pos = 0;
+		Ok(())
+	}
 
-			queue.record_id += 1;
-			let record_id = queue.record_id + 1;
+	fn commit<I, K>(&self, tx: I) -> Result<CommitHandle<'_>>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.commit_transaction(tx.into_iter().collect())
+	}
 
-			let mut bytes = 0;
-			for (c, k, v) in &commit {
-				bytes += k.len();
-				bytes += v.as_ref().map_or(0, |v|v.len());
-				// Don't add removed ref-counted values to overlay.
-				if !self.metadata.columns[*c as usize].ref_counted || v.is_some() {
-					overlay[*c as usize].insert(*k, (record_id, v.clone()));
-				}
-			}
+	fn validate_key(&self, col: ColId, key: &[u8]) -> Result<&ColumnOptions> {
+		let options = self.metadata.columns.get(col as usize)
+			.ok_or_else(|| Error::InvalidInput(format!("Column {} does not exist", col)))?;
+		if options.uniform && key.len() < 32 {
+			return Err(Error::InvalidInput(format!(
+				"Key of {} bytes is too short for uniform column {}", key.len(), col
+			)));
+		}
+		if options.btree_index && key.len() > MAX_KEY_LEN {
+			return Err(Error::InvalidInput(format!(
+				"Key of {} bytes exceeds btree key limit of {} bytes", key.len(), MAX_KEY_LEN
+			)));
+		}
+		Ok(options)
+	}
 
-			let commit = Commit {
-				id: record_id,
-				changeset: commit,
-				bytes,
-			};
+	fn validate_operation(&self, col: ColId, key: &[u8], op: &Operation) -> Result<()> {
+		let options = self.validate_key(col, key)?;
+		match op {
+			Operation::Reference | Operation::Dereference if !options.ref_counted => {
+				Err(Error::InvalidInput(format!("Column {} is not reference counted", col)))
+			},
+			_ => Ok(()),
+		}
+	}
 
-			log::debug!(
-				target: "parity-db",
-				"Queued commit {}, {} bytes",
-				commit.id,
-				bytes,
-			);
-			queue.commits.push_back(commit);
-			queue.bytes += bytes;
-			self.signal_log_worker();
+	fn validate_stream(&self, col: ColId, key: &[u8]) -> Result<()> {
+		let options = self.validate_key(col, key)?;
+		if options.ref_counted || options.btree_index {
+			return Err(Error::InvalidInput(format!("Column {} does not support streamed values", col)));
 		}
 		Ok(())
 	}
 
-	fn process_commits(&self) -> Result<bool> {
-		{
-			// Wait if the queue is too big.
-			let mut queue = self.log_queue_bytes.lock();
-			if !self.shutdown.load(Ordering::Relaxed) && *queue > MAX_LOG_QUEUE_BYTES {
-				log::debug!(target: "parity-db", "Waiting, log_bytes={}", queue);
-				self.log_cv.wait(&mut queue);
-			}
+	fn validate_condition(&self, col: ColId, key: &[u8], condition: &Condition) -> Result<()> {
+		let options = self.validate_key(col, key)?;
+		match condition {
+			Condition::RefCount(_) if !options.ref_counted => {
+				Err(Error::InvalidInput(format!("Column {} is not reference counted", col)))
+			},
+			_ => Ok(()),
 		}
-		let commit = {
-			let mut queue = self.commit_queue.lock();
-			if let Some(commit) = queue.commits.pop_front() {
-				queue.bytes -= commit.bytes;
-				log::debug!(
-					target: "parity-db",
-					"Removed {}. Still queued commits {} bytes",
-					commit.bytes,
-					queue.bytes,
-				);
-				if queue.bytes <= MAX_COMMIT_QUEUE_BYTES && (queue.bytes + commit.bytes) > MAX_COMMIT_QUEUE_BYTES {
-					// Past the waiting threshold.
-					log::debug!(
-						target: "parity-db",
-						"Waking up commit queue worker",
-					);
-					self.commit_queue_full_cv.notify_one();
-				}
-				Some(commit)
-			} else {
-				None
-			}
-		};
+	}
 
-		if let Some(commit) = commit {
-			let mut reindex = false;
-			let mut writer = self.log.begin_record();
-			log::debug!(
-				target: "parity-db",
-				"Processing commit {}, record {}, {} bytes",
-				commit.id,
-				writer.record_id(),
-				commit.bytes,
-			);
-			let mut ops: u64 = 0;
-			for (c, key, value) in commit.changeset.iter() {
-				match self.columns[*c as usize].write_plan(key, value, &mut writer)? {
-					// Reindex has triggered another reindex.
-					PlanOutcome::NeedReindex => {
-						reindex = true;
-					},
-					_ => {},
-				}
-				ops += 1;
-			}
-			// Collect final changes to value tables
-			for c in self.columns.iter() {
-				c.complete_plan(&mut writer)?;
-			}
-			let record_id = writer.record_id();
-			let l = writer.drain();
+	fn clear_column(&self, col: ColId) -> Result<CommitHandle<'_>> {
+		if col as usize >= self.columns.len() {
+			return Err(Error::InvalidInput(format!("Column {} does not exist", col)));
+		}
+		self.commit_raw(CommitChangeSet { cleared: vec![col], ..Default::default() }, true)
+	}
 
-			let bytes = {
-				let bytes = self.log.end_record(l)?;
-				let mut logged_bytes = self.log_queue_bytes.lock();
-				*logged_bytes += bytes as i64;
-				self.signal_flush_worker();
-				bytes
-			};
+	// Commit simply adds the the data to the queue and to the overlay and
+	// exits as early as possible.
+	fn commit_transaction(&self, tx: Transaction) -> Result<CommitHandle<'_>> {
+		let (commit, sync) = self.prepare_transaction(tx)?;
+		self.commit_raw(commit, sync)
+	}
 
-			{
-				// Cleanup the commit overlay.
-				let mut overlay = self.commit_overlay.write();
-				for (c, key, _) in commit.changeset.iter() {
-					let overlay = &mut overlay[*c as usize];
-					if let std::collections::hash_map::Entry::Occupied(e) = overlay.entry(*key) {
-						if e.get().0 == commit.id {
-							e.remove_entry();
-						}
-					}
-				}
+	fn prepare_transaction(&self, tx: Transaction) -> Result<(CommitChangeSet, bool)> {
+		for (c, k, op) in &tx.ops {
+			self.validate_operation(*c, k, op)?;
+		}
+		for (c, k, _) in &tx.streams {
+			self.validate_stream(*c, k)?;
+		}
+		for (c, k, condition) in &tx.conditions {
+			self.validate_condition(*c, k, condition)?;
+		}
+		let mut commit = CommitChangeSet::default();
+		{
+			let subscribers = self.subscribers.lock();
+			if !subscribers.is_empty() {
+				commit.changes = Some(self.transaction_changes(&tx, &subscribers));
 			}
-
-			if reindex {
-				self.start_reindex(record_id);
+		}
+		for (c, k, condition) in tx.conditions {
+			commit.conditions.push((c, self.columns[c as usize].hash(&k), condition));
+		}
+		for (c, k, op) in tx.ops {
+			let column = &self.columns[c as usize];
+			if column.is_btree() {
+				commit.btree_indexed.push((c, k, op));
+			} else {
+				commit.indexed.push((c, column.hash(&k), op));
 			}
-
-			log::debug!(
-				target: "parity-db",
-				"Processed commit {} (record {}), {} ops, {} bytes written",
-				commit.id,
-				record_id,
-				ops,
-				bytes,
-			);
-			Ok(true)
-		} else {
-			Ok(false)
 		}
+		for (c, k, value) in tx.streams {
+			commit.streams.push((c, self.columns[c as usize].hash(&k), value));
+		}
+		Ok((commit, tx.sync))
 	}
 
-	fn start_reindex(&self, record_id: u64) {
-		self.next_reindex.store(record_id, Ordering::SeqCst);
+	// Changes of the transaction, with original keys. Only the subscribed columns are copied.
+	fn transaction_changes(&self, tx: &Transaction, subscribers: &Subscribers) -> Vec<Change> {
+		let subscribed = |col: &ColId| subscribers.is_subscribed(*col);
+		let ops = tx.ops.iter().filter(|(col, _, _)| subscribed(col)).map(|(col, key, op)| Change::Key {
+			col: *col,
+			hash: self.columns[*col as usize].hash(key),
+			key: Some(key.clone()),
+			op: op.clone(),
+		});
+		let streams = tx.streams.iter().filter(|(col, _, _)| subscribed(col)).map(|(col, key, value)| Change::Stream {
+			col: *col,
+			hash: self.columns[*col as usize].hash(key),
+			key: Some(key.clone()),
+			len: value.len() as u32,
+		});
+		ops.chain(streams).collect()
+	}
+
+	// Changes of a commit that was not made from a transaction. Original keys are only known for btree columns.
+	// Only the subscribed columns are copied.
+	fn commit_changes(&self, commit: &CommitChangeSet, subscribers: &Subscribers) -> Vec<Change> {
+		let subscribed = |col: &ColId| subscribers.is_subscribed(*col);
+		let mut changes = Vec::new();
+		for (col, hash, op) in commit.indexed.iter().filter(|(col, _, _)| subscribed(col)) {
+			changes.push(Change::Key { col: *col, hash: *hash, key: None, op: op.clone() });
+		}
+		for (col, key, op) in commit.btree_indexed.iter().filter(|(col, _, _)| subscribed(col)) {
+			let hash = self.columns[*col as usize].hash(key);
+			changes.push(Change::Key { col: *col, hash, key: Some(key.clone()), op: op.clone() });
+		}
+		for (col, hash, value) in commit.streams.iter().filter(|(col, _, _)| subscribed(col)) {
+			changes.push(Change::Stream { col: *col, hash: *hash, key: None, len: value.len() as u32 });
+		}
+		for col in commit.cleared.iter().filter(|col| subscribed(col)) {
+			changes.push(Change::ClearColumn(*col));
+		}
+		changes
+	}
+
+	// Deliver the accepted commit to the subscribers.
+	fn publish_changes(&self, commit: &mut CommitChangeSet, id: u64) {
+		let mut subscribers = self.subscribers.lock();
+		if subscribers.is_empty() {
+			subscribers.skip(id);
+			return;
+		}
+		let changes = match commit.changes.take() {
+			Some(changes) => changes,
+			None => self.commit_changes(commit, &subscribers),
+		};
+		subscribers.accepted(id, changes);
+	}
+
+	// Returns `true` if the commit writes a key of a pending commit, or a column that is being cleared.
+	fn is_blocked(&self, queue: &CommitQueue, commit: &CommitChangeSet) -> bool {
+		if queue.pending.is_empty() {
+			return false;
+		}
+		let columns = commit.columns();
+		queue.pending.values().any(|pending| {
+			pending.cleared.iter().any(|c| columns.contains(c))
+				|| commit.keys(&self.columns).any(|key| pending.keys.contains(&key))
+		})
+	}
+
+	fn commit_raw(&self, commit: CommitChangeSet, sync: bool) -> Result<CommitHandle<'_>> {
+		let mut queue = self.commit_queue.lock();
+		if queue.bytes > self.options.pipeline.max_commit_queue_bytes {
+			log::debug!(target: "parity-db", "Waiting, qb={}", queue.bytes);
+			self.commit_queue_full_cv.wait(&mut queue);
+		}
+		while self.is_blocked(&queue, &commit) && self.bg_err.lock().is_none() {
+			log::debug!(target: "parity-db", "Waiting for a pending commit");
+			self.commit_queue_full_cv.wait(&mut queue);
+		}
+		self.queue_commit(&mut queue, commit, sync)
+	}
+
+	// Same as `commit_raw`, but registers the waker instead of blocking when the queue is full.
+	fn poll_commit_raw(
+		&self,
+		commit: &mut Option<CommitChangeSet>,
+		sync: bool,
+		slot: &mut Option<u64>,
+		waker: &Waker,
+	) -> Poll<Result<CommitHandle<'_>>> {
+		let mut queue = self.commit_queue.lock();
+		let blocked = queue.bytes > self.options.pipeline.max_commit_queue_bytes
+			|| commit.as_ref().is_some_and(|commit| self.is_blocked(&queue, commit));
+		if blocked && self.bg_err.lock().is_none() {
+			log::debug!(target: "parity-db", "Yielding, qb={}", queue.bytes);
+			queue.wakers.register(slot, (), waker);
+			return Poll::Pending;
+		}
+		let commit = commit.take().expect("Commit future polled after completion");
+		Poll::Ready(self.queue_commit(&mut queue, commit, sync))
+	}
+
+	fn queue_commit(&self, queue: &mut CommitQueue, mut commit: CommitChangeSet, sync: bool) -> Result<CommitHandle<'_>> {
+		{
+			let bg_err = self.bg_err.lock();
+			if let Some(err) = &*bg_err {
+				return Err(Error::Background(err.clone()));
+			}
+		}
+
+		// Column clears delete the table files, which the snapshots may still read. Snapshots taken
+		// while the clear is pending wait until it is enacted.
+		if !commit.cleared.is_empty() && !self.snapshots.lock().is_empty() {
+			return Err(Error::InvalidInput("Can't clear a column while there are snapshots or value readers".into()));
+		}
+
+		queue.record_id += 1;
+		let record_id = queue.record_id + 1;
+
+		let newest_snapshot = self.snapshots.lock().keys().next_back().cloned();
+		let mut overlay = self.commit_overlay.write();
+
+		let mut bytes = 0;
+		for (_, k, op) in &commit.indexed {
+			bytes += k.len();
+			bytes += op.value().map_or(0, |v|v.len());
+		}
+		for (_, k, op) in &commit.btree_indexed {
+			bytes += k.len();
+			bytes += op.value().map_or(0, |v|v.len());
+		}
+		for (_, k, value) in &commit.streams {
+			bytes += k.len();
+			bytes += value.len();
+		}
+
+		let deferred = !commit.conditions.is_empty() || !commit.streams.is_empty() || !commit.cleared.is_empty();
+		let rejected = if !deferred {
+			// Values that are replaced in the overlay are saved for the snapshots here. Values
+			// that are only on disk are saved by the log worker.
+			let save_undo = |overlay: &mut CommitOverlay, key: &Key, btree_key: Option<&Vec<u8>>, prev: Option<(u64, Option<Value>)>| {
+				if let (Some(snapshot), Some((prev_id, value))) = (newest_snapshot, prev) {
+					if prev_id <= snapshot && overlay.undo_needed(key, record_id, snapshot) {
+						overlay.save_undo(*key, btree_key.cloned(), record_id, value);
+					}
+				}
+			};
+			for (c, k, op) in &commit.indexed {
+				self.columns[*c as usize].cache_invalidate(k);
+				if self.metadata.columns[*c as usize].ref_counted {
+					let change = match op {
+						Operation::Set(_) => RcChange::Insert,
+						Operation::Reference => RcChange::Reference,
+						Operation::Delete | Operation::Dereference => RcChange::Dereference,
+					};
+					overlay[*c as usize].rc_changes.entry(*k).or_default().push((record_id, change));
+				}
+				let prev = match op {
+					Operation::Set(v) => {
+						overlay[*c as usize].indexed.insert(*k, (record_id, Some(v.clone())))
+					},
+					// Don't add removed ref-counted values to overlay.
+					Operation::Delete if !self.metadata.columns[*c as usize].ref_counted => {
+						overlay[*c as usize].indexed.insert(*k, (record_id, None))
+					},
+					_ => None,
+				};
+				save_undo(&mut overlay[*c as usize], k, None, prev);
+			}
+			for (c, k, op) in &commit.btree_indexed {
+				let key = self.columns[*c as usize].hash(k);
+				self.columns[*c as usize].cache_invalidate(&key);
+				overlay[*c as usize].btree_indexed.insert(k.clone(), (record_id, op.value().cloned()));
+				let prev = overlay[*c as usize].indexed.insert(key, (record_id, op.value().cloned()));
+				save_undo(&mut overlay[*c as usize], &key, Some(k), prev);
+			}
+			self.publish_changes(&mut commit, record_id);
+			None
+		} else {
+			// Conditional commits are only made visible once the conditions are checked
+			// and the commit is written to the log. Streamed values are not kept in the
+			// overlay and are made visible the same way. Further commits that write the same
+			// keys wait until then, and commits to cleared columns wait until the clear is enacted.
+			let mut keys: HashSet<(ColId, Key)> = commit.keys(&self.columns).collect();
+			keys.extend(commit.conditions.iter().map(|(c, k, _)| (*c, *k)));
+			queue.pending.insert(record_id, PendingCommit { keys, cleared: commit.cleared.clone() });
+			self.subscribers.lock().defer(record_id);
+			Some(Arc::new(AtomicBool::new(false)))
+		};
+
+		let commit = Commit {
+			id: record_id,
+			changeset: commit,
+			bytes,
+			sync,
+			rejected: rejected.clone(),
+			queued: Instant::now(),
+		};
+
+		log::debug!(
+			target: "parity-db",
+			"Queued commit {}, {} bytes",
+			commit.id,
+			bytes,
+		);
+		queue.commits.push_back(commit);
+		queue.bytes += bytes;
+		self.signal_log_worker();
+		Ok(CommitHandle { db: self, id: record_id, rejected })
+	}
+
+	// Read the values overwritten by the commit that are needed by `snapshot`, the newest live snapshot
+	// that does not see the commit. Only the log worker changes the logged values, so no locks are held
+	// while they are read.
+	fn read_commit_undo(
+		&self,
+		commit: &CommitChangeSet,
+		id: u64,
+		snapshot: u64,
+	) -> Result<Vec<UndoValue>> {
+		let mut keys = Vec::new();
+		{
+			let overlay = self.commit_overlay.read();
+			let mut add = |c: ColId, key: Key, btree_key: Option<&Vec<u8>>| {
+				if overlay[c as usize].undo_needed(&key, id, snapshot) {
+					keys.push((c, key, btree_key.cloned()));
+				}
+			};
+			for (c, k, _) in commit.indexed.iter().filter(|(_, _, op)| *op != Operation::Reference) {
+				add(*c, *k, None);
+			}
+			for (c, k, _) in &commit.btree_indexed {
+				add(*c, self.columns[*c as usize].hash(k), Some(k));
+			}
+			for (c, k, _) in &commit.streams {
+				add(*c, *k, None);
+			}
+		}
+		keys.into_iter().map(|(c, key, btree_key)| {
+			let value = self.columns[c as usize].get(&key, self.log.overlays())?;
+			Ok((c, key, btree_key, value))
+		}).collect()
+	}
+
+	// Returns `false` if any of the commit conditions fails.
+	fn check_conditions(&self, commit: &CommitChangeSet) -> Result<bool> {
+		for (c, key, condition) in &commit.conditions {
+			if !self.columns[*c as usize].check_condition(key, condition, self.log.overlays())? {
+				log::debug!(target: "parity-db", "Condition failed for {}: {:?}", hex(key), condition);
+				return Ok(false);
+			}
+		}
+		Ok(true)
+	}
+
+	// Let the commits waiting for a pending commit proceed.
+	fn clear_pending(&self, id: u64) {
+		let mut queue = self.commit_queue.lock();
+		if queue.pending.remove(&id).is_some() {
+			self.commit_queue_full_cv.notify_all();
+			queue.wakers.wake_all();
+		}
+	}
+
+	// Advance commit progress to match the log state.
+	fn update_commit_progress(&self) {
+		let enacted = self.advance_commit_progress();
+		let enacted_clears = {
+			let mut clears = self.pending_clears.lock();
+			let pending = clears.split_off(&(enacted + 1));
+			std::mem::replace(&mut *clears, pending)
+		};
+		for id in enacted_clears.into_keys() {
+			self.clear_pending(id);
+		}
+	}
+
+	// Returns the last enacted commit.
+	fn advance_commit_progress(&self) -> u64 {
+		let mut progress = self.commit_progress.lock();
+		let durable_record = self.log.durable_record_id();
+		let enacted_record = self.last_enacted.load(Ordering::SeqCst);
+		let now = Instant::now();
+		for commit in progress.records.iter_mut().take_while(|c| c.record_id <= durable_record) {
+			if commit.durable.is_none() {
+				self.record_latency(&commit.columns, Latency::Durable, now - commit.logged);
+				commit.durable = Some(now);
+			}
+		}
+		while let Some(commit) = progress.records.front() {
+			if commit.record_id > enacted_record {
+				break;
+			}
+			match commit.durable {
+				Some(durable) => self.record_latency(&commit.columns, Latency::Enacted, now - durable),
+				// Enacted records are flushed, even if the log has not reported it yet. There is no
+				// durable to enacted sample then.
+				None => self.record_latency(&commit.columns, Latency::Durable, now - commit.logged),
+			}
+			progress.enacted = commit.id;
+			progress.records.pop_front();
+		}
+		let durable = progress.records.iter()
+			.take_while(|c| c.record_id <= durable_record)
+			.last()
+			.map_or(progress.enacted, |c| c.id);
+		progress.durable = std::cmp::max(progress.durable, durable);
+		// Deliver before the waiters are woken up.
+		self.subscribers.lock().enacted(progress.enacted);
+		let (logged, durable, enacted) = (progress.logged, progress.durable, progress.enacted);
+		progress.wakers.wake_if(|(stage, id)| match stage {
+			CommitStage::Logged => logged >= *id,
+			CommitStage::Durable => durable >= *id,
+			CommitStage::Enacted => enacted >= *id,
+		});
+		self.commit_progress_cv.notify_all();
+		progress.enacted
+	}
+
+	// Returns `None` if the commit has not reached the stage yet.
+	fn check_commit(
+		&self,
+		progress: &CommitProgress,
+		id: u64,
+		stage: CommitStage,
+		rejected: Option<&AtomicBool>,
+		flush_requested: &mut bool,
+	) -> Option<Result<()>> {
+		if rejected.is_some_and(|r| r.load(Ordering::SeqCst)) {
+			return Some(Err(Error::ConditionFailed));
+		}
+		if progress.reached(stage) >= id {
+			return Some(Ok(()));
+		}
+		if let Some(err) = &*self.bg_err.lock() {
+			return Some(Err(Error::Background(err.clone())));
+		}
+		if !*flush_requested && progress.logged >= id {
+			// Don't wait for the log file to fill up.
+			self.flush_requested.store(true, Ordering::SeqCst);
+			self.signal_flush_worker();
+			*flush_requested = true;
+		}
+		None
+	}
+
+	fn wait_commit(&self, id: u64, stage: CommitStage, rejected: Option<&AtomicBool>) -> Result<()> {
+		let mut flush_requested = false;
+		let mut progress = self.commit_progress.lock();
+		loop {
+			if let Some(result) = self.check_commit(&progress, id, stage, rejected, &mut flush_requested) {
+				return result;
+			}
+			self.commit_progress_cv.wait(&mut progress);
+		}
+	}
+
+	fn poll_commit(
+		&self,
+		id: u64,
+		stage: CommitStage,
+		rejected: Option<&AtomicBool>,
+		flush_requested: &mut bool,
+		slot: &mut Option<u64>,
+		waker: &Waker,
+	) -> Poll<Result<()>> {
+		let mut progress = self.commit_progress.lock();
+		if let Some(result) = self.check_commit(&progress, id, stage, rejected, flush_requested) {
+			return Poll::Ready(result);
+		}
+		// Wake up once the commit is logged to request a flush.
+		let stage = if progress.logged < id { CommitStage::Logged } else { stage };
+		progress.wakers.register(slot, (stage, id), waker);
+		Poll::Pending
+	}
+
+	// Wait until all queued commits are written to the log, synced and enacted.
+	fn flush(&self) -> Result<()> {
+		if let Some(err) = &*self.bg_err.lock() {
+			return Err(Error::Background(err.clone()));
+		}
+		let id = {
+			let queue = self.commit_queue.lock();
+			if queue.record_id == 0 {
+				return Ok(());
+			}
+			queue.record_id + 1
+		};
+		let mut flush_requested = false;
+		let mut progress = self.commit_progress.lock();
+		loop {
+			if progress.enacted >= id {
+				return Ok(());
+			}
+			if let Some(err) = &*self.bg_err.lock() {
+				return Err(Error::Background(err.clone()));
+			}
+			if !flush_requested && progress.logged >= id {
+				self.flush_requested.store(true, Ordering::SeqCst);
+				self.signal_flush_worker();
+				flush_requested = true;
+			}
+			self.commit_progress_cv.wait(&mut progress);
+		}
+	}
+
+	// Validated to fit.
+	fn max_log_queue_bytes(&self) -> i64 {
+		self.options.pipeline.max_log_queue_bytes as i64
+	}
+
+	fn last_durable_commit(&self) -> u64 {
+		self.commit_progress.lock().durable
+	}
+
+	fn process_commits(&self) -> Result<bool> {
+		{
+			// Wait if the queue is too big.
+			let mut queue = self.log_queue_bytes.lock();
+			if !self.shutdown.load(Ordering::Relaxed) && *queue > self.max_log_queue_bytes() {
+				log::debug!(target: "parity-db", "Waiting, log_bytes={}", queue);
+				self.log_cv.wait(&mut queue);
+			}
+		}
+		let commit = {
+			let mut queue = self.commit_queue.lock();
+			if let Some(commit) = queue.commits.pop_front() {
+				queue.bytes -= commit.bytes;
+				log::debug!(
+					target: "parity-db",
+					"Removed {}. Still queued commits {} bytes",
+					commit.bytes,
+					queue.bytes,
+				);
+				let max_bytes = self.options.pipeline.max_commit_queue_bytes;
+				if queue.bytes <= max_bytes && (queue.bytes + commit.bytes) > max_bytes {
+					// Past the waiting threshold.
+					log::debug!(
+						target: "parity-db",
+						"Waking up commit queue worker",
+					);
+					self.commit_queue_full_cv.notify_one();
+					queue.wakers.wake_all();
+				}
+				Some(commit)
+			} else {
+				None
+			}
+		};
+
+		if let Some(mut commit) = commit {
+			if commit.rejected.is_some() {
+				// Commits that write the checked keys wait until the conditions are checked.
+				if !self.check_conditions(&commit.changeset)? {
+					log::debug!(target: "parity-db", "Rejected commit {}", commit.id);
+					self.subscribers.lock().skip(commit.id);
+					if let Some(rejected) = &commit.rejected {
+						rejected.store(true, Ordering::SeqCst);
+					}
+					{
+						let mut progress = self.commit_progress.lock();
+						let now = Instant::now();
+						progress.logged = commit.id;
+						progress.records.push_back(LoggedCommit { record_id: 0, id: commit.id, columns: Vec::new(), logged: now, durable: Some(now) });
+					}
+					self.update_commit_progress();
+					self.clear_pending(commit.id);
+					return Ok(true);
+				}
+				self.publish_changes(&mut commit.changeset, commit.id);
+			}
+			let mut reindex = false;
+			let mut writer = self.log.begin_record();
+			log::debug!(
+				target: "parity-db",
+				"Processing commit {}, record {}, {} bytes",
+				commit.id,
+				writer.record_id(),
+				commit.bytes,
+			);
+			let mut ops: u64 = 0;
+			for (c, key, value) in commit.changeset.indexed.iter() {
+				match self.columns[*c as usize].write_plan(key, value, &mut writer)? {
+					// Reindex has triggered another reindex.
+					PlanOutcome::NeedReindex => {
+						reindex = true;
+					},
+					_ => {},
+				}
+				ops += 1;
+			}
+			for (c, key, value) in commit.changeset.btree_indexed.iter() {
+				if let PlanOutcome::NeedReindex = self.columns[*c as usize].write_btree_plan(key, value, &mut writer)? {
+					reindex = true;
+				}
+				ops += 1;
+			}
+			for (c, key, value) in commit.changeset.streams.iter_mut() {
+				if let PlanOutcome::NeedReindex = self.columns[*c as usize].write_stream_plan(key, value, &mut writer)? {
+					reindex = true;
+				}
+				ops += 1;
+			}
+			for c in commit.changeset.cleared.iter() {
+				writer.clear_column(*c);
+				ops += 1;
+			}
+			if !commit.changeset.cleared.is_empty() {
+				// Released once the record is enacted.
+				self.pending_clears.lock().insert(commit.id, commit.changeset.cleared.clone());
+			}
+			// Collect final changes to value tables
+			for c in self.columns.iter() {
+				c.complete_plan(&mut writer)?;
+			}
+			let record_id = writer.record_id();
+			let l = writer.drain();
+
+			// Save the values the commit overwrites for the snapshots that don't see it. Snapshots
+			// are not taken until the commit is visible.
+			let snapshot_lock = self.snapshot_lock.lock();
+			let newest_snapshot = self.snapshots.lock().range(..commit.id).next_back().map(|(id, _)| *id);
+			if let Some(snapshot) = newest_snapshot {
+				let undo = self.read_commit_undo(&commit.changeset, commit.id, snapshot)?;
+				let snapshots = self.snapshots.lock();
+				let mut overlay = self.commit_overlay.write();
+				// Snapshots may have been released meanwhile.
+				if let Some((snapshot, _)) = snapshots.range(..commit.id).next_back() {
+					for (c, key, btree_key, value) in undo {
+						if overlay[c as usize].undo_needed(&key, commit.id, *snapshot) {
+							overlay[c as usize].save_undo(key, btree_key, commit.id, value);
+						}
+					}
+				}
+			}
+
+			// Pending reference count changes must be removed together with the log update. The record
+			// is written to the file first, so that the overlay lock is only held while it is applied.
+			let record = self.log.write_record(l)?;
+			let has_rc_changes = commit.changeset.indexed.iter().any(|(c, _, _)| self.metadata.columns[*c as usize].ref_counted);
+			let rc_overlay = if has_rc_changes && commit.rejected.is_none() { Some(self.commit_overlay.write()) } else { None };
+			let bytes = {
+				let bytes = record.apply();
+				let mut logged_bytes = self.log_queue_bytes.lock();
+				*logged_bytes += bytes as i64;
+				if commit.sync {
+					self.flush_requested.store(true, Ordering::SeqCst);
+				}
+				self.signal_flush_worker();
+				bytes
+			};
+			// Changes are now visible in the log overlay. This also covers changes that are not
+			// kept in the commit overlay, such as deferred commits and reference count updates.
+			self.invalidate_cache(&commit.changeset);
+
+			{
+				// Cleanup the commit overlay.
+				let mut overlay = match rc_overlay {
+					Some(overlay) => overlay,
+					None => self.commit_overlay.write(),
+				};
+				for (c, key, _) in commit.changeset.indexed.iter() {
+					let overlay = &mut overlay[*c as usize];
+					if let std::collections::hash_map::Entry::Occupied(e) = overlay.indexed.entry(*key) {
+						if e.get().0 == commit.id {
+							e.remove_entry();
+						}
+					}
+					if let std::collections::hash_map::Entry::Occupied(mut e) = overlay.rc_changes.entry(*key) {
+						e.get_mut().retain(|(id, _)| *id != commit.id);
+						if e.get().is_empty() {
+							e.remove_entry();
+						}
+					}
+				}
+				for (c, key, _) in commit.changeset.btree_indexed.iter() {
+					let overlay = &mut overlay[*c as usize];
+					let hash = self.columns[*c as usize].hash(key);
+					if let std::collections::hash_map::Entry::Occupied(e) = overlay.indexed.entry(hash) {
+						if e.get().0 == commit.id {
+							e.remove_entry();
+						}
+					}
+					if let std::collections::btree_map::Entry::Occupied(e) = overlay.btree_indexed.entry(key.clone()) {
+						if e.get().0 == commit.id {
+							e.remove_entry();
+						}
+					}
+				}
+			}
+
+			let columns = if self.options.stats { commit.changeset.columns() } else { Vec::new() };
+			let logged = Instant::now();
+			self.record_latency(&columns, Latency::Logged, logged - commit.queued);
+			{
+				let mut progress = self.commit_progress.lock();
+				progress.logged = commit.id;
+				progress.records.push_back(LoggedCommit { record_id, id: commit.id, columns, logged, durable: None });
+			}
+			self.update_commit_progress();
+			if commit.rejected.is_some() && commit.changeset.cleared.is_empty() {
+				self.clear_pending(commit.id);
+			}
+			std::mem::drop(snapshot_lock);
+
+			if reindex {
+				self.start_reindex(record_id);
+			}
+
+			log::debug!(
+				target: "parity-db",
+				"Processed commit {} (record {}), {} ops, {} bytes written",
+				commit.id,
+				record_id,
+				ops,
+				bytes,
+			);
+			Ok(true)
+		} else {
+			Ok(false)
+		}
+	}
+
+	fn invalidate_cache(&self, changeset: &CommitChangeSet) {
+		for (c, key, _) in changeset.indexed.iter() {
+			self.columns[*c as usize].cache_invalidate(key);
+		}
+		for (c, key, _) in changeset.btree_indexed.iter() {
+			let column = &self.columns[*c as usize];
+			if column.is_cached() {
+				column.cache_invalidate(&column.hash(key));
+			}
+		}
+		for (c, key, _) in changeset.streams.iter() {
+			self.columns[*c as usize].cache_invalidate(key);
+		}
+	}
+
+	fn record_latency(&self, columns: &[ColId], latency: Latency, elapsed: Duration) {
+		for c in columns {
+			self.columns[*c as usize].record_latency(latency, elapsed);
+		}
+	}
+
+	fn start_reindex(&self, record_id: u64) {
+		self.next_reindex.store(record_id, Ordering::SeqCst);
 	}
 
 	fn process_reindex(&self) -> Result<bool> {
@@ -395,8 +1440,13 @@ impl DbInner {
 		if next_reindex == 0 || next_reindex > self.last_enacted.load(Ordering::SeqCst) {
 			return Ok(false)
 		}
+		// Index tables are replaced when the clear is enacted.
+		let clearing: Vec<ColId> = self.pending_clears.lock().values().flatten().cloned().collect();
 		// Process any pending reindexes
-		for column in self.columns.iter() {
+		for (c, column) in self.columns.iter().enumerate() {
+			if clearing.contains(&(c as ColId)) {
+				continue;
+			}
 			let (drop_index, batch) = column.reindex(&self.log)?;
 			if !batch.is_empty() || drop_index.is_some() {
 				let mut next_reindex = false;
@@ -508,7 +1558,7 @@ impl DbInner {
 									return Ok(false);
 								}
 							},
-							LogAction::DropTable(_) => {
+							LogAction::DropTable(_) | LogAction::ClearColumn(_) => {
 								continue;
 							}
 						}
@@ -543,7 +1593,15 @@ impl DbInner {
 							self.columns[id.col() as usize].drop_index(id)?;
 							// Check if there's another reindex on the next iteration
 							self.start_reindex(reader.record_id());
-						}
+						},
+						LogAction::ClearColumn(col) => {
+							log::debug!(
+								target: "parity-db",
+								"Clearing column {}",
+								col,
+							);
+							self.columns[col as usize].clear()?;
+						},
 					}
 				}
 				log::debug!(
@@ -565,6 +1623,7 @@ impl DbInner {
 
 		if let Some((record_id, cleared, bytes)) = cleared {
 			self.log.end_read(cleared, record_id);
+			self.update_commit_progress();
 			{
 				if !validation_mode {
 					let mut queue = self.log_queue_bytes.lock();
@@ -579,7 +1638,8 @@ impl DbInner {
 						);
 					}
 					*queue -= bytes as i64;
-					if *queue <= MAX_LOG_QUEUE_BYTES && (*queue + bytes as i64) > MAX_LOG_QUEUE_BYTES {
+					let max_bytes = self.max_log_queue_bytes();
+					if *queue <= max_bytes && (*queue + bytes as i64) > max_bytes {
 						self.log_cv.notify_all();
 					}
 					log::debug!(target: "parity-db", "Log queue size: {} bytes", *queue);
@@ -593,6 +1653,7 @@ impl DbInner {
 
 	fn flush_logs(&self, min_log_size: u64) -> Result<bool> {
 		let (flush_next, read_next, cleanup_next) = self.log.flush_one(min_log_size)?;
+		self.update_commit_progress();
 		if read_next {
 			self.signal_commit_worker();
 		}
@@ -603,7 +1664,7 @@ impl DbInner {
 	}
 
 	fn cleanup_logs(&self) -> Result<bool> {
-		let keep_logs = if self.options.sync_data { 0 } else { KEEP_LOGS };
+		let keep_logs = if self.options.sync_data { 0 } else { self.options.pipeline.keep_logs };
 		let num_cleanup = self.log.num_dirty_logs();
 		if num_cleanup > keep_logs {
 			if self.options.sync_data {
@@ -648,17 +1709,31 @@ impl DbInner {
 		self.signal_cleanup_worker();
 	}
 
-	fn kill_logs(&self) -> Result<()> {
+	fn init_filters(&self) -> Result<()> {
+		for c in self.columns.iter() {
+			c.init_filter(&self.log)?;
+		}
+		Ok(())
+	}
+
+	fn kill_logs(&self) -> Result<()> {
 		log::debug!(target: "parity-db", "Processing leftover commits");
 		// Finish logged records and proceed to log and enact queued commits.
 		while self.enact_logs(false)? {};
 		self.flush_logs(0)?;
 		while self.process_commits()? {};
-		while self.enact_logs(false)? {};
-		self.flush_logs(0)?;
+		// Records written above are activated for reading by the second flush.
+		for _ in 0 .. 2 {
+			while self.enact_logs(false)? {};
+			self.flush_logs(0)?;
+		}
 		while self.enact_logs(false)? {};
 		self.clean_all_logs()?;
 		self.log.kill_logs()?;
+		// All keys are enacted, saved filters are valid for the next open.
+		for c in self.columns.iter() {
+			c.save_filter()?;
+		}
 		if self.options.stats {
 			let mut path = self.options.path.clone();
 			path.push("stats.txt");
@@ -683,6 +1758,30 @@ impl DbInner {
 		}
 	}
 
+	fn stats(&self, col: ColId) -> Result<ColumnStatsSnapshot> {
+		let column = self.columns.get(col as usize)
+			.ok_or_else(|| Error::InvalidInput(format!("Column {} does not exist", col)))?;
+		Ok(column.stats(self.pipeline_stats()))
+	}
+
+	fn pipeline_stats(&self) -> PipelineStats {
+		PipelineStats {
+			commit_queue_bytes: self.commit_queue.lock().bytes,
+			log_queue_bytes: *self.log_queue_bytes.lock(),
+			last_enacted_record: self.last_enacted.load(Ordering::SeqCst),
+		}
+	}
+
+	#[cfg(feature = "metrics")]
+	fn metrics(&self) -> crate::metrics::Metrics {
+		let pipeline = self.pipeline_stats();
+		crate::metrics::Metrics {
+			columns: self.columns.iter().map(|c| c.metrics(pipeline.clone())).collect(),
+			queued_commits: self.commit_queue.lock().commits.len(),
+			pipeline,
+		}
+	}
+
 	fn clear_stats(&self, column: Option<u8>) {
 		if let Some(col) = column {
 			self.columns[col as usize].clear_stats();
@@ -696,18 +1795,637 @@ impl DbInner {
 	fn store_err(&self, result: Result<()>) {
 		if let Err(e) = result {
 			log::warn!(target: "parity-db", "Background worker error: {}", e);
-			let mut err =  self.bg_err.lock();
-			if err.is_none() {
-				*err = Some(Arc::new(e));
-				self.shutdown();
+			{
+				let mut err =  self.bg_err.lock();
+				if err.is_none() {
+					*err = Some(Arc::new(e));
+					self.shutdown();
+				}
 			}
-			self.commit_queue_full_cv.notify_one();
+			self.commit_queue_full_cv.notify_all();
+			self.commit_queue.lock().wakers.wake_all();
+			let mut progress = self.commit_progress.lock();
+			progress.wakers.wake_all();
+			self.commit_progress_cv.notify_all();
 		}
 	}
 
+	fn health(&self) -> (Option<Arc<Error>>, usize, usize, i64) {
+		let error = self.bg_err.lock().clone();
+		let (commits, bytes) = {
+			let queue = self.commit_queue.lock();
+			(queue.commits.len(), queue.bytes)
+		};
+		(error, commits, bytes, *self.log_queue_bytes.lock())
+	}
+
 	fn iter_column_while(&self, c: ColId, f: impl FnMut(IterState) -> bool) -> Result<()> {
 		self.columns[c as usize].iter_while(&self.log, f)
 	}
+
+	fn iter_from(&self, col: ColId, chunk: u64, snapshot: Option<u64>) -> Result<ColumnIterator<'_>> {
+		if col as usize >= self.columns.len() {
+			return Err(Error::InvalidInput(format!("Column {} does not exist", col)));
+		}
+		if self.columns[col as usize].is_btree() {
+			return Err(Error::InvalidInput(format!("Column {} is ordered. Use iter_ordered instead", col)));
+		}
+		if chunk > ITER_CHUNKS {
+			return Err(Error::InvalidInput(format!("Iteration chunk {} is out of range", chunk)));
+		}
+		// Commits made after the snapshot are served from the undo entries instead.
+		let visible = |id: u64| match snapshot {
+			Some(snapshot) => id <= snapshot,
+			None => true,
+		};
+		let overlay = match ColumnIterator::chunk_start(chunk) {
+			Some(start) => self.commit_overlay.read()[col as usize].indexed.iter()
+				.filter(|(k, (id, _))| **k >= start && visible(*id))
+				.map(|(k, (_, v))| (*k, v.clone()))
+				.collect(),
+			None => Default::default(),
+		};
+		Ok(ColumnIterator {
+			db: self,
+			col,
+			chunk,
+			snapshot,
+			overlay,
+			pending: Default::default(),
+		})
+	}
+
+	fn btree_iter(&self, col: ColId, prefix: &[u8], snapshot: Option<u64>) -> Result<BTreeIterator<'_>> {
+		match self.columns.get(col as usize) {
+			Some(column) if column.is_btree() => (),
+			Some(_) => return Err(Error::InvalidInput(format!("Column {} is not ordered", col))),
+			None => return Err(Error::InvalidInput(format!("Column {} does not exist", col))),
+		}
+		let mut iter = BTreeIterator {
+			db: self,
+			col,
+			prefix: prefix.to_vec(),
+			position: Position::Start,
+			snapshot,
+		};
+		iter.seek_to_first();
+		Ok(iter)
+	}
+
+	// Find the next key after the position in the given direction, merging the btree with the commit overlay.
+	fn btree_step(
+		&self,
+		col: ColId,
+		position: &Position,
+		forward: bool,
+		snapshot: Option<u64>,
+	) -> Result<Option<(Vec<u8>, Value)>> {
+		let mut bound = match (position, forward) {
+			(Position::Start, false) | (Position::End, true) => return Ok(None),
+			(Position::Start, true) | (Position::End, false) => Bound::Unbounded,
+			(Position::Before(key), true) | (Position::After(key), false) => Bound::Included(key.clone()),
+			(Position::Before(key), false) | (Position::After(key), true) => Bound::Excluded(key.clone()),
+		};
+		// Checks if `a` comes first in the iteration direction.
+		let first = |a: &Vec<u8>, b: &Vec<u8>| if forward { a <= b } else { a >= b };
+		let column = &self.columns[col as usize];
+		let overlay = self.commit_overlay.read();
+		let overlay = &overlay[col as usize];
+		// Hold the log overlay lock so that the tree does not change while it is being traversed.
+		let log = self.log.overlays().read();
+		loop {
+			let b = match &bound {
+				Bound::Included(k) => Bound::Included(k.as_slice()),
+				Bound::Excluded(k) => Bound::Excluded(k.as_slice()),
+				Bound::Unbounded => Bound::Unbounded,
+			};
+			let range = if forward { (b, Bound::Unbounded) } else { (Bound::Unbounded, b) };
+			// Commits made after the snapshot are served from the tree and the undo entries instead.
+			let mut overlay_entries = overlay.btree_indexed.range::<[u8], _>(range)
+				.filter(|(_, (id, _))| snapshot.is_none_or(|s| *id <= s));
+			let (tree_key, overlay_entry) = if forward {
+				(column.btree_next(b, &*log)?, overlay_entries.next())
+			} else {
+				(column.btree_prev(b, &*log)?, overlay_entries.next_back())
+			};
+			let current = match (tree_key, overlay_entry) {
+				(None, None) => None,
+				(Some(key), None) => Some((key, None)),
+				(None, Some((key, (_, value)))) => Some((key.clone(), Some(value))),
+				(Some(tree_key), Some((key, (_, value)))) => {
+					if first(key, &tree_key) {
+						Some((key.clone(), Some(value)))
+					} else {
+						Some((tree_key, None))
+					}
+				}
+			};
+			// Keys that were changed after the snapshot was taken.
+			let undo = snapshot.and_then(|snapshot| {
+				let mut undo = overlay.btree_undo.range::<[u8], _>(range)
+					.filter_map(|(k, u)| snapshot_value(u, snapshot).map(|v| (k, v)));
+				if forward { undo.next() } else { undo.next_back() }
+			});
+			let (key, overlay_value) = match (current, undo) {
+				(None, None) => return Ok(None),
+				(Some(current), None) => current,
+				(None, Some((key, value))) => (key.clone(), Some(value)),
+				(Some(current), Some((key, value))) => {
+					if first(key, &current.0) {
+						(key.clone(), Some(value))
+					} else {
+						current
+					}
+				}
+			};
+			let value = match overlay_value {
+				Some(value) => value.clone(),
+				None => column.get(&column.hash(&key), &*log)?,
+			};
+			if let Some(value) = value {
+				return Ok(Some((key, value)));
+			}
+			// Removed in the commit queue or missing in the snapshot.
+			bound = Bound::Excluded(key);
+		}
+	}
+
+	fn snapshot(&self) -> u64 {
+		loop {
+			let lock = self.snapshot_lock.lock();
+			let mut queue = self.commit_queue.lock();
+			// The files of a cleared column are deleted when the clear is enacted.
+			if queue.pending.values().any(|pending| !pending.cleared.is_empty()) && self.bg_err.lock().is_none() {
+				std::mem::drop(lock);
+				self.commit_queue_full_cv.wait(&mut queue);
+				continue;
+			}
+			// Pending conditional or streamed commits are not visible yet, and neither are the commits
+			// queued after them.
+			let id = match queue.pending.keys().next() {
+				Some(id) => id - 1,
+				None => queue.record_id + 1,
+			};
+			*self.snapshots.lock().entry(id).or_default() += 1;
+			return id;
+		}
+	}
+
+	fn release_snapshot(&self, id: u64) {
+		let mut snapshots = self.snapshots.lock();
+		if let std::collections::btree_map::Entry::Occupied(mut e) = snapshots.entry(id) {
+			*e.get_mut() -= 1;
+			if *e.get() != 0 {
+				return;
+			}
+			e.remove_entry();
+		}
+		let mut overlay = self.commit_overlay.write();
+		if snapshots.is_empty() {
+			for o in overlay.iter_mut() {
+				o.clear_undo();
+			}
+			return;
+		}
+		// Only the entries made after the released snapshot and up to the next one may be no longer needed.
+		let live: Vec<u64> = snapshots.keys().cloned().collect();
+		let next = snapshots.range(id + 1 ..).next().map_or(Bound::Unbounded, |(s, _)| Bound::Included(*s));
+		for o in overlay.iter_mut() {
+			o.prune_undo((Bound::Excluded(id), next), &live);
+		}
+	}
+}
+
+/// Iterator over all entries of a column, created with `Db::iter` or `Db::iter_from`.
+///
+/// Yields `(key, value, reference count)`, where `key` is the hashed key, in key order.
+/// The key space is split into `ITER_CHUNKS` chunks that are loaded one at a time and
+/// no locks are held between chunks, so iteration may be paused or dropped at any point.
+/// Commits that are queued when the iterator is created are included. Commits made later
+/// may or may not be observed.
+pub struct ColumnIterator<'a> {
+	db: &'a DbInner,
+	col: ColId,
+	chunk: u64,
+	snapshot: Option<u64>,
+	overlay: BTreeMap<Key, Option<Value>>,
+	pending: VecDeque<Result<(Key, Value, u32)>>,
+}
+
+impl<'a> ColumnIterator<'a> {
+	/// Chunk position to pass to `Db::iter_from` to resume iteration. If the current chunk
+	/// was only partially consumed, its entries are yielded again after resuming.
+	pub fn chunk_index(&self) -> u64 {
+		if self.pending.is_empty() {
+			self.chunk
+		} else {
+			self.chunk - 1
+		}
+	}
+
+	fn chunk_start(chunk: u64) -> Option<Key> {
+		if chunk >= ITER_CHUNKS {
+			return None;
+		}
+		let mut key = Key::default();
+		let shift = 64 - ITER_CHUNKS.trailing_zeros();
+		key[0..8].copy_from_slice(&(chunk << shift).to_be_bytes());
+		Some(key)
+	}
+
+	fn load_chunk(&mut self) {
+		let chunk = self.chunk;
+		self.chunk += 1;
+		let col = self.col as usize;
+		let ref_counted = self.db.metadata.columns[col].ref_counted;
+		// Pending reference count changes are removed from the overlay together with the log
+		// update, so the lock is held while reading the tables to apply each change once.
+		let rc_overlay = if ref_counted { Some(self.db.commit_overlay.read()) } else { None };
+		let items = self.db.columns[col].iter_chunk(chunk, &self.db.log);
+		// Undo entries are read after the tables, so they cover all commits that
+		// could have been observed in the tables.
+		let commit_overlay = rc_overlay.unwrap_or_else(|| self.db.commit_overlay.read());
+		let next = Self::chunk_start(self.chunk);
+		let mut overlay = match next {
+			Some(next) => {
+				let rest = self.overlay.split_off(&next);
+				std::mem::replace(&mut self.overlay, rest)
+			},
+			None => std::mem::take(&mut self.overlay),
+		};
+		use std::ops::RangeBounds;
+		let range = (
+			Self::chunk_start(chunk).map_or(Bound::Unbounded, Bound::Included),
+			next.map_or(Bound::Unbounded, Bound::Excluded),
+		);
+		if let Some(snapshot) = self.snapshot {
+			for (key, undo) in commit_overlay[col].undo.range(range) {
+				if let Some(value) = snapshot_value(undo, snapshot) {
+					overlay.insert(*key, value.clone());
+				}
+			}
+		}
+		let mut entries = BTreeMap::new();
+		for item in items {
+			match item {
+				Ok((key, value, rc)) => match overlay.remove(&key) {
+					Some(Some(value)) => { entries.insert(key, (value, rc)); },
+					Some(None) => {},
+					None => { entries.insert(key, (value, rc)); },
+				},
+				Err(e) => self.pending.push_back(Err(e)),
+			}
+		}
+		if ref_counted {
+			// Values that are only in the commit queue are inserted by the reference count changes.
+			let column_overlay = &commit_overlay[col];
+			let visible = |id: u64| self.snapshot.is_none_or(|s| id <= s);
+			for (key, changes) in column_overlay.rc_changes.iter().filter(|(k, _)| range.contains(*k)) {
+				let inserted = column_overlay.indexed.get(key).and_then(|(_, v)| v.as_ref());
+				let mut current = entries.remove(key);
+				for (_, change) in changes.iter().filter(|(id, _)| visible(*id)) {
+					current = apply_rc_change(current, *change, inserted);
+				}
+				if let Some(current) = current {
+					entries.insert(*key, current);
+				}
+			}
+		} else {
+			// Values that are only in the commit queue.
+			for (key, value) in overlay {
+				if let Some(value) = value {
+					entries.insert(key, (value, 1));
+				}
+			}
+		}
+		self.pending.extend(entries.into_iter().map(|(key, (value, rc))| Ok((key, value, rc))));
+	}
+}
+
+impl<'a> Iterator for ColumnIterator<'a> {
+	type Item = Result<(Key, Value, u32)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if let Some(item) = self.pending.pop_front() {
+				return Some(item);
+			}
+			if self.chunk >= ITER_CHUNKS {
+				return None;
+			}
+			self.load_chunk();
+		}
+	}
+}
+
+// Iterator position. Always points between two keys.
+enum Position {
+	Start,
+	End,
+	Before(Vec<u8>),
+	After(Vec<u8>),
+}
+
+/// Ordered iterator over a btree column, created with `Db::iter_ordered` or `Db::iter_prefix`.
+///
+/// Yields `(key, value)` in key order. `next` and `prev` may be mixed freely: the iterator
+/// is positioned between keys, so `prev` after `next` returns the same entry again.
+/// Each step starts at the tree root and no locks are held between steps. Commits made
+/// during iteration may or may not be observed.
+pub struct BTreeIterator<'a> {
+	db: &'a DbInner,
+	col: ColId,
+	prefix: Vec<u8>,
+	position: Position,
+	snapshot: Option<u64>,
+}
+
+impl<'a> BTreeIterator<'a> {
+	/// Position the iterator before the first key that is equal or greater than `key`.
+	/// Keys outside of the iterator prefix are clamped to the prefix range.
+	pub fn seek(&mut self, key: &[u8]) {
+		if key < self.prefix.as_slice() {
+			self.seek_to_first();
+		} else if !key.starts_with(&self.prefix) {
+			self.seek_to_last();
+		} else {
+			self.position = Position::Before(key.to_vec());
+		}
+	}
+
+	/// Position the iterator before the first key.
+	pub fn seek_to_first(&mut self) {
+		self.position = if self.prefix.is_empty() {
+			Position::Start
+		} else {
+			Position::Before(self.prefix.clone())
+		};
+	}
+
+	/// Position the iterator after the last key.
+	pub fn seek_to_last(&mut self) {
+		// Smallest key that is greater than all keys with the prefix.
+		let mut end = self.prefix.clone();
+		while let Some(last) = end.pop() {
+			if last != 0xff {
+				end.push(last + 1);
+				self.position = Position::Before(end);
+				return;
+			}
+		}
+		self.position = Position::End;
+	}
+
+	/// Move back and return the previous entry.
+	pub fn prev(&mut self) -> Option<Result<(Vec<u8>, Value)>> {
+		self.step(false)
+	}
+
+	fn step(&mut self, forward: bool) -> Option<Result<(Vec<u8>, Value)>> {
+		match self.db.btree_step(self.col, &self.position, forward, self.snapshot) {
+			Ok(Some((key, value))) if key.starts_with(&self.prefix) => {
+				self.position = if forward {
+					Position::After(key.clone())
+				} else {
+					Position::Before(key.clone())
+				};
+				Some(Ok((key, value)))
+			},
+			Ok(_) => {
+				if forward {
+					self.seek_to_last();
+				} else {
+					self.seek_to_first();
+				}
+				None
+			},
+			Err(e) => Some(Err(e)),
+		}
+	}
+}
+
+impl<'a> Iterator for BTreeIterator<'a> {
+	type Item = Result<(Vec<u8>, Value)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.step(true)
+	}
+}
+
+/// Consistent read-only view of the database, created with `Db::snapshot`.
+///
+/// Values overwritten after the snapshot was taken are kept in memory until the snapshot is dropped,
+/// so snapshots should not be held for too long while the database is being written to.
+pub struct Snapshot<'a> {
+	db: &'a DbInner,
+	id: u64,
+}
+
+impl<'a> Snapshot<'a> {
+	pub fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
+		self.db.get(col, key, Some(self.id))
+	}
+
+	/// Iterate over all entries in the column. See `Db::iter`.
+	pub fn iter(&self, col: ColId) -> Result<ColumnIterator<'_>> {
+		self.db.iter_from(col, 0, Some(self.id))
+	}
+
+	/// Iterate over a btree column in key order. See `Db::iter_ordered`.
+	pub fn iter_ordered(&self, col: ColId) -> Result<BTreeIterator<'_>> {
+		self.db.btree_iter(col, &[], Some(self.id))
+	}
+
+	/// Iterate over the keys of a btree column that start with `prefix`. See `Db::iter_prefix`.
+	pub fn iter_prefix(&self, col: ColId, prefix: &[u8]) -> Result<BTreeIterator<'_>> {
+		self.db.btree_iter(col, prefix, Some(self.id))
+	}
+}
+
+/// Reader for a single value, created with `Db::get_reader`.
+///
+/// Large uncompressed values are read one table entry at a time. The reader holds a snapshot,
+/// so the original value is still returned if it is modified while reading. As with other
+/// snapshots, keeping a reader alive makes commits save the values they overwrite.
+pub struct ValueReader<'a> {
+	snapshot: Snapshot<'a>,
+	col: ColId,
+	key: Key,
+	// Value table tier and the index of the next part. Zero once all parts are loaded.
+	tier: u8,
+	next: u64,
+	// Total number of value bytes loaded so far.
+	loaded: usize,
+	// Current part and the read position in it.
+	buf: Vec<u8>,
+	pos: usize,
+}
+
+impl<'a> std::io::Read for ValueReader<'a> {
+	fn read(&mut self, dest: &mut [u8]) -> std::io::Result<usize> {
+		if self.pos == self.buf.len() && self.next != 0 {
+			let db = self.snapshot.db;
+			db.read_next_part(self).map_err(std::io::Error::other)?;
+		}
+		let len = dest.len().min(self.buf.len() - self.pos);
+		dest[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+		self.pos += len;
+		Ok(len)
+	}
+}
+
+impl<'a> Drop for Snapshot<'a> {
+	fn drop(&mut self) {
+		self.db.release_snapshot(self.id);
+	}
+}
+
+/// State of a background worker thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WorkerState {
+	Running,
+	/// The worker has exited, normally after a background error.
+	Stopped,
+	/// The database is opened read-only.
+	Disabled,
+}
+
+/// Database health, returned by `Db::health`.
+#[derive(Clone, Debug)]
+pub struct Health {
+	pub log_worker: WorkerState,
+	pub flush_worker: WorkerState,
+	pub commit_worker: WorkerState,
+	pub cleanup_worker: WorkerState,
+	/// Error that stopped the workers. Commits fail with `Error::Background` until `Db::recover` is called.
+	pub error: Option<Arc<Error>>,
+	/// Commits waiting to be written to the log.
+	pub queued_commits: usize,
+	pub queued_commit_bytes: usize,
+	/// Log data waiting to be enacted.
+	pub log_queue_bytes: i64,
+}
+
+impl Health {
+	pub fn is_healthy(&self) -> bool {
+		self.error.is_none() && [self.log_worker, self.flush_worker, self.commit_worker, self.cleanup_worker]
+			.iter().all(|s| *s != WorkerState::Stopped)
+	}
+}
+
+/// Handle to a queued commit, returned by `Db::commit`.
+/// Allows waiting for the commit to pass through the write pipeline.
+pub struct CommitHandle<'a> {
+	db: &'a DbInner,
+	id: u64,
+	rejected: Option<Arc<AtomicBool>>,
+}
+
+impl<'a> CommitHandle<'a> {
+	/// Commit id. Commit ids are assigned in commit order.
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+
+	/// Block until the commit is written to the log.
+	/// Fails with `Error::ConditionFailed` if the transaction conditions are not met.
+	pub fn wait_logged(&self) -> Result<()> {
+		self.db.wait_commit(self.id, CommitStage::Logged, self.rejected.as_deref())
+	}
+
+	/// Block until the commit is written to disk. The commit survives a crash after this returns,
+	/// unless `sync_wal` is disabled.
+	pub fn wait_durable(&self) -> Result<()> {
+		self.db.wait_commit(self.id, CommitStage::Durable, self.rejected.as_deref())
+	}
+
+	/// Block until the commit is enacted into the tables.
+	pub fn wait_enacted(&self) -> Result<()> {
+		self.db.wait_commit(self.id, CommitStage::Enacted, self.rejected.as_deref())
+	}
+
+	/// Future version of `wait_logged`.
+	pub fn wait_logged_async(&self) -> CommitWait<'a> {
+		self.wait_async(CommitStage::Logged)
+	}
+
+	/// Future version of `wait_durable`.
+	pub fn wait_durable_async(&self) -> CommitWait<'a> {
+		self.wait_async(CommitStage::Durable)
+	}
+
+	/// Future version of `wait_enacted`.
+	pub fn wait_enacted_async(&self) -> CommitWait<'a> {
+		self.wait_async(CommitStage::Enacted)
+	}
+
+	fn wait_async(&self, stage: CommitStage) -> CommitWait<'a> {
+		CommitWait {
+			db: self.db,
+			id: self.id,
+			stage,
+			rejected: self.rejected.clone(),
+			flush_requested: false,
+			waker_slot: None,
+		}
+	}
+}
+
+/// Future returned by `Db::commit_async`. Resolves once the commit is queued.
+pub struct CommitFuture<'a> {
+	db: &'a DbInner,
+	commit: Option<CommitChangeSet>,
+	sync: bool,
+	error: Option<Error>,
+	waker_slot: Option<u64>,
+}
+
+impl<'a> Future for CommitFuture<'a> {
+	type Output = Result<CommitHandle<'a>>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		if let Some(error) = self.error.take() {
+			return Poll::Ready(Err(error));
+		}
+		let this = &mut *self;
+		this.db.poll_commit_raw(&mut this.commit, this.sync, &mut this.waker_slot, cx.waker())
+	}
+}
+
+impl<'a> Drop for CommitFuture<'a> {
+	fn drop(&mut self) {
+		if let Some(slot) = self.waker_slot {
+			self.db.commit_queue.lock().wakers.remove(slot);
+		}
+	}
+}
+
+/// Future that resolves once a commit reaches a stage of the write pipeline.
+/// Created with `CommitHandle::wait_durable_async` and similar methods.
+pub struct CommitWait<'a> {
+	db: &'a DbInner,
+	id: u64,
+	stage: CommitStage,
+	rejected: Option<Arc<AtomicBool>>,
+	flush_requested: bool,
+	waker_slot: Option<u64>,
+}
+
+impl<'a> Future for CommitWait<'a> {
+	type Output = Result<()>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+		let this = &mut *self;
+		this.db.poll_commit(this.id, this.stage, this.rejected.as_deref(), &mut this.flush_requested, &mut this.waker_slot, cx.waker())
+	}
+}
+
+impl<'a> Drop for CommitWait<'a> {
+	fn drop(&mut self) {
+		if let Some(slot) = self.waker_slot {
+			self.db.commit_progress.lock().wakers.remove(slot);
+		}
+	}
 }
 
 pub struct Db {
@@ -716,6 +2434,10 @@ pub struct Db {
 	flush_thread: Option<std::thread::JoinHandle<()>>,
 	log_thread: Option<std::thread::JoinHandle<()>>,
 	cleanup_thread: Option<std::thread::JoinHandle<()>>,
+	// Options for reopening the database, updated when columns are added or dropped.
+	options: Options,
+	read_only: bool,
+	closed: bool,
 }
 
 impl Db {
@@ -740,21 +2462,31 @@ impl Db {
 	}
 
 	pub fn open_inner(options: &Options, create: bool, read_only: bool) -> Result<Db> {
+		options.pipeline.validate()?;
 		assert!(options.is_valid());
 		let mut db = DbInner::open(options, create)?;
 		// This needs to be call before log thread: so first reindexing
 		// will run in correct state.
 		db.replay_all_logs()?;
-		let db = Arc::new(db);
-		if read_only {
-			return Ok(Db {
-				inner: db,
-				commit_thread: None,
-				flush_thread: None,
-				log_thread: None,
-				cleanup_thread: None,
-			})
+		db.init_filters()?;
+		let mut db = Db {
+			inner: Arc::new(db),
+			commit_thread: None,
+			flush_thread: None,
+			log_thread: None,
+			cleanup_thread: None,
+			options: options.clone(),
+			read_only,
+			closed: false,
+		};
+		if !read_only {
+			db.start_workers();
 		}
+		Ok(db)
+	}
+
+	fn start_workers(&mut self) {
+		let db = &self.inner;
 		let commit_worker_db = db.clone();
 		let commit_thread = std::thread::spawn(move ||
 			commit_worker_db.store_err(Self::commit_worker(commit_worker_db.clone()))
@@ -771,24 +2503,220 @@ impl Db {
 		let cleanup_thread = std::thread::spawn(move ||
 			cleanup_worker_db.store_err(Self::cleanup_worker(cleanup_worker_db.clone()))
 		);
-		Ok(Db {
-			inner: db,
-			commit_thread: Some(commit_thread),
-			flush_thread: Some(flush_thread),
-			log_thread: Some(log_thread),
-			cleanup_thread: Some(cleanup_thread),
-		})
+		self.commit_thread = Some(commit_thread);
+		self.flush_thread = Some(flush_thread);
+		self.log_thread = Some(log_thread);
+		self.cleanup_thread = Some(cleanup_thread);
+	}
+
+	fn join_workers(&mut self) {
+		self.inner.shutdown();
+		self.log_thread.take().map(|t| t.join());
+		self.flush_thread.take().map(|t| t.join());
+		self.commit_thread.take().map(|t| t.join());
+		self.cleanup_thread.take().map(|t| t.join());
+	}
+
+	// Stop the workers, enact all queued commits and remove the log files.
+	fn close_inner(&mut self) -> Result<()> {
+		if self.closed {
+			return Ok(());
+		}
+		self.closed = true;
+		self.join_workers();
+		self.inner.kill_logs()
+	}
+
+	/// Block until all queued commits are written to the log, synced to disk and enacted.
+	pub fn flush(&self) -> Result<()> {
+		self.inner.flush()
+	}
+
+	/// Close the database. Unlike dropping it, returns the first error, which is either
+	/// the background error or an error that occurred while enacting the remaining commits.
+	pub fn close(mut self) -> Result<()> {
+		let result = self.close_inner();
+		if let Some(err) = self.inner.bg_err.lock().clone() {
+			return Err(Error::Background(err));
+		}
+		result
+	}
+
+	/// Report the state of the background workers, the background error, if any,
+	/// and the size of the write queues.
+	pub fn health(&self) -> Health {
+		let state = |thread: &Option<std::thread::JoinHandle<()>>| match thread {
+			Some(t) if t.is_finished() => WorkerState::Stopped,
+			Some(_) => WorkerState::Running,
+			None => WorkerState::Disabled,
+		};
+		let (error, queued_commits, queued_commit_bytes, log_queue_bytes) = self.inner.health();
+		Health {
+			log_worker: state(&self.log_thread),
+			flush_worker: state(&self.flush_thread),
+			commit_worker: state(&self.commit_thread),
+			cleanup_worker: state(&self.cleanup_thread),
+			error,
+			queued_commits,
+			queued_commit_bytes,
+			log_queue_bytes,
+		}
+	}
+
+	/// Recover from a background error without closing the database. The workers are stopped and
+	/// the database state is reloaded from disk, replaying the log as on `open`. Commits that were
+	/// not durable when the error occurred may be lost. Subscriptions are kept, but commits enacted
+	/// during recovery are not delivered to `ChangeStage::Enacted` subscribers. Does nothing if there
+	/// is no background error. If the recovery fails, the database stays locked and it may be retried.
+	pub fn recover(&mut self) -> Result<()> {
+		if self.inner.bg_err.lock().is_none() {
+			return Ok(());
+		}
+		self.join_workers();
+		log::info!(target: "parity-db", "Recovering from background error");
+		// Buffered log data must not be written once the log files are replayed.
+		self.inner.log.discard_appending();
+		self.reopen()?;
+		if !self.read_only {
+			self.start_workers();
+		}
+		Ok(())
+	}
+
+	// Reload the database state from disk. The workers must be stopped. On failure, the database
+	// stays locked and in the error state, so that `recover` may be retried.
+	fn reopen(&mut self) -> Result<()> {
+		self.inner.lock_file.unlock()?;
+		let db = DbInner::open(&self.options, false).and_then(|mut db| {
+			db.replay_all_logs()?;
+			db.init_filters()?;
+			Ok(db)
+		});
+		let db = match db {
+			Ok(db) => db,
+			Err(e) => {
+				self.inner.lock_file.try_lock_exclusive().map_err(Error::Locked)?;
+				if self.inner.bg_err.lock().is_some() {
+					return Err(e);
+				}
+				self.inner.store_err(Err(e));
+				let err = self.inner.bg_err.lock().clone().expect("Error is stored above");
+				return Err(Error::Background(err));
+			},
+		};
+		let mut subscribers = std::mem::take(&mut *self.inner.subscribers.lock());
+		subscribers.reset();
+		*db.subscribers.lock() = subscribers;
+		self.inner = Arc::new(db);
+		Ok(())
+	}
+
+	/// Add a column to the database and return its id. Queued commits are enacted and the database
+	/// is reopened with the new column. The updated options are returned by `Db::options`.
+	pub fn add_column(&mut self, column: ColumnOptions) -> Result<ColId> {
+		if !column.is_valid() {
+			return Err(Error::InvalidConfiguration("Invalid column options".into()));
+		}
+		let mut options = self.options.clone();
+		if options.columns.len() > ColId::MAX as usize {
+			return Err(Error::InvalidConfiguration("Too many columns".into()));
+		}
+		options.columns.push(column);
+		let col = (options.columns.len() - 1) as ColId;
+		self.update_columns(options, || Ok(()))?;
+		log::info!(target: "parity-db", "Added column {}", col);
+		Ok(col)
+	}
+
+	/// Remove a column from the database and delete its files. Queued commits are enacted and the
+	/// database is reopened without the column. Columns after `col` are renumbered down by one.
+	/// An interrupted drop is finished when the database is opened next.
+	pub fn drop_column(&mut self, col: ColId) -> Result<()> {
+		let mut options = self.options.clone();
+		if col as usize >= options.columns.len() {
+			return Err(Error::InvalidInput(format!("Column {} does not exist", col)));
+		}
+		let columns = options.columns.len();
+		options.columns.remove(col as usize);
+		let path = options.path.clone();
+		// The column files are removed and renumbered when the database is reopened.
+		self.update_columns(options, || crate::migration::DropColumn::start(&path, col, columns).map(|_| ()))
+	}
+
+	/// Options the database is opened with, including the columns added or dropped since.
+	pub fn options(&self) -> &Options {
+		&self.options
+	}
+
+	// Enact all queued commits, write the metadata with the new columns and reopen the database.
+	// `prepare` is called before the metadata is written.
+	fn update_columns(&mut self, options: Options, prepare: impl FnOnce() -> Result<()>) -> Result<()> {
+		if let Some(err) = self.inner.bg_err.lock().clone() {
+			return Err(Error::Background(err));
+		}
+		if self.read_only {
+			return Err(Error::InvalidInput("Database is opened read-only".into()));
+		}
+		self.join_workers();
+		let (salt, version) = (self.inner.metadata.salt, self.inner.metadata.version);
+		let result = self.inner.kill_logs()
+			.and_then(|_| prepare())
+			.and_then(|_| options.write_metadata_with_version(&Self::metadata_path(&options), &salt, version));
+		// The metadata is replaced atomically, so the database is reopened with the old columns on failure.
+		if result.is_ok() {
+			self.options = options;
+		}
+		self.reopen()?;
+		self.start_workers();
+		result
+	}
+
+	fn metadata_path(options: &Options) -> std::path::PathBuf {
+		let mut path = options.path.clone();
+		path.push("metadata");
+		path
 	}
 
 	pub fn get(&self, col: ColId, key: &[u8]) -> Result<Option<Value>> {
-		self.inner.get(col, key)
+		self.inner.get(col, key, None)
+	}
+
+	/// Query multiple keys at once. Locks are taken once per column and values are read in the
+	/// order of their location in the value tables, using a few threads for large batches. See
+	/// `benches/get_many.rs` for a comparison with calling `get` for each key. Values are returned
+	/// in the same order as the keys.
+	pub fn get_many(&self, keys: &[(ColId, &[u8])]) -> Result<Vec<Option<Value>>> {
+		self.inner.get_many(keys)
+	}
+
+	/// Query a value along with its reference count. The count is always 1 for columns
+	/// that are not `ref_counted`.
+	pub fn get_with_rc(&self, col: ColId, key: &[u8]) -> Result<Option<(Value, u32)>> {
+		self.inner.get_with_rc(col, key)
 	}
 
 	pub fn get_size(&self, col: ColId, key: &[u8]) -> Result<Option<u32>> {
 		self.inner.get_size(col, key)
 	}
 
-	pub fn commit<I, K>(&self, tx: I) -> Result<()>
+	/// Check if the key exists, without reading the value.
+	pub fn contains(&self, col: ColId, key: &[u8]) -> Result<bool> {
+		self.inner.contains(col, key)
+	}
+
+	/// Get a reader for the value. Unlike `get`, large values are not loaded into memory as a whole.
+	pub fn get_reader(&self, col: ColId, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+		self.inner.get_reader(col, key)
+	}
+
+	/// Query `len` bytes of the value, starting at `offset`. The range is truncated to the value
+	/// size. Only the parts of large uncompressed values that cover the range are read.
+	pub fn get_range(&self, col: ColId, key: &[u8], offset: u32, len: u32) -> Result<Option<Value>> {
+		self.inner.get_range(col, key, offset, len)
+	}
+
+	/// Queue a commit. The returned handle may be used to wait for the commit to be persisted.
+	pub fn commit<I, K>(&self, tx: I) -> Result<CommitHandle<'_>>
 	where
 		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
 		K: AsRef<[u8]>,
@@ -796,8 +2724,60 @@ impl Db {
 		self.inner.commit(tx)
 	}
 
+	/// Commit a transaction. Operations are validated before anything is queued.
+	pub fn commit_transaction(&self, tx: Transaction) -> Result<CommitHandle<'_>> {
+		self.inner.commit_transaction(tx)
+	}
+
+	/// Subscribe to committed changes. Changesets are delivered in commit order, either when the
+	/// commit is queued or when it is enacted, as selected by the filter. This includes commits
+	/// made with `commit_raw`. The channel is unbounded unless `ChangeFilter::capacity` is set. The
+	/// subscription ends when the receiver is dropped.
+	pub fn subscribe(&self, filter: ChangeFilter) -> std::sync::mpsc::Receiver<ChangeSet> {
+		self.inner.subscribers.lock().add(filter)
+	}
+
+	/// Remove all keys from the column. Instead of deleting each key, the column tables are
+	/// replaced with empty ones when the commit is enacted. Commits to the column queued after
+	/// this one wait until then, and so do new snapshots. Readers see the old values until the
+	/// commit is enacted. Fails with `Error::InvalidInput` while there are snapshots or value readers.
+	pub fn clear_column(&self, col: ColId) -> Result<CommitHandle<'_>> {
+		self.inner.clear_column(col)
+	}
+
+	/// Same as `commit`, but yields instead of blocking the thread when the commit queue is full.
+	pub fn commit_async<I, K>(&self, tx: I) -> CommitFuture<'_>
+	where
+		I: IntoIterator<Item=(ColId, K, Option<Value>)>,
+		K: AsRef<[u8]>,
+	{
+		self.commit_transaction_async(tx.into_iter().collect())
+	}
+
+	/// Same as `commit_transaction`, but yields instead of blocking the thread when the commit queue is full.
+	pub fn commit_transaction_async(&self, tx: Transaction) -> CommitFuture<'_> {
+		let (commit, sync, error) = match self.inner.prepare_transaction(tx) {
+			Ok((commit, sync)) => (Some(commit), sync, None),
+			Err(e) => (None, false, Some(e)),
+		};
+		CommitFuture {
+			db: &self.inner,
+			commit,
+			sync,
+			error,
+			waker_slot: None,
+		}
+	}
+
 	pub(crate) fn commit_raw(&self, commit: Vec<(ColId, Key, Option<Value>)>) -> Result<()> {
-		self.inner.commit_raw(commit)
+		let indexed = commit.into_iter().map(|(c, k, v)| (c, k, v.into())).collect();
+		self.inner.commit_raw(CommitChangeSet { indexed, ..Default::default() }, false)?;
+		Ok(())
+	}
+
+	/// Id of the last commit that was written to disk. See `CommitHandle::wait_durable`.
+	pub fn last_durable_commit(&self) -> u64 {
+		self.inner.last_durable_commit()
 	}
 
 	pub fn num_columns(&self) -> u8 {
@@ -808,6 +2788,37 @@ impl Db {
 		self.inner.iter_column_while(c, f)
 	}
 
+	/// Iterate over all entries in the column.
+	pub fn iter(&self, col: ColId) -> Result<ColumnIterator<'_>> {
+		self.inner.iter_from(col, 0, None)
+	}
+
+	/// Iterate over column entries, starting at the given chunk position.
+	/// See `ColumnIterator::chunk_index`.
+	pub fn iter_from(&self, col: ColId, chunk: u64) -> Result<ColumnIterator<'_>> {
+		self.inner.iter_from(col, chunk, None)
+	}
+
+	/// Iterate over a btree column in key order.
+	pub fn iter_ordered(&self, col: ColId) -> Result<BTreeIterator<'_>> {
+		self.inner.btree_iter(col, &[], None)
+	}
+
+	/// Iterate over the keys of a btree column that start with `prefix`, in key order.
+	pub fn iter_prefix(&self, col: ColId, prefix: &[u8]) -> Result<BTreeIterator<'_>> {
+		self.inner.btree_iter(col, prefix, None)
+	}
+
+	/// Take a snapshot of the database. All queries made through the snapshot observe
+	/// the commits made before it was taken and none of the commits made afterwards.
+	/// Blocks while a column clear is pending.
+	pub fn snapshot(&self) -> Snapshot<'_> {
+		Snapshot {
+			db: &self.inner,
+			id: self.inner.snapshot(),
+		}
+	}
+
 	fn commit_worker(db: Arc<DbInner>) -> Result<()> {
 		let mut more_work = false;
 		while !db.shutdown.load(Ordering::SeqCst) || more_work {
@@ -855,7 +2866,12 @@ impl Db {
 				};
 				*work = false;
 			}
-			more_work = db.flush_logs(MIN_LOG_SIZE)?;
+			let min_log_size = if db.flush_requested.swap(false, Ordering::SeqCst) {
+				0
+			} else {
+				db.options.pipeline.min_log_size
+			};
+			more_work = db.flush_logs(min_log_size)?;
 		}
 		log::debug!(target: "parity-db", "Flush worker shutdown");
 		Ok(())
@@ -885,6 +2901,19 @@ impl Db {
 		self.inner.clear_stats(column)
 	}
 
+	/// Column statistics and the state of the write pipeline. Counters are only collected
+	/// when `Options::stats` is set.
+	pub fn stats(&self, col: ColId) -> Result<ColumnStatsSnapshot> {
+		self.inner.stats(col)
+	}
+
+	/// Write column statistics, reindex progress, queue sizes and value table sizes in
+	/// OpenMetrics text format.
+	#[cfg(feature = "metrics")]
+	pub fn write_metrics(&self, writer: &mut impl std::io::Write) -> Result<()> {
+		self.inner.metrics().write(writer)
+	}
+
 	pub fn check_from_index(&self, check_param: check::CheckOptions) -> Result<()> {
 		if let Some(col) = check_param.column.clone() {
 			self.inner.columns[col as usize].check_from_index(&self.inner.log, &check_param, col)?;
@@ -899,12 +2928,7 @@ impl Db {
 
 impl Drop for Db {
 	fn drop(&mut self) {
-		self.inner.shutdown();
-		self.log_thread.take().map(|t| t.join());
-		self.flush_thread.take().map(|t| t.join());
-		self.commit_thread.take().map(|t| t.join());
-		self.cleanup_thread.take().map(|t| t.join());
-		if let Err(e) = self.inner.kill_logs() {
+		if let Err(e) = self.close_inner() {
 			log::warn!(target: "parity-db", "Shutdown error: {:?}", e);
 		}
 	}
@@ -953,7 +2977,8 @@ pub mod check {
 
 #[cfg(test)]
 mod tests {
-	use super::{Db, Options};
+	use super::{Db, Options, ColumnOptions, Transaction, Condition, Operation, ColId, Error, Arc, Future, Context, Poll};
+	use crate::subscription::{Change, ChangeFilter, ChangeStage};
 	use tempfile::tempdir;
 
 	#[test]
@@ -967,6 +2992,1112 @@ mod tests {
 		assert!(Db::open(&options).map(|_| ()).unwrap_err().to_string().contains("use open_or_create"));
 	}
 
+	#[test]
+	fn test_iter() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let keys: Vec<_> = (0u32 .. 1000).map(|i| i.to_le_bytes()).collect();
+		db.commit(keys.iter().map(|k| (0, k, Some(k.to_vec())))).unwrap();
+		db.commit([(0, &keys[0], None)]).unwrap();
+
+		let check = |db: &Db| {
+			let mut expected: std::collections::HashMap<_, _> = keys[1..].iter()
+				.map(|k| (db.inner.columns[0].hash(k), k.to_vec()))
+				.collect();
+			let mut last = None;
+			for item in db.iter(0).unwrap() {
+				let (key, value, rc) = item.unwrap();
+				assert_eq!(rc, 1);
+				assert_eq!(expected.remove(&key), Some(value));
+				assert!(last < Some(key));
+				last = Some(key);
+			}
+			assert!(expected.is_empty());
+		};
+		check(&db);
+		assert!(db.iter(1).is_err());
+		std::mem::drop(db);
+		check(&Db::open(&options).unwrap());
+	}
+
+	#[test]
+	fn test_iter_resume() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let keys: Vec<_> = (0u32 .. 1000).map(|i| i.to_le_bytes()).collect();
+		db.commit(keys.iter().map(|k| (0, k, Some(k.to_vec())))).unwrap();
+
+		let all: Vec<_> = db.iter(0).unwrap().map(|i| i.unwrap()).collect();
+		let mut iter = db.iter(0).unwrap();
+		let mut resumed: Vec<_> = iter.by_ref().take(500).map(|i| i.unwrap()).collect();
+		let chunk = iter.chunk_index();
+		std::mem::drop(iter);
+		for item in db.iter_from(0, chunk).unwrap() {
+			let item = item.unwrap();
+			if !resumed.contains(&item) {
+				resumed.push(item);
+			}
+		}
+		assert_eq!(all, resumed);
+		assert_eq!(db.iter_from(0, crate::ITER_CHUNKS).unwrap().count(), 0);
+	}
+
+	#[test]
+	fn test_btree() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let keys: Vec<_> = (0u32 .. 2000).map(|i| i.to_be_bytes()).collect();
+		db.commit(keys.iter().map(|k| (0, k, Some(k.to_vec())))).unwrap();
+		db.commit(keys.iter().step_by(2).map(|k| (0, k, None))).unwrap();
+		assert!(db.commit([(0, vec![0; crate::MAX_BTREE_KEY_LEN + 1], Some(vec![]))]).is_err());
+		assert!(db.iter(0).is_err());
+		assert!(db.iter_ordered(1).is_err());
+
+		let check = |db: &Db| {
+			let expected: Vec<_> = keys.iter().skip(1).step_by(2).map(|k| (k.to_vec(), k.to_vec())).collect();
+			let all: Vec<_> = db.iter_ordered(0).unwrap().map(|i| i.unwrap()).collect();
+			assert_eq!(all, expected);
+			assert_eq!(db.get(0, &keys[1]).unwrap(), Some(keys[1].to_vec()));
+			assert_eq!(db.get(0, &keys[2]).unwrap(), None);
+
+			let mut iter = db.iter_ordered(0).unwrap();
+			iter.seek_to_last();
+			let reversed: Vec<_> = std::iter::from_fn(|| iter.prev()).map(|i| i.unwrap()).collect();
+			assert_eq!(reversed, expected.iter().rev().cloned().collect::<Vec<_>>());
+
+			let mut iter = db.iter_ordered(0).unwrap();
+			iter.seek(&keys[100]);
+			assert_eq!(iter.next().unwrap().unwrap().0, keys[101].to_vec());
+			assert_eq!(iter.next().unwrap().unwrap().0, keys[103].to_vec());
+			assert_eq!(iter.prev().unwrap().unwrap().0, keys[103].to_vec());
+			assert_eq!(iter.prev().unwrap().unwrap().0, keys[101].to_vec());
+
+			// Keys 0x0100 .. 0x01ff
+			let prefixed: Vec<_> = db.iter_prefix(0, &[0, 0, 1]).unwrap().map(|i| i.unwrap()).collect();
+			assert_eq!(prefixed, expected[128 .. 256]);
+			let mut iter = db.iter_prefix(0, &[0, 0, 1]).unwrap();
+			iter.seek_to_last();
+			assert_eq!(iter.prev().unwrap().unwrap(), expected[255]);
+			assert_eq!(db.iter_prefix(0, &[1]).unwrap().count(), 0);
+			// Seeking outside of the prefix stays within the prefix.
+			iter.seek(&keys[0]);
+			assert_eq!(iter.next().unwrap().unwrap(), expected[128]);
+			iter.seek(&keys[1999]);
+			assert_eq!(iter.prev().unwrap().unwrap(), expected[255]);
+		};
+		check(&db);
+		db.flush().unwrap();
+		// Tree nodes are not counted as values.
+		let mut values = 0;
+		db.iter_column_while(0, |state| {
+			values += !state.btree_node as usize;
+			true
+		}).unwrap();
+		assert_eq!(values, 1000);
+		assert_eq!(db.stats(0).unwrap().total_values, 1000);
+		std::mem::drop(db);
+		let db = Db::open(&options).unwrap();
+		check(&db);
+		db.commit(keys.iter().map(|k| (0, k, None))).unwrap();
+		assert_eq!(db.iter_ordered(0).unwrap().count(), 0);
+	}
+
+	#[test]
+	fn test_snapshot() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let keys: Vec<_> = (0u32 .. 200).map(|i| i.to_be_bytes()).collect();
+		let old: Vec<_> = keys[..100].iter().map(|k| (k.to_vec(), k.to_vec())).collect();
+		for col in 0 .. 2 {
+			db.commit(old.iter().map(|(k, v)| (col, k, Some(v.clone())))).unwrap();
+		}
+		let snapshot = db.snapshot();
+		for col in 0 .. 2 {
+			db.commit(keys[..50].iter().map(|k| (col, k, Some(vec![1])))).unwrap();
+			db.commit(keys[50..60].iter().map(|k| (col, k, None))).unwrap();
+			db.commit(keys[100..].iter().map(|k| (col, k, Some(vec![2])))).unwrap();
+		}
+		// Wait for the commits to be processed.
+		while !db.inner.commit_queue.lock().commits.is_empty() {
+			std::thread::sleep(std::time::Duration::from_millis(1));
+		}
+		let newer = db.snapshot();
+		db.commit(keys.iter().map(|k| (0, k, None))).unwrap();
+
+		for col in 0 .. 2 {
+			for (k, v) in old.iter() {
+				assert_eq!(snapshot.get(col, k).unwrap().as_ref(), Some(v));
+			}
+			assert_eq!(snapshot.get(col, &keys[150]).unwrap(), None);
+			assert_eq!(newer.get(col, &keys[0]).unwrap(), Some(vec![1]));
+			assert_eq!(newer.get(col, &keys[55]).unwrap(), None);
+			assert_eq!(newer.get(col, &keys[150]).unwrap(), Some(vec![2]));
+		}
+		assert_eq!(db.get(0, &keys[0]).unwrap(), None);
+		assert_eq!(db.get(1, &keys[0]).unwrap(), Some(vec![1]));
+
+		let mut hashed: Vec<_> = snapshot.iter(0).unwrap().map(|i| i.unwrap().1).collect();
+		hashed.sort();
+		assert_eq!(hashed, old.iter().map(|(_, v)| v.clone()).collect::<Vec<_>>());
+		assert_eq!(newer.iter(0).unwrap().count(), 190);
+		assert_eq!(db.iter(0).unwrap().count(), 0);
+		let ordered: Vec<_> = snapshot.iter_ordered(1).unwrap().map(|i| i.unwrap()).collect();
+		assert_eq!(ordered, old);
+		let mut iter = snapshot.iter_ordered(1).unwrap();
+		iter.seek_to_last();
+		assert_eq!(iter.prev().unwrap().unwrap(), old[99]);
+		assert_eq!(newer.iter_ordered(1).unwrap().count(), 190);
+
+		std::mem::drop(snapshot);
+		// Only the values overwritten after the newer snapshot are kept.
+		assert!(db.inner.commit_overlay.read().iter()
+			.all(|o| o.undo.values().chain(o.btree_undo.values()).flatten().all(|(id, _)| *id > newer.id)));
+		std::mem::drop(newer);
+		assert!(db.inner.commit_overlay.read().iter().all(|o| o.undo.is_empty() && o.btree_undo.is_empty()));
+	}
+
+	#[test]
+	fn test_get_many() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = Db::open_or_create(&options).unwrap();
+		let keys: Vec<_> = (0u32 .. 1000).map(|i| i.to_le_bytes()).collect();
+		db.commit(keys.iter().map(|k| (0, k, Some(k.to_vec())))).unwrap();
+		db.commit([(1, &keys[0], Some(vec![1; 10000]))]).unwrap();
+		std::mem::drop(db);
+		let db = Db::open(&options).unwrap();
+		db.commit([(0, &keys[1], None), (0, &keys[2], Some(vec![2]))]).unwrap();
+
+		let mut query: Vec<(u8, &[u8])> = keys.iter().map(|k| (0, &k[..])).collect();
+		query.push((1, &keys[0]));
+		query.push((1, &keys[1]));
+		let expected: Vec<_> = query.iter().map(|(c, k)| db.get(*c, k).unwrap()).collect();
+		assert_eq!(expected[1], None);
+		assert_eq!(expected[2], Some(vec![2]));
+		assert_eq!(db.get_many(&query).unwrap(), expected);
+		assert!(db.get_many(&[(2, &keys[0])]).is_err());
+	}
+
+	#[test]
+	fn test_get_size() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		options.columns[1].ref_counted = true;
+		options.columns[1].compression = crate::CompressionType::Snappy;
+		let db = Db::open_or_create(&options).unwrap();
+		let values: Vec<Vec<u8>> = vec![
+			vec![1; 10],
+			vec![2; 5000],
+			vec![3; 100_000],
+			(0..100_000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect(),
+			(0..200_000u32).map(|i| ((i % 30_000).wrapping_mul(2654435761) >> 24) as u8).collect(),
+		];
+		for col in 0..2 {
+			let handle = db.commit(values.iter().enumerate().map(|(i, v)| (col, [i as u8], Some(v.clone())))).unwrap();
+			for (i, v) in values.iter().enumerate() {
+				assert_eq!(db.get_size(col, &[i as u8]).unwrap(), Some(v.len() as u32));
+			}
+			handle.wait_enacted().unwrap();
+		}
+		std::mem::drop(db);
+		let db = Db::open(&options).unwrap();
+		for col in 0..2 {
+			for (i, v) in values.iter().enumerate() {
+				assert_eq!(db.get_size(col, &[i as u8]).unwrap(), Some(v.len() as u32));
+				assert_eq!(db.get(col, &[i as u8]).unwrap().as_ref(), Some(v));
+			}
+			assert_eq!(db.get_size(col, &[100]).unwrap(), None);
+		}
+	}
+
+	#[test]
+	fn test_contains() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(vec![1])), (0, b"b", Some(vec![2; 100_000])), (1, b"a", Some(vec![1]))]).unwrap();
+		assert!(db.contains(0, b"a").unwrap());
+		assert!(!db.contains(0, b"c").unwrap());
+		db.commit([(0, b"a", None)]).unwrap().wait_enacted().unwrap();
+		assert!(!db.contains(0, b"a").unwrap());
+		assert!(db.contains(0, b"b").unwrap());
+		assert!(db.contains(1, b"a").unwrap());
+		db.commit_transaction(Transaction::new().dereference(1, b"a")).unwrap().wait_enacted().unwrap();
+		assert!(!db.contains(1, b"a").unwrap());
+	}
+
+	#[test]
+	fn test_clear_column_delete_error() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].cache_bytes = 4096;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(vec![1])), (0, b"b", Some(vec![1; 1000]))]).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1]));
+
+		// Value table files can't be deleted while a directory is in their place.
+		let tables: Vec<_> = std::fs::read_dir(tmp.path()).unwrap()
+			.map(|e| e.unwrap().path())
+			.filter(|p| p.file_name().unwrap().to_str().unwrap().starts_with("table_00_"))
+			.collect();
+		assert_eq!(tables.len(), 2);
+		for path in &tables {
+			std::fs::rename(path, path.with_extension("moved")).unwrap();
+			std::fs::create_dir(path).unwrap();
+		}
+		assert!(matches!(db.clear_column(0).unwrap().wait_enacted(), Err(Error::Background(_))));
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert_eq!(db.get(0, b"b").unwrap(), None);
+		std::mem::drop(db);
+
+		// The files are deleted when the clear is replayed.
+		for path in &tables {
+			std::fs::remove_dir(path).unwrap();
+			std::fs::rename(path.with_extension("moved"), path).unwrap();
+		}
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert!(tables.iter().all(|p| !p.exists()));
+		db.commit([(0, b"a", Some(vec![2]))]).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![2]));
+	}
+
+	#[test]
+	fn test_clear_column() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].btree_index = true;
+		let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			let tx = keys.iter().flat_map(|k| [
+				(0, k.clone(), Some(k.clone())),
+				(1, k.clone(), Some(k.clone())),
+				(2, k.clone(), Some(k.clone())),
+			]);
+			db.commit(tx).unwrap();
+			db.commit([(0, b"large", Some(vec![1; 100_000]))]).unwrap();
+			db.clear_column(0).unwrap();
+			db.clear_column(1).unwrap();
+			db.commit([(0, b"new", Some(vec![1])), (1, b"new", Some(vec![1]))]).unwrap().wait_enacted().unwrap();
+			assert_eq!(db.get(0, &keys[0]).unwrap(), None);
+			assert_eq!(db.get(0, b"large").unwrap(), None);
+			assert_eq!(db.get(1, &keys[0]).unwrap(), None);
+			assert_eq!(db.get(2, &keys[0]).unwrap(), Some(keys[0].clone()));
+			assert_eq!(db.get(0, b"new").unwrap(), Some(vec![1]));
+			let all: Vec<_> = db.iter_ordered(1).unwrap().map(|i| i.unwrap()).collect();
+			assert_eq!(all, vec![(b"new".to_vec(), vec![1])]);
+			assert!(matches!(db.clear_column(3), Err(Error::InvalidInput(_))));
+
+			let snapshot = db.snapshot();
+			assert!(matches!(db.clear_column(2), Err(Error::InvalidInput(_))));
+			std::mem::drop(snapshot);
+
+			// Only commits to the cleared column wait for the clear.
+			let waker = std::task::Waker::noop();
+			let mut cx = Context::from_waker(waker);
+			let snapshot_lock = db.inner.snapshot_lock.lock();
+			let clear = db.clear_column(0).unwrap();
+			let mut other = Box::pin(db.commit_async([(2, b"other", Some(vec![1]))]));
+			assert!(other.as_mut().poll(&mut cx).is_ready());
+			let mut cleared = Box::pin(db.commit_async([(0, b"new", Some(vec![2]))]));
+			assert!(cleared.as_mut().poll(&mut cx).is_pending());
+			std::mem::drop(snapshot_lock);
+			clear.wait_enacted().unwrap();
+			block_on(cleared).unwrap().wait_enacted().unwrap();
+			assert_eq!(db.get(0, b"new").unwrap(), Some(vec![2]));
+			assert_eq!(db.get(2, b"other").unwrap(), Some(vec![1]));
+			db.commit([(0, b"new", Some(vec![1]))]).unwrap();
+		}
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &keys[1]).unwrap(), None);
+		assert_eq!(db.get(0, b"new").unwrap(), Some(vec![1]));
+		assert_eq!(db.get(1, &keys[1]).unwrap(), None);
+		assert_eq!(db.get(2, &keys[1]).unwrap(), Some(keys[1].clone()));
+		// The first record after reopening is not reported as enacted early.
+		db.clear_column(2).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(2, &keys[1]).unwrap(), None);
+	}
+
+	#[test]
+	fn test_subscribe() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].btree_index = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let queued = db.subscribe(Default::default());
+		let enacted = db.subscribe(ChangeFilter { columns: Some(vec![1]), stage: ChangeStage::Enacted, ..Default::default() });
+		let bounded = db.subscribe(ChangeFilter { capacity: Some(1), ..Default::default() });
+		let key = |col: ColId, k: &[u8], op| Change::Key { col, hash: db.inner.columns[col as usize].hash(k), key: Some(k.to_vec()), op };
+
+		let first = db.commit([(0, b"a", Some(vec![1])), (1, b"b", Some(vec![2]))]).unwrap();
+		let tx = Transaction::new().require(0, b"a", Condition::Missing).set(0, b"c", vec![3]);
+		assert!(db.commit_transaction(tx).unwrap().wait_logged().is_err());
+		db.commit_transaction(Transaction::new().delete(1, b"b").set_reader(0, b"d", 10, &[4u8; 10][..])).unwrap();
+		db.clear_column(1).unwrap().wait_enacted().unwrap();
+
+		assert_eq!(queued.try_recv().unwrap().commit_id, first.id());
+		let changes: Vec<_> = queued.try_iter().map(|c| c.changes).collect();
+		assert_eq!(changes, vec![
+			vec![
+				key(1, b"b", Operation::Delete),
+				Change::Stream { col: 0, hash: db.inner.columns[0].hash(b"d"), key: Some(b"d".to_vec()), len: 10 },
+			],
+			vec![Change::ClearColumn(1)],
+		]);
+		let changes: Vec<_> = enacted.try_iter().map(|c| c.changes).collect();
+		assert_eq!(changes, vec![
+			vec![key(1, b"b", Operation::Set(vec![2]))],
+			vec![key(1, b"b", Operation::Delete)],
+			vec![Change::ClearColumn(1)],
+		]);
+
+		// The lagging subscriber is disconnected once the channel is full.
+		assert_eq!(bounded.recv().unwrap().commit_id, first.id());
+		assert!(bounded.recv().is_err());
+	}
+
+	#[test]
+	fn test_corrupted_compression() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].compression = crate::CompressionType::Lz4;
+		let value: Vec<u8> = (0..5000u32).map(|i| (i % 7) as u8).collect();
+		{
+			let db = Db::open_or_create(&options).unwrap();
+			db.commit([(0, b"a", Some(value.clone()))]).unwrap().wait_enacted().unwrap();
+		}
+		// Uncompressed size prefix, followed by the size prepended by lz4.
+		let size = (value.len() as u32).to_le_bytes();
+		let pattern = [size, size].concat();
+		let mut found = false;
+		for entry in std::fs::read_dir(tmp.path()).unwrap() {
+			let path = entry.unwrap().path();
+			if !path.file_name().unwrap().to_string_lossy().starts_with("table_00") {
+				continue;
+			}
+			let mut data = std::fs::read(&path).unwrap();
+			if let Some(pos) = data.windows(pattern.len()).position(|w| w == &pattern[..]) {
+				data[pos + 4..pos + 8].copy_from_slice(&1u32.to_le_bytes());
+				std::fs::write(&path, data).unwrap();
+				found = true;
+			}
+		}
+		assert!(found);
+		let db = Db::open(&options).unwrap();
+		assert!(matches!(db.get(0, b"a"), Err(Error::Corruption(_))));
+		assert!(matches!(db.iter(0).unwrap().next(), Some(Err(Error::Corruption(_)))));
+		std::mem::drop(db);
+
+		let path = tmp.path().join("metadata");
+		let metadata = std::fs::read_to_string(&path).unwrap().replace("compression: 1", "compression: 9");
+		std::fs::write(&path, metadata).unwrap();
+		assert!(matches!(Db::open(&options), Err(Error::InvalidConfiguration(_))));
+	}
+
+	#[test]
+	fn test_recover() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let mut db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(vec![1]))]).unwrap().wait_durable().unwrap();
+		assert!(db.health().is_healthy());
+		db.recover().unwrap();
+
+		db.inner.store_err(Err(Error::Corruption("Test error".into())));
+		assert!(matches!(db.commit([(0, b"b", Some(vec![2]))]), Err(Error::Background(_))));
+		let health = db.health();
+		assert!(!health.is_healthy());
+		assert!(matches!(health.error.as_deref(), Some(Error::Corruption(_))));
+
+		db.recover().unwrap();
+		let health = db.health();
+		assert!(health.is_healthy());
+		assert_eq!(health.log_worker, crate::WorkerState::Running);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1]));
+		db.commit([(0, b"b", Some(vec![2]))]).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![2]));
+		std::mem::drop(db);
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1]));
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![2]));
+	}
+
+	#[test]
+	fn test_flush_close() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.flush().unwrap();
+		db.commit([(0, b"a", Some(vec![1]))]).unwrap();
+		let tx = Transaction::new().require(0, b"b", Condition::Equals(vec![2])).set(0, b"c", vec![3]);
+		db.commit_transaction(tx).unwrap();
+		db.flush().unwrap();
+		assert_eq!(db.inner.columns[0].get(&db.inner.columns[0].hash(b"a"), db.inner.log.overlays()).unwrap(), Some(vec![1]));
+		db.close().unwrap();
+
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1]));
+		db.inner.store_err(Err(Error::Corruption("Test error".into())));
+		assert!(matches!(db.flush(), Err(Error::Background(_))));
+		assert!(matches!(db.close(), Err(Error::Background(_))));
+	}
+
+	#[test]
+	fn test_pipeline_options() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.pipeline = crate::PipelineOptions {
+			max_commit_queue_bytes: 1024,
+			max_log_queue_bytes: 64 * 1024,
+			min_log_size: 4096,
+			keep_logs: 0,
+			max_log_pool_size: 1,
+			max_rebalance_batch: 16,
+		};
+		options.pipeline.min_log_size = options.pipeline.max_log_queue_bytes + 1;
+		assert!(matches!(Db::open_or_create(&options), Err(Error::InvalidConfiguration(_))));
+		options.pipeline.min_log_size = 4096;
+		let db = Db::open_or_create(&options).unwrap();
+		for batch in 0..100u32 {
+			db.commit((0..20u32).map(|i| (0, (batch * 20 + i).to_le_bytes(), Some(vec![batch as u8; 100])))).unwrap();
+		}
+		db.flush().unwrap();
+		for batch in 0..100u32 {
+			assert_eq!(db.get(0, &(batch * 20 + 19).to_le_bytes()).unwrap(), Some(vec![batch as u8; 100]));
+		}
+	}
+
+	#[test]
+	fn test_stats() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(vec![1; 10])), (0, b"b", Some(vec![2; 100]))]).unwrap().wait_enacted().unwrap();
+		db.commit([(0, b"a", Some(vec![3; 10])), (0, b"c", None)]).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![3; 10]));
+		assert_eq!(db.get(0, b"d").unwrap(), None);
+
+		let stats = db.stats(0).unwrap();
+		assert_eq!(stats.total_values, 2);
+		assert_eq!(stats.total_bytes, 110);
+		assert_eq!(stats.inserted_new, 2);
+		assert_eq!(stats.inserted_overwrite, 1);
+		assert_eq!(stats.removed_miss, 1);
+		assert_eq!(stats.queries_miss, 1);
+		assert_eq!(stats.query_histogram.iter().sum::<u64>(), 1);
+		assert_eq!(stats.value_histogram[0], 1);
+		assert_eq!(stats.value_histogram[3], 1);
+		assert!(stats.pipeline.last_enacted_record > 0);
+		assert!(matches!(db.stats(1), Err(Error::InvalidInput(_))));
+	}
+
+	#[test]
+	fn test_latency_stats() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 2);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(vec![1; 10]))]).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1; 10]));
+		assert_eq!(db.get(0, b"b").unwrap(), None);
+
+		let count = |h: &[u64]| h.iter().sum::<u64>();
+		let check = |db: &Db| {
+			let stats = db.stats(0).unwrap();
+			assert_eq!(count(&stats.get_latency), 2);
+			assert_eq!(count(&stats.logged_latency), 1);
+			assert_eq!(count(&stats.durable_latency), 1);
+			assert_eq!(count(&stats.enacted_latency), 1);
+			let stats = db.stats(1).unwrap();
+			assert_eq!(count(&stats.logged_latency), 0);
+			assert_eq!(count(&stats.get_latency), 0);
+		};
+		check(&db);
+
+		// Persisted with the rest of the stats.
+		db.collect_stats(&mut Vec::new(), None);
+		drop(db);
+		let db = Db::open(&options).unwrap();
+		check(&db);
+		// Read from the value table once the log is enacted.
+		let reads = count(&db.stats(0).unwrap().read_latency);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1; 10]));
+		assert!(count(&db.stats(0).unwrap().read_latency) > reads);
+	}
+
+	#[test]
+	fn test_value_cache() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[0].cache_bytes = 1024 * 1024;
+		options.columns[1].cache_bytes = 1024 * 1024;
+		options.columns[1].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(vec![1])), (0, b"b", Some(vec![2])), (1, b"a", Some(vec![1]))])
+			.unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1]));
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1]));
+		let stats = db.stats(0).unwrap();
+		assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+		// Batched queries use the cache as well.
+		assert_eq!(db.get_many(&[(0, b"a"), (0, b"b")]).unwrap(), vec![Some(vec![1]), Some(vec![2])]);
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![2]));
+		let stats = db.stats(0).unwrap();
+		assert_eq!((stats.cache_hits, stats.cache_misses), (3, 2));
+
+		// Updates.
+		db.commit([(0, b"a", Some(vec![3]))]).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![3]));
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![2]));
+		let tx = Transaction::new().require(0, b"a", Condition::Equals(vec![3])).set(0, b"b", vec![4]);
+		db.commit_transaction(tx).unwrap().wait_logged().unwrap();
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![4]));
+
+		// Reference counted removal.
+		assert_eq!(db.get(1, b"a").unwrap(), Some(vec![1]));
+		db.commit_transaction(Transaction::new().dereference(1, b"a")).unwrap().wait_logged().unwrap();
+		assert_eq!(db.get(1, b"a").unwrap(), None);
+
+		// Column clear.
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![3]));
+		db.clear_column(0).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		db.commit([(0, b"a", Some(vec![5]))]).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![5]));
+		drop(db);
+
+		// Not part of the column metadata.
+		options.columns[0].cache_bytes = 0;
+		let db = Db::open(&options).unwrap();
+		let before = db.stats(0).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![5]));
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![5]));
+		let stats = db.stats(0).unwrap();
+		assert_eq!((stats.cache_hits, stats.cache_misses), (before.cache_hits, before.cache_misses));
+	}
+
+	#[test]
+	fn test_filter() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].filter_bytes = 1024;
+		let filter_path = tmp.path().join("filter_00");
+		let keys: Vec<Vec<u8>> = (0..100u32).map(|i| i.to_le_bytes().to_vec()).collect();
+		let check = |db: &Db| {
+			for key in &keys {
+				assert_eq!(db.get(0, key).unwrap(), Some(key.clone()));
+			}
+			assert!(!db.contains(0, b"missing").unwrap());
+			assert_eq!(db.get(0, b"missing").unwrap(), None);
+		};
+		let db = Db::open_or_create(&options).unwrap();
+		assert_eq!(db.get(0, &keys[0]).unwrap(), None);
+		db.commit(keys.iter().map(|k| (0, k.clone(), Some(k.clone())))).unwrap();
+		// Visible before the commit is enacted.
+		check(&db);
+		drop(db);
+		assert!(filter_path.exists());
+
+		// Loaded from the saved file.
+		let db = Db::open(&options).unwrap();
+		assert!(!filter_path.exists());
+		check(&db);
+		drop(db);
+
+		// Rebuilt from the index.
+		std::fs::remove_file(&filter_path).unwrap();
+		let db = Db::open(&options).unwrap();
+		check(&db);
+		db.clear_column(0).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, &keys[0]).unwrap(), None);
+		db.commit([(0, keys[0].clone(), Some(keys[0].clone()))]).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, &keys[0]).unwrap(), Some(keys[0].clone()));
+		drop(db);
+
+		// Not part of the column metadata.
+		options.columns[0].filter_bytes = 0;
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, &keys[0]).unwrap(), Some(keys[0].clone()));
+		assert_eq!(db.get(0, &keys[1]).unwrap(), None);
+	}
+
+	#[test]
+	fn test_get_range() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].compression = crate::CompressionType::Lz4;
+		let db = Db::open_or_create(&options).unwrap();
+		let large: Vec<u8> = (0..100_000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+		let compressed: Vec<u8> = (0..200_000u32).map(|i| ((i % 30_000).wrapping_mul(2654435761) >> 24) as u8).collect();
+		let ranges: [(u32, u32); 6] = [(0, 10), (8000, 10), (5000, 20_000), (99_990, 100), (150_000, 10), (0, u32::MAX)];
+		let check = |db: &Db| {
+			for (col, key, value) in [(0, b"small", &vec![1, 2, 3]), (0, b"large", &large), (1, b"large", &compressed)] {
+				for (offset, len) in ranges {
+					let end = offset.saturating_add(len).min(value.len() as u32) as usize;
+					let expected = value[(offset as usize).min(end)..end].to_vec();
+					assert_eq!(db.get_range(col, key, offset, len).unwrap(), Some(expected));
+				}
+			}
+			assert_eq!(db.get_range(0, b"missing", 0, 10).unwrap(), None);
+		};
+		let handle = db.commit([
+			(0, b"small", Some(vec![1, 2, 3])),
+			(0, b"large", Some(large.clone())),
+			(1, b"large", Some(compressed.clone())),
+		]).unwrap();
+		check(&db);
+		handle.wait_enacted().unwrap();
+		std::mem::drop(db);
+		let db = Db::open(&options).unwrap();
+		check(&db);
+	}
+
+	#[test]
+	fn test_stream() {
+		use std::io::Read;
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let value: Vec<u8> = (0..1_000_000u32).map(|i| (i.wrapping_mul(2654435761) >> 24) as u8).collect();
+		let read = |db: &Db, key: &[u8]| {
+			let mut result = Vec::new();
+			db.get_reader(0, key).unwrap().map(|mut r| r.read_to_end(&mut result).unwrap()).map(|_| result)
+		};
+
+		let len = value.len() as u32;
+		let tx = Transaction::new().set_reader(1, b"a", len, std::io::Cursor::new(value.clone()));
+		assert!(matches!(db.commit_transaction(tx), Err(Error::InvalidInput(_))));
+
+		let tx = Transaction::new()
+			.set_reader(0, b"a", len, std::io::Cursor::new(value.clone()))
+			.set_reader(0, b"b", 3, &[1u8, 2, 3][..]);
+		db.commit_transaction(tx).unwrap().wait_logged().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap().as_ref(), Some(&value));
+		assert_eq!(db.get_size(0, b"a").unwrap(), Some(value.len() as u32));
+		assert_eq!(read(&db, b"a").as_ref(), Some(&value));
+		assert_eq!(read(&db, b"b"), Some(vec![1, 2, 3]));
+		assert_eq!(read(&db, b"c"), None);
+
+		// The reader keeps returning the original value when it is replaced.
+		let mut reader = db.get_reader(0, b"a").unwrap().unwrap();
+		let mut start = vec![0; 10_000];
+		reader.read_exact(&mut start).unwrap();
+		let tx = Transaction::new().set_reader(0, b"a", 1000, std::io::Cursor::new(value[..1000].to_vec()));
+		db.commit_transaction(tx).unwrap().wait_enacted().unwrap();
+		db.commit([(0, b"a", None)]).unwrap().wait_enacted().unwrap();
+		reader.read_to_end(&mut start).unwrap();
+		assert_eq!(start, value);
+		std::mem::drop(reader);
+		assert_eq!(read(&db, b"a"), None);
+
+		let tx = Transaction::new().set_reader(0, b"a", len, std::io::Cursor::new(value.clone()));
+		db.commit_transaction(tx).unwrap().wait_enacted().unwrap();
+		std::mem::drop(db);
+		let mut db = Db::open(&options).unwrap();
+		assert_eq!(read(&db, b"a").as_ref(), Some(&value));
+
+		// A short read fails the commit.
+		let tx = Transaction::new().set_reader(0, b"a", len + 1, std::io::Cursor::new(value.clone()));
+		assert!(db.commit_transaction(tx).unwrap().wait_logged().is_err());
+		db.recover().unwrap();
+		assert_eq!(read(&db, b"a").as_ref(), Some(&value));
+	}
+
+	#[test]
+	fn test_recover_retry() {
+		use fs2::FileExt;
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let mut db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(b"1".to_vec()))]).unwrap().wait_enacted().unwrap();
+		let tx = Transaction::new().set_reader(0, b"b", 10, &[1u8, 2, 3][..]);
+		assert!(db.commit_transaction(tx).unwrap().wait_logged().is_err());
+
+		// Recovery fails without the metadata, the database stays locked and in the error state.
+		let metadata = tmp.path().join("metadata");
+		let moved = tmp.path().join("metadata.moved");
+		std::fs::rename(&metadata, &moved).unwrap();
+		assert!(db.recover().is_err());
+		let lock = std::fs::OpenOptions::new().write(true).open(tmp.path().join("lock")).unwrap();
+		assert!(lock.try_lock_exclusive().is_err());
+		assert!(matches!(db.commit([(0, b"c", Some(b"3".to_vec()))]), Err(Error::Background(_))));
+
+		std::fs::rename(&moved, &metadata).unwrap();
+		db.recover().unwrap();
+		db.commit([(0, b"c", Some(b"3".to_vec()))]).unwrap().wait_enacted().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(b"1".to_vec()));
+		assert_eq!(db.get(0, b"c").unwrap(), Some(b"3".to_vec()));
+	}
+
+	#[test]
+	fn test_transaction() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[1].ref_counted = true;
+		options.columns[2].uniform = true;
+		let db = Db::open_or_create(&options).unwrap();
+		let invalid = |tx: Transaction| matches!(db.commit_transaction(tx), Err(Error::InvalidInput(_)));
+		assert!(invalid(Transaction::new().set(0, b"a", vec![1]).set(3, b"a", vec![1])));
+		assert!(invalid(Transaction::new().set(2, [0u8; 31], vec![1])));
+		assert!(invalid(Transaction::new().reference(0, b"a")));
+		assert!(invalid(Transaction::new().dereference(0, b"a")));
+		assert!(db.commit([(2, [0u8; 8], Some(vec![1]))]).is_err());
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+
+		db.commit_transaction(Transaction::new()
+			.set(0, b"a", vec![1])
+			.set(1, b"a", vec![2])
+			.set(2, [0u8; 32], vec![3])
+		).unwrap();
+		db.commit_transaction(Transaction::new().reference(1, b"a").reference(1, b"b").delete(0, b"a")).unwrap();
+		db.commit_transaction(Transaction::new().dereference(1, b"a")).unwrap();
+		std::mem::drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert_eq!(db.get(1, b"a").unwrap(), Some(vec![2]));
+		assert_eq!(db.get(1, b"b").unwrap(), None);
+		assert_eq!(db.get(2, &[0u8; 32]).unwrap(), Some(vec![3]));
+		db.commit_transaction(Transaction::new().dereference(1, b"a")).unwrap();
+		std::mem::drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(1, b"a").unwrap(), None);
+
+		// Synced commits are written and enacted without waiting for more data.
+		let handle = db.commit_transaction(Transaction::new().set(0, b"b", vec![1]).sync(true)).unwrap();
+		let start = std::time::Instant::now();
+		while db.inner.commit_progress.lock().enacted < handle.id() {
+			assert!(start.elapsed() < std::time::Duration::from_secs(10));
+			std::thread::sleep(std::time::Duration::from_millis(1));
+		}
+	}
+
+	#[test]
+	fn test_commit_handle() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		assert_eq!(db.last_durable_commit(), 0);
+		let first = db.commit([(0, b"a", Some(vec![1]))]).unwrap();
+		let second = db.commit([(0, b"b", Some(vec![2]))]).unwrap();
+		assert!(second.id() > first.id());
+		first.wait_logged().unwrap();
+		second.wait_durable().unwrap();
+		assert!(db.last_durable_commit() >= second.id());
+		first.wait_durable().unwrap();
+		second.wait_enacted().unwrap();
+		first.wait_enacted().unwrap();
+
+		let third = db.commit([(0, b"a", None)]).unwrap();
+		third.wait_enacted().unwrap();
+		assert!(db.last_durable_commit() >= third.id());
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![2]));
+	}
+
+	#[test]
+	fn test_conditional_commit() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(vec![1]))]).unwrap();
+
+		let tx = || Transaction::new().require(0, b"a", Condition::Equals(vec![1])).set(0, b"a", vec![2]);
+		db.commit_transaction(tx()).unwrap().wait_logged().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![2]));
+		let rejected = db.commit_transaction(tx().set(0, b"b", vec![1])).unwrap();
+		assert!(matches!(rejected.wait_logged(), Err(Error::ConditionFailed)));
+		assert!(matches!(rejected.wait_enacted(), Err(Error::ConditionFailed)));
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![2]));
+		// Rejected commits are enacted in order with the other commits.
+		db.flush().unwrap();
+		assert_eq!(db.inner.commit_progress.lock().enacted, rejected.id());
+		assert_eq!(db.get(0, b"b").unwrap(), None);
+
+		// Conditions see the result of the previous commits.
+		let tx = || Transaction::new().require(0, b"b", Condition::Missing).set(0, b"b", vec![1]);
+		let first = db.commit_transaction(tx()).unwrap();
+		let second = db.commit_transaction(tx()).unwrap();
+		let third = db.commit_transaction(Transaction::new()
+			.require(0, b"a", Condition::Equals(vec![2]))
+			.require(0, b"b", Condition::Equals(vec![1]))
+			.delete(0, b"a")
+		).unwrap();
+		first.wait_enacted().unwrap();
+		assert!(matches!(second.wait_durable(), Err(Error::ConditionFailed)));
+		third.wait_logged().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), None);
+
+		db.commit([(1, b"a", Some(vec![1])), (1, b"a", Some(vec![1]))]).unwrap();
+		assert!(matches!(
+			db.commit_transaction(Transaction::new().require(0, b"a", Condition::RefCount(1))),
+			Err(Error::InvalidInput(_))
+		));
+		let tx = || Transaction::new().require(1, b"a", Condition::RefCount(2)).dereference(1, b"a");
+		db.commit_transaction(tx()).unwrap().wait_logged().unwrap();
+		assert!(matches!(db.commit_transaction(tx()).unwrap().wait_logged(), Err(Error::ConditionFailed)));
+		let tx = Transaction::new().require(1, b"a", Condition::RefCount(1)).dereference(1, b"a");
+		db.commit_transaction(tx).unwrap().wait_logged().unwrap();
+		assert_eq!(db.get(1, b"a").unwrap(), None);
+		let tx = Transaction::new().require(1, b"a", Condition::RefCount(0)).set(1, b"a", vec![2]);
+		db.commit_transaction(tx).unwrap().wait_logged().unwrap();
+		assert_eq!(db.get(1, b"a").unwrap(), Some(vec![2]));
+	}
+
+	#[test]
+	fn test_conditional_counter() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"counter", Some(0u32.to_le_bytes().to_vec()))]).unwrap();
+		std::thread::scope(|scope| {
+			for _ in 0 .. 2 {
+				scope.spawn(|| {
+					let mut increments = 0;
+					while increments < 50 {
+						let current = db.get(0, b"counter").unwrap().unwrap();
+						let next = u32::from_le_bytes(current.clone().try_into().unwrap()) + 1;
+						let tx = Transaction::new()
+							.require(0, b"counter", Condition::Equals(current))
+							.set(0, b"counter", next.to_le_bytes().to_vec());
+						match db.commit_transaction(tx).unwrap().wait_logged() {
+							Ok(()) => increments += 1,
+							Err(Error::ConditionFailed) => {},
+							Err(e) => panic!("Unexpected error: {:?}", e),
+						}
+					}
+				});
+			}
+		});
+		assert_eq!(db.get(0, b"counter").unwrap(), Some(100u32.to_le_bytes().to_vec()));
+	}
+
+	#[test]
+	fn test_conditional_commit_blocks_keys() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		let waker = std::task::Waker::noop();
+		let mut cx = Context::from_waker(waker);
+
+		// Keep the conditional commit pending.
+		let snapshot_lock = db.inner.snapshot_lock.lock();
+		let conditional = db.commit_transaction(Transaction::new()
+			.require(0, b"a", Condition::Missing)
+			.set(0, b"b", vec![1])
+		).unwrap();
+		let mut unrelated = Box::pin(db.commit_async([(0, b"c", Some(vec![1]))]));
+		assert!(unrelated.as_mut().poll(&mut cx).is_ready());
+		let mut checked = Box::pin(db.commit_async([(0, b"a", Some(vec![1]))]));
+		assert!(checked.as_mut().poll(&mut cx).is_pending());
+		let mut written = Box::pin(db.commit_async([(0, b"b", Some(vec![2]))]));
+		assert!(written.as_mut().poll(&mut cx).is_pending());
+		std::mem::drop(snapshot_lock);
+
+		conditional.wait_logged().unwrap();
+		block_on(checked).unwrap();
+		block_on(written).unwrap().wait_logged().unwrap();
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![1]));
+		assert_eq!(db.get(0, b"b").unwrap(), Some(vec![2]));
+		assert_eq!(db.get(0, b"c").unwrap(), Some(vec![1]));
+	}
+
+	#[test]
+	fn test_get_with_rc() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 2);
+		options.columns[1].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(vec![1])), (1, b"a", Some(vec![2]))]).unwrap();
+		assert_eq!(db.get_with_rc(0, b"a").unwrap(), Some((vec![1], 1)));
+		assert_eq!(db.get_with_rc(1, b"a").unwrap(), Some((vec![2], 1)));
+		db.commit_transaction(Transaction::new().reference(1, b"a").reference(1, b"a").reference(1, b"b")).unwrap();
+		assert_eq!(db.get_with_rc(1, b"a").unwrap(), Some((vec![2], 3)));
+		assert_eq!(db.get_with_rc(1, b"b").unwrap(), None);
+		let handle = db.commit_transaction(Transaction::new().dereference(1, b"a")).unwrap();
+		assert_eq!(db.get_with_rc(1, b"a").unwrap(), Some((vec![2], 2)));
+		handle.wait_enacted().unwrap();
+		assert_eq!(db.get_with_rc(1, b"a").unwrap(), Some((vec![2], 2)));
+
+		std::mem::drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get_with_rc(1, b"a").unwrap(), Some((vec![2], 2)));
+		db.commit_transaction(Transaction::new().dereference(1, b"a").delete(1, b"a")).unwrap();
+		assert_eq!(db.get_with_rc(1, b"a").unwrap(), None);
+		db.commit([(1, b"a", Some(vec![3]))]).unwrap();
+		assert_eq!(db.get_with_rc(1, b"a").unwrap(), Some((vec![3], 1)));
+	}
+
+	#[test]
+	fn test_iter_rc() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 1);
+		options.columns[0].ref_counted = true;
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit([(0, b"a", Some(vec![1])), (0, b"b", Some(vec![2]))]).unwrap();
+		db.commit_transaction(Transaction::new().reference(0, b"a").reference(0, b"a").dereference(0, b"b")).unwrap();
+		let items: Vec<_> = db.iter(0).unwrap().map(|i| i.unwrap()).collect();
+		assert_eq!(items, vec![(db.inner.columns[0].hash(b"a"), vec![1], 3)]);
+	}
+
+	fn block_on<F: Future>(future: F) -> F::Output {
+		struct ThreadWaker(std::thread::Thread);
+		impl std::task::Wake for ThreadWaker {
+			fn wake(self: Arc<Self>) {
+				self.0.unpark();
+			}
+		}
+		let waker = Arc::new(ThreadWaker(std::thread::current())).into();
+		let mut cx = Context::from_waker(&waker);
+		let mut future = Box::pin(future);
+		loop {
+			match future.as_mut().poll(&mut cx) {
+				Poll::Ready(result) => return result,
+				Poll::Pending => std::thread::park(),
+			}
+		}
+	}
+
+	#[test]
+	fn test_commit_async() {
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 1);
+		let db = Db::open_or_create(&options).unwrap();
+		assert!(matches!(block_on(db.commit_async([(1, b"a", Some(vec![1]))])), Err(Error::InvalidInput(_))));
+
+		// Overflow the commit queue.
+		let value = vec![0u8; 9 * 1024 * 1024];
+		let mut last = None;
+		for i in 0u8 .. 3 {
+			last = Some(block_on(db.commit_async([(0, [i], Some(value.clone()))])).unwrap());
+		}
+		let last = last.unwrap();
+		block_on(last.wait_logged_async()).unwrap();
+		block_on(last.wait_durable_async()).unwrap();
+		assert!(db.last_durable_commit() >= last.id());
+		block_on(last.wait_enacted_async()).unwrap();
+
+		let handle = block_on(db.commit_transaction_async(Transaction::new().delete(0, [0u8]))).unwrap();
+		block_on(handle.wait_enacted_async()).unwrap();
+		assert_eq!(db.get(0, &[0]).unwrap(), None);
+		assert_eq!(db.get(0, &[2]).unwrap(), Some(value));
+
+		// Repeated polls keep a single waker, which is removed with the future.
+		let waker = std::task::Waker::noop();
+		let mut cx = Context::from_waker(waker);
+		let mut wait = Box::pin(super::CommitWait {
+			db: &db.inner,
+			id: u64::MAX,
+			stage: super::CommitStage::Enacted,
+			rejected: None,
+			flush_requested: false,
+			waker_slot: None,
+		});
+		for _ in 0 .. 3 {
+			assert!(wait.as_mut().poll(&mut cx).is_pending());
+		}
+		assert_eq!(db.inner.commit_progress.lock().wakers.wakers.len(), 1);
+		std::mem::drop(wait);
+		assert!(db.inner.commit_progress.lock().wakers.wakers.is_empty());
+	}
+
+	#[test]
+	fn test_add_drop_column() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		options.columns[2].btree_index = true;
+		let mut db = Db::open_or_create(&options).unwrap();
+		db.commit((0..3).map(|c| (c, b"a", Some(vec![c])))).unwrap();
+
+		let mut column = ColumnOptions::default();
+		column.ref_counted = true;
+		assert_eq!(db.add_column(column).unwrap(), 3);
+		assert_eq!(db.options().columns.len(), 4);
+		db.commit([(3, b"a", Some(vec![3]))]).unwrap();
+
+		db.drop_column(1).unwrap();
+		assert!(db.drop_column(3).is_err());
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![0]));
+		assert_eq!(db.get(1, b"a").unwrap(), Some(vec![2]));
+		assert_eq!(db.get(2, b"a").unwrap(), Some(vec![3]));
+		assert_eq!(db.iter_ordered(1).unwrap().count(), 1);
+		db.commit_transaction(Transaction::new().reference(2, b"a")).unwrap();
+		assert_eq!(db.get_with_rc(2, b"a").unwrap(), Some((vec![3], 2)));
+
+		let new_options = db.options().clone();
+		std::mem::drop(db);
+		assert!(Db::open(&options).is_err());
+		let db = Db::open(&new_options).unwrap();
+		assert_eq!(db.get_with_rc(2, b"a").unwrap(), Some((vec![3], 2)));
+		std::mem::drop(db);
+		assert!(Db::open_read_only(&new_options).unwrap().add_column(Default::default()).is_err());
+	}
+
+	#[test]
+	fn test_drop_column_filter() {
+		let tmp = tempdir().unwrap();
+		let mut options = Options::with_columns(tmp.path(), 3);
+		for c in options.columns.iter_mut() {
+			c.filter_bytes = 1024;
+		}
+		let mut db = Db::open_or_create(&options).unwrap();
+		db.commit((0..3).map(|c| (c, vec![c], Some(vec![c])))).unwrap().wait_enacted().unwrap();
+
+		// The filter of the dropped column is removed and the following filters are renumbered.
+		db.drop_column(1).unwrap();
+		assert!(!tmp.path().join("filter_02").exists());
+		assert_eq!(db.get(0, &[0]).unwrap(), Some(vec![0]));
+		assert_eq!(db.get(1, &[2]).unwrap(), Some(vec![2]));
+		assert_eq!(db.get(1, &[1]).unwrap(), None);
+		let options = db.options().clone();
+		std::mem::drop(db);
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(1, &[2]).unwrap(), Some(vec![2]));
+	}
+
+	#[test]
+	fn test_drop_column_interrupted() {
+		use crate::migration::DropColumn;
+		let tmp = tempdir().unwrap();
+		let options = Options::with_columns(tmp.path(), 4);
+		let db = Db::open_or_create(&options).unwrap();
+		db.commit((0..4).map(|c| (c, b"a", Some(vec![c])))).unwrap();
+		std::mem::drop(db);
+		let metadata = Options::load_metadata(&tmp.path().join("metadata")).unwrap().unwrap();
+		let mut new_options = options.clone();
+		new_options.columns.remove(1);
+
+		// The drop is discarded if the metadata was not written.
+		DropColumn::start(tmp.path(), 1, 4).unwrap();
+		let db = Db::open(&options).unwrap();
+		assert_eq!(db.get(1, b"a").unwrap(), Some(vec![1]));
+		std::mem::drop(db);
+		assert_eq!(DropColumn::load(tmp.path()).unwrap(), None);
+
+		// Interrupted after the files of column 2 are renumbered.
+		let mut drop = DropColumn::start(tmp.path(), 1, 4).unwrap();
+		new_options.write_metadata_with_version(&tmp.path().join("metadata"), &metadata.salt, metadata.version).unwrap();
+		assert!(drop.step(tmp.path()).unwrap());
+		assert!(drop.step(tmp.path()).unwrap());
+		let db = Db::open(&new_options).unwrap();
+		assert_eq!(DropColumn::load(tmp.path()).unwrap(), None);
+		assert_eq!(db.get(0, b"a").unwrap(), Some(vec![0]));
+		assert_eq!(db.get(1, b"a").unwrap(), Some(vec![2]));
+		assert_eq!(db.get(2, b"a").unwrap(), Some(vec![3]));
+		std::mem::drop(db);
+		assert!(Db::open(&options).is_err());
+	}
+
 	#[test]
 	fn test_db_open_or_create() {
 		let tmp = tempdir().unwrap();
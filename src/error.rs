@@ -28,6 +28,7 @@ pub enum Error {
 	Background(Arc<Error>),
 	Locked(std::io::Error),
 	Migration(String),
+	ConditionFailed,
 }
 
 impl fmt::Display for Error {
@@ -40,10 +41,13 @@ impl fmt::Display for Error {
 			Error::Background(e) => write!(f, "Background worker error: {}", e),
 			Error::Locked(e) => write!(f, "Database file is in use. ({})", e),
 			Error::Migration(e) => write!(f, "Migration error: {}", e),
+			Error::ConditionFailed => write!(f, "Commit condition failed"),
 		}
     }
 }
 
+impl std::error::Error for Error {}
+
 impl From<std::io::Error> for Error {
 	fn from(e: std::io::Error) -> Self {
 		Error::Io(e)
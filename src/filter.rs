@@ -0,0 +1,193 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+// Bloom filter of the keys in a column, enabled with `ColumnOptions::filter_bytes`.
+//
+// Only the key prefix that is stored in the index is used, so the filter can be rebuilt from
+// the index tables. Keys are added when they are written to the log and are never removed,
+// so the filter may only give false positives. The filter is saved when the database is closed
+// and the file is removed once it is loaded, so it is rebuilt after a crash.
+//
+// File layout: version (u32), number of words (u64), words (u64 each), crc32 of the words.
+
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::{
+	error::Result,
+	index::KEY_PREFIX_BITS,
+	table::Key,
+};
+
+const FILTER_VERSION: u32 = 1;
+const FILTER_HASHES: u64 = 4;
+
+fn mix(mut x: u64) -> u64 {
+	x ^= x >> 30;
+	x = x.wrapping_mul(0xbf58476d1ce4e5b9);
+	x ^= x >> 27;
+	x = x.wrapping_mul(0x94d049bb133111eb);
+	x ^ (x >> 31)
+}
+
+pub struct Filter {
+	words: Vec<AtomicU64>,
+	// Set once the filter holds all keys of the column.
+	ready: AtomicBool,
+}
+
+impl Filter {
+	pub fn new(bytes: usize) -> Filter {
+		Filter {
+			words: (0 .. std::cmp::max(bytes / 8, 1)).map(|_| AtomicU64::new(0)).collect(),
+			ready: AtomicBool::new(false),
+		}
+	}
+
+	fn bits(&self, key: &Key) -> impl Iterator<Item = (usize, u64)> {
+		let prefix = u64::from_be_bytes(key[0..8].try_into().unwrap()) >> (64 - KEY_PREFIX_BITS);
+		let h1 = mix(prefix);
+		let h2 = mix(h1) | 1;
+		let total = self.words.len() as u64 * 64;
+		(0 .. FILTER_HASHES).map(move |i| {
+			let bit = h1.wrapping_add(i.wrapping_mul(h2)) % total;
+			((bit / 64) as usize, 1u64 << (bit % 64))
+		})
+	}
+
+	pub fn insert(&self, key: &Key) {
+		for (word, mask) in self.bits(key) {
+			self.words[word].fetch_or(mask, Ordering::Relaxed);
+		}
+	}
+
+	/// Returns `false` if the key is definitely not in the column.
+	pub fn may_contain(&self, key: &Key) -> bool {
+		!self.ready.load(Ordering::Acquire)
+			|| self.bits(key).all(|(word, mask)| self.words[word].load(Ordering::Relaxed) & mask != 0)
+	}
+
+	pub fn set_ready(&self) {
+		self.ready.store(true, Ordering::Release);
+	}
+
+	pub fn clear(&self) {
+		for w in self.words.iter() {
+			w.store(0, Ordering::Relaxed);
+		}
+	}
+
+	/// Load the saved filter and remove the file. Returns `false` if there's no valid file
+	/// saved with the same size.
+	pub fn load(&self, path: &std::path::Path) -> Result<bool> {
+		let mut file = match std::fs::File::open(path) {
+			Ok(file) => file,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+			Err(e) => return Err(e.into()),
+		};
+		let mut data = Vec::new();
+		file.read_to_end(&mut data)?;
+		std::mem::drop(file);
+		std::fs::remove_file(path)?;
+
+		let expected_len = 4 + 8 + self.words.len() * 8 + 4;
+		if data.len() != expected_len
+			|| data[0..4] != FILTER_VERSION.to_le_bytes()
+			|| data[4..12] != (self.words.len() as u64).to_le_bytes()
+		{
+			log::debug!(target: "parity-db", "Ignoring filter {}, size mismatch", path.display());
+			return Ok(false);
+		}
+		let words = &data[12..expected_len - 4];
+		if crc32fast::hash(words).to_le_bytes() != data[expected_len - 4..] {
+			log::warn!(target: "parity-db", "Ignoring filter {}, bad checksum", path.display());
+			return Ok(false);
+		}
+		for (w, bytes) in self.words.iter().zip(words.chunks_exact(8)) {
+			w.store(u64::from_le_bytes(bytes.try_into().unwrap()), Ordering::Relaxed);
+		}
+		Ok(true)
+	}
+
+	pub fn save(&self, path: &std::path::Path) -> Result<()> {
+		let mut words = Vec::with_capacity(self.words.len() * 8);
+		for w in self.words.iter() {
+			words.extend_from_slice(&w.load(Ordering::Relaxed).to_le_bytes());
+		}
+		let mut file = std::fs::File::create(path)?;
+		file.write_all(&FILTER_VERSION.to_le_bytes())?;
+		file.write_all(&(self.words.len() as u64).to_le_bytes())?;
+		file.write_all(&words)?;
+		file.write_all(&crc32fast::hash(&words).to_le_bytes())?;
+		file.sync_all()?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn key(n: u64) -> Key {
+		let mut key = Key::default();
+		key[0..8].copy_from_slice(&mix(n).to_be_bytes());
+		key
+	}
+
+	#[test]
+	fn test_filter() {
+		let filter = Filter::new(1024);
+		filter.insert(&key(1));
+		// Everything may be contained until the filter is ready.
+		assert!(filter.may_contain(&key(2)));
+		filter.set_ready();
+		assert!(filter.may_contain(&key(1)));
+		let false_positives = (2 .. 1002).filter(|n| filter.may_contain(&key(*n))).count();
+		assert!(false_positives < 10);
+		// Bits below the key prefix are ignored.
+		let mut suffix = key(1);
+		suffix[7] ^= 1;
+		suffix[31] ^= 1;
+		assert!(filter.may_contain(&suffix));
+		filter.clear();
+		assert!(!filter.may_contain(&key(1)));
+	}
+
+	#[test]
+	fn test_save_load() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("filter");
+		let filter = Filter::new(1024);
+		assert!(!filter.load(&path).unwrap());
+		filter.insert(&key(1));
+		filter.save(&path).unwrap();
+
+		let loaded = Filter::new(1024);
+		assert!(loaded.load(&path).unwrap());
+		loaded.set_ready();
+		assert!(loaded.may_contain(&key(1)));
+		// Removed once loaded.
+		assert!(!path.exists());
+
+		filter.save(&path).unwrap();
+		assert!(!Filter::new(2048).load(&path).unwrap());
+
+		filter.save(&path).unwrap();
+		let mut data = std::fs::read(&path).unwrap();
+		data[20] ^= 1;
+		std::fs::write(&path, data).unwrap();
+		assert!(!Filter::new(1024).load(&path).unwrap());
+	}
+}
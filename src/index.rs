@@ -30,9 +30,12 @@ const CHUNK_ENTRIES: usize = 1 << CHUNK_ENTRIES_BITS;
 const CHUNK_ENTRIES_BITS: u8 = 6;
 const HEADER_SIZE: usize = 512;
 const META_SIZE: usize = 16 * 1024; // Contains header and column stats
+const _: () = assert!(META_SIZE >= HEADER_SIZE + stats::TOTAL_SIZE);
 const KEY_LEN: usize = 32;
 const ENTRY_LEN: u8 = 64;
 pub const ENTRY_BYTES: usize = ENTRY_LEN as usize / 8;
+/// Number of leading key bits that are stored in the index, see `IndexTable::recover_key_prefix`.
+pub const KEY_PREFIX_BITS: u8 = ENTRY_LEN - CHUNK_ENTRIES_BITS - SIZE_TIERS_BITS;
 
 const EMPTY_CHUNK: Chunk = [0u8; CHUNK_LEN];
 
@@ -228,7 +231,6 @@ impl IndexTable {
 	}
 
 	pub fn load_stats(&self) -> ColumnStats {
-		debug_assert!(META_SIZE >= HEADER_SIZE + stats::TOTAL_SIZE);
 		if let Some(map) = &*self.map.read() {
 			ColumnStats::from_slice(&map[HEADER_SIZE .. HEADER_SIZE + stats::TOTAL_SIZE])
 		} else {
@@ -237,7 +239,6 @@ impl IndexTable {
 	}
 
 	pub fn write_stats(&self, stats: &ColumnStats) {
-		debug_assert!(META_SIZE >= HEADER_SIZE + stats::TOTAL_SIZE);
 		if let Some(map) = &mut *self.map.write() {
 			let mut slice = &mut map[HEADER_SIZE .. HEADER_SIZE + stats::TOTAL_SIZE];
 			stats.to_slice(&mut slice);
@@ -260,9 +261,9 @@ impl IndexTable {
 		return (Entry::empty(), 0)
 	}
 
-	// Only returns 54 bits of the actual key.
+	// Only returns `KEY_PREFIX_BITS` bits of the actual key.
 	pub fn recover_key_prefix(&self, chunk: u64, entry: Entry) -> Key {
-		// Restore first 54 bits of the key.
+		// Restore first `KEY_PREFIX_BITS` bits of the key.
 		let partial_key = entry.key_material(self.id.index_bits());
 		let k = 64 - Entry::address_bits(self.id.index_bits());
 		let index_key = (chunk << 64 - self.id.index_bits()) |
@@ -478,7 +479,11 @@ impl IndexTable {
 
 	pub fn drop_file(self) -> Result<()> {
 		std::mem::drop(self.map);
-		std::fs::remove_file(self.path.as_path())?;
+		match std::fs::remove_file(self.path.as_path()) {
+			// The file is only created on the first write.
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+			r => r?,
+		}
 		log::debug!(target: "parity-db", "{}: Dropped table", self.id);
 		Ok(())
 	}
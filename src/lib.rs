@@ -25,10 +25,22 @@ mod options;
 mod stats;
 mod compress;
 mod migration;
+mod btree;
+mod transaction;
+mod subscription;
+mod cache;
+mod filter;
+#[cfg(feature = "metrics")]
+mod metrics;
 
-pub use db::{Db, Value, check::CheckOptions};
+pub use db::{Db, Value, ColumnIterator, BTreeIterator, Snapshot, CommitHandle, CommitFuture, CommitWait, ValueReader, Health, WorkerState, check::CheckOptions};
+pub use column::{ColId, ITER_CHUNKS};
 pub use table::Key;
 pub use error::{Error, Result};
-pub use options::{ColumnOptions, Options};
+pub use options::{ColumnOptions, Options, PipelineOptions};
+pub use stats::{ColumnStatsSnapshot, PipelineStats};
 pub use migration::migrate;
 pub use compress::CompressionType;
+pub use btree::MAX_KEY_LEN as MAX_BTREE_KEY_LEN;
+pub use transaction::{Condition, Operation, Transaction};
+pub use subscription::{Change, ChangeFilter, ChangeSet, ChangeStage};
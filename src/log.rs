@@ -20,14 +20,13 @@ use std::convert::TryInto;
 use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU32, Ordering};
 use parking_lot::{Condvar, Mutex, RwLock, RwLockWriteGuard, MappedRwLockWriteGuard};
 use crate::{
+	column::ColId,
 	error::{Error, Result},
 	table::TableId as ValueTableId,
 	index::{TableId as IndexTableId, Chunk as IndexChunk, ENTRY_BYTES},
 	options::Options,
 };
 
-const MAX_LOG_POOL_SIZE: usize = 16;
-
 pub struct InsertIndexAction {
 	pub table: IndexTableId,
 	pub index: u64,
@@ -43,6 +42,7 @@ pub enum LogAction {
 	InsertIndex(InsertIndexAction),
 	InsertValue(InsertValueAction),
 	DropTable(IndexTableId),
+	ClearColumn(ColId),
 	EndRecord,
 }
 
@@ -57,21 +57,29 @@ pub struct LogOverlays {
 	value: HashMap<ValueTableId, ValueLogOverlay>,
 }
 
-impl LogQuery for RwLock<LogOverlays> {
+impl LogQuery for LogOverlays {
 	fn with_index<R, F: FnOnce(&IndexChunk) -> R> (&self, table: IndexTableId, index: u64, f: F) -> Option<R> {
-		self.read().index.get(&table).and_then(|o| o.map.get(&index).map(|(_id, _mask, data)| f(data)))
+		self.index.get(&table).and_then(|o| o.map.get(&index).map(|(_id, _mask, data)| f(data)))
 	}
 
 	fn value(&self, table: ValueTableId, index: u64, dest: &mut[u8]) -> bool {
-		let s = self.read();
-		if let Some(d) = s.value.get(&table).and_then(|o| o.map.get(&index).map(|(_id, data)| data)) {
+		if let Some(d) = self.value.get(&table).and_then(|o| o.map.get(&index).map(|(_id, data)| data)) {
 			let len = dest.len().min(d.len());
 			dest[0..len].copy_from_slice(&d[0..len]);
 			true
 		} else {
 			false
 		}
+	}
+}
 
+impl LogQuery for RwLock<LogOverlays> {
+	fn with_index<R, F: FnOnce(&IndexChunk) -> R> (&self, table: IndexTableId, index: u64, f: F) -> Option<R> {
+		self.read().with_index(table, index, f)
+	}
+
+	fn value(&self, table: ValueTableId, index: u64, dest: &mut[u8]) -> bool {
+		self.read().value(table, index, dest)
 	}
 }
 
@@ -176,7 +184,11 @@ impl<'a> LogReader<'a> {
 				read_buf(2, &mut buf)?;
 				let table = IndexTableId::from_u16(u16::from_le_bytes(buf[0..2].try_into().unwrap()));
 				Ok(LogAction::DropTable(table))
-			}
+			},
+			6 => { // ClearColumn
+				read_buf(1, &mut buf)?;
+				Ok(LogAction::ClearColumn(buf[0]))
+			},
 			_ => {
 				Err(Error::Corruption("Bad log entry type".into()))
 			}
@@ -206,6 +218,7 @@ pub struct LogChange {
 	local_values: HashMap<ValueTableId, ValueLogOverlay>,
 	record_id: u64,
 	dropped_tables: Vec<IndexTableId>,
+	cleared_columns: Vec<ColId>,
 }
 
 impl LogChange {
@@ -216,6 +229,7 @@ impl LogChange {
 			local_index: Default::default(),
 			local_values: Default::default(),
 			dropped_tables: Default::default(),
+			cleared_columns: Default::default(),
 			record_id,
 		}
 	}
@@ -263,6 +277,11 @@ impl LogChange {
 			write(&5u8.to_le_bytes().as_ref())?;
 			write(&id.as_u16().to_le_bytes())?;
 		}
+		for col in self.cleared_columns.iter() {
+			log::debug!(target: "parity-db", "Finalizing clear of column {}", col);
+			write(&6u8.to_le_bytes())?;
+			write(&[*col])?;
+		}
 
 		write(&4u8.to_le_bytes())?; // End record
 		let checksum: u32 = crc32.finalize();
@@ -312,6 +331,10 @@ impl<'a> LogWriter<'a> {
 		self.log.dropped_tables.push(id);
 	}
 
+	pub fn clear_column(&mut self, col: ColId) {
+		self.log.cleared_columns.push(col);
+	}
+
 	pub fn drain(self) -> LogChange {
 		self.log
 	}
@@ -372,11 +395,54 @@ struct Appending {
 	id: u32,
 	file: std::io::BufWriter<std::fs::File>,
 	size: u64,
+	last_record: u64,
+}
+
+// Log record written to the log file, but not added to the log overlays yet.
+// The log file is locked for writing until the record is applied.
+pub struct LogRecord<'a> {
+	log: &'a Log,
+	appending: RwLockWriteGuard<'a, Option<Appending>>,
+	record_id: u64,
+	index: HashMap<IndexTableId, IndexLogOverlay>,
+	values: HashMap<ValueTableId, ValueLogOverlay>,
+	bytes: u64,
+}
+
+impl<'a> LogRecord<'a> {
+	// Make the record visible in the log overlays. Returns the record size.
+	pub fn apply(self) -> u64 {
+		let LogRecord { log, mut appending, record_id, index, values, bytes } = self;
+		let mut overlays = log.overlays.write();
+		let mut total_index = 0;
+		for (id, overlay) in index.into_iter() {
+			total_index += overlay.map.len();
+			overlays.index.entry(id).or_default().map.extend(overlay.map);
+		}
+		let mut total_value = 0;
+		for (id, overlay) in values.into_iter() {
+			total_value += overlay.map.len();
+			overlays.value.entry(id).or_default().map.extend(overlay.map);
+		}
+		log::debug!(
+			target: "parity-db",
+			"Finalizing log record {} ({} index, {} value)",
+			record_id,
+			total_index,
+			total_value,
+		);
+		let appending = appending.as_mut().unwrap();
+		appending.size += bytes;
+		appending.last_record = record_id;
+		log.dirty.store(true, Ordering::Relaxed);
+		bytes
+	}
 }
 
 struct Flushing {
 	id: u32,
 	file: std::fs::File,
+	last_record: u64,
 }
 
 struct Reading {
@@ -398,6 +464,8 @@ pub struct Log {
 	done_reading_cv: Condvar,
 	flushing: Mutex<Option<Flushing>>,
 	next_record_id: AtomicU64,
+	// Last record written to disk.
+	durable_record_id: AtomicU64,
 	dirty: AtomicBool,
 	log_pool: RwLock<VecDeque<(u32, std::fs::File)>>,
 	cleanup_queue: RwLock<VecDeque<(u32, std::fs::File)>>,
@@ -405,6 +473,7 @@ pub struct Log {
 	path: std::path::PathBuf,
 	next_log_id: AtomicU32,
 	sync: bool,
+	max_pool_size: usize,
 }
 
 impl Log {
@@ -444,10 +513,13 @@ impl Log {
 			reading_state: Mutex::new(ReadingState::Idle),
 			done_reading_cv: Condvar::new(),
 			flushing: Mutex::new(None),
-			next_record_id: AtomicU64::new(1),
+			// Records up to 1 are considered enacted on open, see `DbInner::open`.
+			next_record_id: AtomicU64::new(2),
+			durable_record_id: AtomicU64::new(0),
 			next_log_id: AtomicU32::new(next_log_id),
 			dirty: AtomicBool::new(true),
 			sync: options.sync_wal,
+			max_pool_size: options.pipeline.max_log_pool_size,
 			replay_queue: RwLock::new(logs),
 			cleanup_queue: RwLock::new(VecDeque::new()),
 			log_pool: RwLock::new(Default::default()),
@@ -520,6 +592,11 @@ impl Log {
 	}
 
 	pub fn end_record(&self, log: LogChange) -> Result<u64> {
+		Ok(self.write_record(log)?.apply())
+	}
+
+	// Write the record to the log file. The changes are visible once the record is applied.
+	pub fn write_record(&self, log: LogChange) -> Result<LogRecord<'_>> {
 		assert!(log.record_id + 1 == self.next_record_id.load(Ordering::Relaxed));
 		let record_id = log.record_id;
 		let mut appending = self.appending.write();
@@ -540,31 +617,11 @@ impl Log {
 				size: 0,
 				file: std::io::BufWriter::new(file),
 				id,
+				last_record: record_id,
 			});
 		}
-		let appending = appending.as_mut().unwrap();
-		let (index, values, bytes) = log.to_file(&mut appending.file)?;
-		let mut overlays = self.overlays.write();
-		let mut total_index = 0;
-		for (id, overlay) in index.into_iter() {
-			total_index += overlay.map.len();
-			overlays.index.entry(id).or_default().map.extend(overlay.map.into_iter());
-		}
-		let mut total_value = 0;
-		for (id, overlay) in values.into_iter() {
-			total_value += overlay.map.len();
-			overlays.value.entry(id).or_default().map.extend(overlay.map.into_iter());
-		}
-		log::debug!(
-			target: "parity-db",
-			"Finalizing log record {} ({} index, {} value)",
-			record_id,
-			total_index,
-			total_value,
-		);
-		appending.size += bytes;
-		self.dirty.store(true, Ordering::Relaxed);
-		Ok(bytes)
+		let (index, values, bytes) = log.to_file(&mut appending.as_mut().unwrap().file)?;
+		Ok(LogRecord { log: self, appending, record_id, index, values, bytes })
 	}
 
 	pub fn end_read(&self, cleared: Cleared, record_id: u64) {
@@ -645,6 +702,7 @@ impl Log {
 				*flushing = to_flush.map(|to_flush| Flushing {
 					file: to_flush.file.into_inner().unwrap(),
 					id: to_flush.id,
+					last_record: to_flush.last_record,
 				});
 			}
 		}
@@ -657,6 +715,9 @@ impl Log {
 				log::debug!(target: "parity-db", "Flush: Flushing log completed");
 			}
 		}
+		if let Some(flushing) = flushing.as_ref() {
+			self.durable_record_id.store(flushing.last_record, Ordering::SeqCst);
+		}
 
 		Ok((flushing.is_some(), read_next, cleanup))
 	}
@@ -698,8 +759,8 @@ impl Log {
 		pool.extend(cleaned);
 		// Sort to reuse lower IDs an prevent IDs from growing.
 		pool.make_contiguous().sort_by_key(|(id, _)| *id);
-		if pool.len() > MAX_LOG_POOL_SIZE {
-			let removed = pool.drain(MAX_LOG_POOL_SIZE..);
+		if pool.len() > self.max_pool_size {
+			let removed = pool.drain(self.max_pool_size..);
 			for (id, file) in removed {
 				std::mem::drop(file);
 				self.drop_log(id)?;
@@ -708,6 +769,11 @@ impl Log {
 		Ok(!self.cleanup_queue.read().is_empty())
 	}
 
+	/// Id of the last record that was written to disk. The record is synced if `sync_wal` is enabled.
+	pub fn durable_record_id(&self) -> u64 {
+		self.durable_record_id.load(Ordering::SeqCst)
+	}
+
 	pub fn num_dirty_logs(&self) -> usize {
 		self.cleanup_queue.read().len()
 	}
@@ -745,6 +811,15 @@ impl Log {
 		&self.overlays
 	}
 
+	/// Close the log file that is being written without flushing buffered records.
+	pub fn discard_appending(&self) {
+		if let Some(appending) = self.appending.write().take() {
+			log::debug!(target: "parity-db", "Discarding log {}", appending.id);
+			let (file, _) = appending.file.into_parts();
+			std::mem::drop(file);
+		}
+	}
+
 	pub fn kill_logs(&self) -> Result<()> {
 		let mut log_pool = self.log_pool.write();
 		for (id, file) in log_pool.drain(..) {
@@ -0,0 +1,214 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+// Database metrics in OpenMetrics text format.
+
+use std::fmt::Display;
+use std::io::Write;
+use crate::{
+	error::Result,
+	column::ColId,
+	stats::{ColumnStatsSnapshot, PipelineStats, LATENCY_BUCKETS},
+};
+
+const PREFIX: &str = "parity_db";
+
+pub(crate) struct TableMetrics {
+	pub size_tier: u8,
+	pub entry_size: u16,
+	pub filled: u64,
+	pub capacity: u64,
+	pub file_bytes: u64,
+}
+
+pub(crate) struct ColumnMetrics {
+	pub col: ColId,
+	pub stats: ColumnStatsSnapshot,
+	pub index_bits: u8,
+	// Number of old index tables waiting to be reindexed.
+	pub reindex_tables: usize,
+	// Reindexed and total chunks of the table that is currently being reindexed.
+	pub reindex_progress: u64,
+	pub reindex_chunks: u64,
+	// Only tables that have a file.
+	pub tables: Vec<TableMetrics>,
+}
+
+pub(crate) struct Metrics {
+	pub pipeline: PipelineStats,
+	pub queued_commits: usize,
+	pub columns: Vec<ColumnMetrics>,
+}
+
+type ColumnValue = fn(&ColumnMetrics) -> u64;
+
+const COLUMN_COUNTERS: &[(&str, &str, ColumnValue)] = &[
+	("commits", "Commits that modified the column.", |c| c.stats.commits),
+	("inserted_new", "Inserted new keys.", |c| c.stats.inserted_new),
+	("inserted_overwrite", "Overwritten existing keys.", |c| c.stats.inserted_overwrite),
+	("removed_hit", "Removed existing keys.", |c| c.stats.removed_hit),
+	("removed_miss", "Removals of missing keys.", |c| c.stats.removed_miss),
+	("queries_miss", "Queries for missing keys.", |c| c.stats.queries_miss),
+	("cache_hits", "Value cache hits.", |c| c.stats.cache_hits),
+	("cache_misses", "Value cache misses.", |c| c.stats.cache_misses),
+];
+
+const COLUMN_GAUGES: &[(&str, &str, ColumnValue)] = &[
+	("values", "Number of values in the column.", |c| c.stats.total_values),
+	("value_bytes", "Total size of values in the column.", |c| c.stats.total_bytes),
+	("uncompressed_bytes", "Total size of compressed values before compression.", |c| c.stats.uncompressed_bytes),
+	("oversized_values", "Number of values stored in multiple entries.", |c| c.stats.oversized),
+	("oversized_bytes", "Total size of values stored in multiple entries.", |c| c.stats.oversized_bytes),
+	("index_bits", "Number of bits addressed by the index table.", |c| c.index_bits as u64),
+	("reindex_tables", "Index tables waiting to be reindexed.", |c| c.reindex_tables as u64),
+	("reindex_progress_chunks", "Reindexed chunks of the index table that is being reindexed.", |c| c.reindex_progress),
+	("reindex_total_chunks", "Total chunks of the index table that is being reindexed.", |c| c.reindex_chunks),
+];
+
+type ColumnHistogram = fn(&ColumnMetrics) -> &[u64];
+
+const LATENCY_HISTOGRAMS: &[(&str, &str, ColumnHistogram)] = &[
+	("get_latency_seconds", "Duration of get calls.", |c| &c.stats.get_latency),
+	("logged_latency_seconds", "Time from queueing a commit until it is written to the log.", |c| &c.stats.logged_latency),
+	("durable_latency_seconds", "Time from writing a commit to the log until it is flushed.", |c| &c.stats.durable_latency),
+	("enacted_latency_seconds", "Time from flushing a commit until it is enacted.", |c| &c.stats.enacted_latency),
+	("read_latency_seconds", "Duration of value table reads.", |c| &c.stats.read_latency),
+];
+
+type TableValue = fn(&TableMetrics) -> u64;
+
+const TABLE_GAUGES: &[(&str, &str, TableValue)] = &[
+	("table_entry_bytes", "Value table entry size.", |t| t.entry_size as u64),
+	("table_filled_entries", "Highest value table entry that was ever used.", |t| t.filled),
+	("table_capacity_entries", "Value table capacity.", |t| t.capacity),
+	("table_file_bytes", "Value table file size.", |t| t.file_bytes),
+];
+
+fn family(w: &mut impl Write, name: &str, kind: &str, help: &str) -> Result<()> {
+	writeln!(w, "# TYPE {}_{} {}", PREFIX, name, kind)?;
+	writeln!(w, "# HELP {}_{} {}", PREFIX, name, help)?;
+	Ok(())
+}
+
+fn sample(w: &mut impl Write, name: &str, labels: &str, value: impl Display) -> Result<()> {
+	if labels.is_empty() {
+		writeln!(w, "{}_{} {}", PREFIX, name, value)?;
+	} else {
+		writeln!(w, "{}_{}{{{}}} {}", PREFIX, name, labels, value)?;
+	}
+	Ok(())
+}
+
+impl Metrics {
+	pub fn write(&self, w: &mut impl Write) -> Result<()> {
+		family(w, "commit_queue_commits", "gauge", "Commits waiting to be written to the log.")?;
+		sample(w, "commit_queue_commits", "", self.queued_commits)?;
+		family(w, "commit_queue_bytes", "gauge", "Size of keys and values in the commit queue.")?;
+		sample(w, "commit_queue_bytes", "", self.pipeline.commit_queue_bytes)?;
+		family(w, "log_queue_bytes", "gauge", "Size of log records that are not enacted yet.")?;
+		sample(w, "log_queue_bytes", "", self.pipeline.log_queue_bytes)?;
+		family(w, "last_enacted_record", "gauge", "Last log record enacted into the tables.")?;
+		sample(w, "last_enacted_record", "", self.pipeline.last_enacted_record)?;
+
+		for (name, help, value) in COLUMN_COUNTERS {
+			family(w, name, "counter", help)?;
+			for c in &self.columns {
+				sample(w, &format!("{}_total", name), &format!("column=\"{}\"", c.col), value(c))?;
+			}
+		}
+		family(w, "queries_hit", "counter", "Queries for existing keys, by value size tier.")?;
+		for c in &self.columns {
+			for (tier, count) in c.stats.query_histogram.iter().enumerate().filter(|(_, count)| **count != 0) {
+				sample(w, "queries_hit_total", &format!("column=\"{}\",tier=\"{}\"", c.col, tier), count)?;
+			}
+		}
+		for (name, help, value) in COLUMN_GAUGES {
+			family(w, name, "gauge", help)?;
+			for c in &self.columns {
+				sample(w, name, &format!("column=\"{}\"", c.col), value(c))?;
+			}
+		}
+		for (name, help, value) in LATENCY_HISTOGRAMS {
+			family(w, name, "histogram", help)?;
+			for c in &self.columns {
+				// Bucket `i` counts durations below `2^i` microseconds, the last one is unbounded.
+				let mut count = 0;
+				for (i, n) in value(c).iter().enumerate() {
+					count += n;
+					let le = if i == LATENCY_BUCKETS - 1 {
+						"+Inf".to_string()
+					} else {
+						((1u64 << i) as f64 / 1_000_000.0).to_string()
+					};
+					sample(w, &format!("{}_bucket", name), &format!("column=\"{}\",le=\"{}\"", c.col, le), count)?;
+				}
+				sample(w, &format!("{}_count", name), &format!("column=\"{}\"", c.col), count)?;
+			}
+		}
+		for (name, help, value) in TABLE_GAUGES {
+			family(w, name, "gauge", help)?;
+			for c in &self.columns {
+				for t in &c.tables {
+					sample(w, name, &format!("column=\"{}\",tier=\"{}\"", c.col, t.size_tier), value(t))?;
+				}
+			}
+		}
+		writeln!(w, "# EOF")?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_write() {
+		let metrics = Metrics {
+			pipeline: PipelineStats { commit_queue_bytes: 10, log_queue_bytes: -1, last_enacted_record: 3 },
+			queued_commits: 2,
+			columns: vec![ColumnMetrics {
+				col: 1,
+				stats: ColumnStatsSnapshot {
+					query_histogram: vec![0, 5],
+					commits: 4,
+					get_latency: { let mut h = vec![0; LATENCY_BUCKETS]; h[1] = 2; h[LATENCY_BUCKETS - 1] = 1; h },
+					..Default::default()
+				},
+				index_bits: 16,
+				reindex_tables: 1,
+				reindex_progress: 7,
+				reindex_chunks: 8,
+				tables: vec![TableMetrics { size_tier: 1, entry_size: 64, filled: 2, capacity: 4, file_bytes: 256 }],
+			}],
+		};
+		let mut out = Vec::new();
+		metrics.write(&mut out).unwrap();
+		let out = String::from_utf8(out).unwrap();
+		assert!(out.contains("# TYPE parity_db_commits counter\n"));
+		assert!(out.contains("\nparity_db_commits_total{column=\"1\"} 4\n"));
+		assert!(out.contains("\nparity_db_queries_hit_total{column=\"1\",tier=\"1\"} 5\n"));
+		assert!(!out.contains("tier=\"0\""));
+		assert!(out.contains("\nparity_db_log_queue_bytes -1\n"));
+		assert!(out.contains("\nparity_db_reindex_progress_chunks{column=\"1\"} 7\n"));
+		assert!(out.contains("\nparity_db_table_file_bytes{column=\"1\",tier=\"1\"} 256\n"));
+		assert!(out.contains("\nparity_db_get_latency_seconds_bucket{column=\"1\",le=\"0.000001\"} 0\n"));
+		assert!(out.contains("\nparity_db_get_latency_seconds_bucket{column=\"1\",le=\"0.000002\"} 2\n"));
+		assert!(out.contains("\nparity_db_get_latency_seconds_bucket{column=\"1\",le=\"+Inf\"} 3\n"));
+		assert!(out.contains("\nparity_db_get_latency_seconds_count{column=\"1\"} 3\n"));
+		assert!(out.ends_with("# EOF\n"));
+	}
+}
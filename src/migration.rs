@@ -16,6 +16,7 @@
 
 /// Database migration.
 
+use std::io::Write;
 use std::path::Path;
 use crate::{options::Options, db::Db, Error, Result, column::{ColId, IterState}};
 
@@ -35,6 +36,11 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &Ve
 	if source_meta.columns.len() != to.columns.len() {
 		return Err(Error::Migration("Source and dest columns mismatch".into()));
 	}
+	for (c, (source, dest)) in source_meta.columns.iter().zip(to.columns.iter()).enumerate() {
+		if source.btree_index != dest.btree_index {
+			return Err(Error::Migration(format!("Can't change btree index for column {}", c)));
+		}
+	}
 
 	// Make sure we are using the same salt value.
 	to.salt = Some(source_meta.salt);
@@ -68,7 +74,7 @@ pub fn migrate(from: &Path, mut to: Options, overwrite: bool, force_migrate: &Ve
 			continue;
 		}
 		log::info!("Migrating col {}", c);
-		source.iter_column_while(c, |IterState { chunk_index: index, key, rc, mut value }| {
+		source.iter_column_while(c, |IterState { chunk_index: index, key, rc, mut value, .. }| {
 			//TODO: more efficient ref migration
 			for _ in 0 .. rc {
 				let value = std::mem::take(&mut value);
@@ -158,6 +164,133 @@ fn deplace_column(c: ColId, from: &Path, to: &Path, copy: bool) -> Result<()> {
 	Ok(())
 }
 
+pub(crate) fn remove_column_files(c: ColId, path: &Path) -> Result<()> {
+	for entry in std::fs::read_dir(path)? {
+		let entry = entry?;
+		if let Some(file) = entry.path().file_name().and_then(|f| f.to_str()) {
+			if crate::index::TableId::is_file_name(c, file)
+				|| crate::table::TableId::is_file_name(c, file)
+				|| file == format!("filter_{:02}", c) {
+				std::fs::remove_file(entry.path())?;
+			}
+		}
+	}
+	Ok(())
+}
+
+// Rename files of column `from` to belong to column `to`.
+pub(crate) fn renumber_column_files(from: ColId, to: ColId, path: &Path) -> Result<()> {
+	for entry in std::fs::read_dir(path)? {
+		let entry = entry?;
+		if let Some(file) = entry.path().file_name().and_then(|f| f.to_str()) {
+			let renamed = if crate::index::TableId::is_file_name(from, file) {
+				file.replacen(&format!("index_{:02}_", from), &format!("index_{:02}_", to), 1)
+			} else if crate::table::TableId::is_file_name(from, file) {
+				file.replacen(&format!("table_{:02}_", from), &format!("table_{:02}_", to), 1)
+			} else if file == format!("filter_{:02}", from) {
+				format!("filter_{:02}", to)
+			} else {
+				continue;
+			};
+			let mut to_path = path.to_path_buf();
+			to_path.push(renamed);
+			std::fs::rename(entry.path(), to_path)?;
+		}
+	}
+	Ok(())
+}
+
+const DROP_COLUMN_FILE: &str = "drop_column";
+
+// Progress of a column drop, saved in the database directory. The drop takes effect once the metadata
+// without the column is written. The files of the dropped column are then removed and the files of
+// the following columns are renumbered one column at a time, saving the progress after each step.
+// An interrupted drop is finished when the database is opened.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct DropColumn {
+	// Dropped column.
+	col: ColId,
+	// Number of columns before the drop.
+	columns: usize,
+	// Next column to renumber. Equals `col` until the files of the dropped column are removed.
+	next: usize,
+}
+
+impl DropColumn {
+	fn file_path(path: &Path) -> std::path::PathBuf {
+		path.join(DROP_COLUMN_FILE)
+	}
+
+	pub fn load(path: &Path) -> Result<Option<DropColumn>> {
+		let file_path = Self::file_path(path);
+		if !file_path.exists() {
+			return Ok(None);
+		}
+		let content = std::fs::read_to_string(file_path)?;
+		let values: Vec<usize> = content.split_whitespace().map(|v| v.parse())
+			.collect::<std::result::Result<_, _>>()
+			.map_err(|_| Error::Corruption("Bad drop_column file".into()))?;
+		match values[..] {
+			[col, columns, next] if col < columns && col <= next && next <= columns => {
+				Ok(Some(DropColumn { col: col as ColId, columns, next }))
+			},
+			_ => Err(Error::Corruption("Bad drop_column file".into())),
+		}
+	}
+
+	// The file is replaced atomically.
+	fn save(&self, path: &Path) -> Result<()> {
+		let file_path = Self::file_path(path);
+		let tmp_path = file_path.with_extension("tmp");
+		{
+			let mut file = std::fs::File::create(&tmp_path)?;
+			writeln!(file, "{} {} {}", self.col, self.columns, self.next)?;
+			file.sync_all()?;
+		}
+		std::fs::rename(&tmp_path, file_path)?;
+		Ok(())
+	}
+
+	// Start dropping column `col` out of `columns`. Must be followed by writing the metadata without the column.
+	pub fn start(path: &Path, col: ColId, columns: usize) -> Result<DropColumn> {
+		let drop = DropColumn { col, columns, next: col as usize };
+		drop.save(path)?;
+		Ok(drop)
+	}
+
+	// Finish an interrupted drop, or discard it if the metadata was not written.
+	pub fn resume(path: &Path) -> Result<()> {
+		let mut drop = match Self::load(path)? {
+			Some(drop) => drop,
+			None => return Ok(()),
+		};
+		let columns = Options::load_metadata(&path.join("metadata"))?.map_or(0, |m| m.columns.len());
+		if columns == drop.columns {
+			log::info!(target: "parity-db", "Discarding the drop of column {}", drop.col);
+			std::fs::remove_file(Self::file_path(path))?;
+			return Ok(());
+		}
+		while drop.step(path)? {}
+		Ok(())
+	}
+
+	// Remove or renumber the files of the next column. Returns `false` once the drop is complete.
+	pub fn step(&mut self, path: &Path) -> Result<bool> {
+		if self.next == self.col as usize {
+			remove_column_files(self.col, path)?;
+		} else if self.next < self.columns {
+			renumber_column_files(self.next as ColId, self.next as ColId - 1, path)?;
+		} else {
+			std::fs::remove_file(Self::file_path(path))?;
+			log::info!(target: "parity-db", "Dropped column {}", self.col);
+			return Ok(false);
+		}
+		self.next += 1;
+		self.save(path)?;
+		Ok(true)
+	}
+}
+
 #[cfg(test)]
 mod test {
 	use crate::{Db, Options, migration::migrate};
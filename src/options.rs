@@ -22,7 +22,7 @@ use crate::column::Salt;
 use crate::compress::CompressionType;
 use rand::Rng;
 
-pub const CURRENT_VERSION: u32 = 5;
+pub const CURRENT_VERSION: u32 = 6;
 // TODO on last supported 5, remove MULTIHEAD_V4 and MULTIPART_V4
 const LAST_SUPPORTED_VERSION: u32 = 4;
 
@@ -44,6 +44,62 @@ pub struct Options {
 	/// Override salt value. If `None` is specified salt is loaded from metadata
 	/// or randomly generated when creating a new database.
 	pub salt: Option<Salt>,
+	/// Write pipeline limits.
+	pub pipeline: PipelineOptions,
+}
+
+/// Limits of the write pipeline. Smaller values reduce memory and disk usage at the cost of
+/// commit throughput and latency.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PipelineOptions {
+	/// Size of keys and values in the commit queue. Further commits block while the queue is larger.
+	pub max_commit_queue_bytes: usize,
+	/// Size of log records that are written but not enacted yet. Commits are not logged while
+	/// the queue is larger.
+	pub max_log_queue_bytes: u64,
+	/// Log files are flushed once they reach this size, unless a flush is requested earlier.
+	pub min_log_size: u64,
+	/// Number of enacted log files to keep when `sync_data` is off.
+	pub keep_logs: usize,
+	/// Number of empty log files kept for reuse.
+	pub max_log_pool_size: usize,
+	/// Maximum number of index entries moved by a single reindex record.
+	pub max_rebalance_batch: usize,
+}
+
+impl Default for PipelineOptions {
+	fn default() -> PipelineOptions {
+		PipelineOptions {
+			max_commit_queue_bytes: 16 * 1024 * 1024,
+			max_log_queue_bytes: 128 * 1024 * 1024,
+			min_log_size: 64 * 1024 * 1024,
+			keep_logs: 16,
+			max_log_pool_size: 16,
+			max_rebalance_batch: 8192,
+		}
+	}
+}
+
+impl PipelineOptions {
+	pub fn validate(&self) -> Result<()> {
+		let invalid = |msg: String| Err(Error::InvalidConfiguration(msg));
+		if self.max_commit_queue_bytes == 0 {
+			return invalid("max_commit_queue_bytes must not be 0".into());
+		}
+		if self.max_log_queue_bytes == 0 || self.max_log_queue_bytes > i64::MAX as u64 {
+			return invalid(format!("Invalid max_log_queue_bytes {}", self.max_log_queue_bytes));
+		}
+		// Logs are only enacted once flushed, so a larger minimum would stall the log queue.
+		if self.min_log_size > self.max_log_queue_bytes {
+			return invalid(format!(
+				"min_log_size {} exceeds max_log_queue_bytes {}", self.min_log_size, self.max_log_queue_bytes
+			));
+		}
+		if self.max_rebalance_batch == 0 {
+			return invalid("max_rebalance_batch must not be 0".into());
+		}
+		Ok(())
+	}
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -64,6 +120,16 @@ pub struct ColumnOptions {
 	pub compression: CompressionType,
 	/// Minimal value size threshold to attempt compressing a value.
 	pub compression_treshold: u32,
+	/// Keep keys ordered, allowing ordered and prefix iteration with `Db::iter_ordered`
+	/// and `Db::iter_prefix`. Keys are limited to `MAX_BTREE_KEY_LEN` bytes.
+	/// Can't be combined with `preimage`, `uniform` or `ref_counted`.
+	pub btree_index: bool,
+	/// Memory budget of the cache of recently read values. Disabled when 0. Not stored in the
+	/// metadata, so it can be changed on each open.
+	pub cache_bytes: usize,
+	/// Size of the in-memory Bloom filter that answers queries for missing keys without reading
+	/// the index. Disabled when 0. Not stored in the metadata.
+	pub filter_bytes: usize,
 }
 
 
@@ -80,11 +146,12 @@ pub struct Metadata {
 
 impl ColumnOptions {
 	fn as_string(&self) -> String {
-		format!("preimage: {}, uniform: {}, refc: {}, compression: {}, sizes: [{}]",
+		format!("preimage: {}, uniform: {}, refc: {}, compression: {}, btree: {}, sizes: [{}]",
 			self.preimage,
 			self.uniform,
 			self.ref_counted,
 			self.compression as u8,
+			self.btree_index,
 			self.sizes.iter().fold(String::new(), |mut r, s| {
 				if !r.is_empty() {
 					r.push_str(", ");
@@ -104,13 +171,20 @@ impl ColumnOptions {
 				return false;
 			}
 		}
+		if self.btree_index && (self.preimage || self.uniform || self.ref_counted) {
+			return false;
+		}
 		true
 	}
 
-	fn from_string(s: &str) -> Option<Self> {
+	fn from_string(s: &str) -> Result<Self> {
+		let bad = || Error::Corruption("Bad column metadata".into());
 		let mut split = s.split("sizes: ");
-		let vals = split.next()?;
-		let sizes = split.next()?;
+		let vals = split.next().ok_or_else(bad)?;
+		let sizes = split.next().ok_or_else(bad)?;
+		if sizes.len() < 2 {
+			return Err(bad());
+		}
 		let sizes = &sizes[1..sizes.len() - 1];
 		let sizes: Vec<u16> = sizes.split(",").filter_map(|v| v.trim().parse().ok()).collect();
 
@@ -119,18 +193,23 @@ impl ColumnOptions {
 			Some((pair.next()?, pair.next()?))
 		}).collect();
 
-		let preimage = vals.get("preimage")?.parse().ok()?;
-		let uniform = vals.get("uniform")?.parse().ok()?;
-		let ref_counted = vals.get("refc")?.parse().ok()?;
+		let flag = |name| vals.get(name).and_then(|v| v.parse().ok()).ok_or_else(bad);
+		let preimage = flag("preimage")?;
+		let uniform = flag("uniform")?;
+		let ref_counted = flag("refc")?;
 		let compression: u8 = vals.get("compression").and_then(|c| c.parse().ok()).unwrap_or(0);
+		let btree_index = vals.get("btree").and_then(|c| c.parse().ok()).unwrap_or(false);
 
-		Some(ColumnOptions {
+		Ok(ColumnOptions {
 			preimage,
 			uniform,
 			ref_counted,
-			compression: compression.into(),
+			compression: compression.try_into()?,
 			sizes,
 			compression_treshold: ColumnOptions::default().compression_treshold,
+			btree_index,
+			cache_bytes: 0,
+			filter_bytes: 0,
 		})
 	}
 }
@@ -156,6 +235,9 @@ impl Default for ColumnOptions {
 			ref_counted: false,
 			compression: CompressionType::NoCompression,
 			compression_treshold: 4096,
+			btree_index: false,
+			cache_bytes: 0,
+			filter_bytes: 0,
 			sizes,
 		}
 	}
@@ -169,17 +251,28 @@ impl Options {
 			sync_data: true,
 			stats: true,
 			salt: None,
+			pipeline: Default::default(),
 			columns: (0..num_columns).map(|_| Default::default()).collect(),
 		}
 	}
 
 	pub fn write_metadata(&self, path: &std::path::Path, salt: &Salt) -> Result<()> {
-		let mut file = std::fs::File::create(path)?;
-		writeln!(file, "version={}", CURRENT_VERSION)?;
-		writeln!(file, "salt={}", hex::encode(salt))?;
-		for i in 0..self.columns.len() {
-			writeln!(file, "col{}={}", i, self.columns[i].as_string())?;
+		self.write_metadata_with_version(path, salt, CURRENT_VERSION)
+	}
+
+	/// Write the metadata file. The file is replaced atomically.
+	pub fn write_metadata_with_version(&self, path: &std::path::Path, salt: &Salt, version: u32) -> Result<()> {
+		let tmp_path = path.with_extension("tmp");
+		{
+			let mut file = std::fs::File::create(&tmp_path)?;
+			writeln!(file, "version={}", version)?;
+			writeln!(file, "salt={}", hex::encode(salt))?;
+			for i in 0..self.columns.len() {
+				writeln!(file, "col{}={}", i, self.columns[i].as_string())?;
+			}
+			file.sync_all()?;
 		}
+		std::fs::rename(&tmp_path, path)?;
 		Ok(())
 	}
 
@@ -188,12 +281,15 @@ impl Options {
 		path.push("metadata");
 		let meta = Self::load_metadata(&path)?;
 
-		if let Some(meta) = meta {
+		if let Some(mut meta) = meta {
 			if meta.columns.len() != self.columns.len() {
 				return Err(Error::InvalidConfiguration("Column config mismatch".into()));
 			}
 
 			for c in 0..meta.columns.len() {
+				// Runtime options.
+				meta.columns[c].cache_bytes = self.columns[c].cache_bytes;
+				meta.columns[c].filter_bytes = self.columns[c].filter_bytes;
 				if meta.columns[c] != self.columns[c] {
 					return Err(Error::InvalidConfiguration(format!(
 								"Column config mismatch for column {}. Expected \"{}\", got \"{}\"",
@@ -238,7 +334,7 @@ impl Options {
 					s.copy_from_slice(&salt_slice);
 					salt = Some(s);
 			} else if k.starts_with("col") {
-				let col = ColumnOptions::from_string(v).ok_or_else(|| Error::Corruption("Bad column metadata".into()))?;
+				let col = ColumnOptions::from_string(v)?;
 				columns.push(col);
 			}
 		}
@@ -16,8 +16,9 @@
 
 /// Database statistics.
 
-use std::sync::atomic::{AtomicU64, AtomicU32, AtomicI64, Ordering};
+use std::sync::{Arc, atomic::{AtomicU64, AtomicU32, AtomicI64, Ordering}};
 use std::mem::MaybeUninit;
+use std::time::Duration;
 use std::io::{Read, Write, Cursor};
 use crate::{error::Result, column::ColId, table::SIZE_TIERS};
 
@@ -26,7 +27,129 @@ use crate::{error::Result, column::ColId, table::SIZE_TIERS};
 const HISTOGRAM_BUCKETS: usize = 1024;
 const HISTOGRAM_BUCKET_BITS: u8 = 5;
 
-pub const TOTAL_SIZE: usize = 4 * HISTOGRAM_BUCKETS + 8 * HISTOGRAM_BUCKETS + 8 * SIZE_TIERS + 8 * 11;
+// Bucket `i` counts durations of `2^(i - 1) .. 2^i` microseconds, the last one anything longer.
+pub const LATENCY_BUCKETS: usize = 32;
+const LATENCY_HISTOGRAMS: usize = 5;
+
+pub const TOTAL_SIZE: usize = 4 * HISTOGRAM_BUCKETS + 8 * HISTOGRAM_BUCKETS + 8 * SIZE_TIERS + 8 * 11
+	+ 8 * LATENCY_BUCKETS * LATENCY_HISTOGRAMS + 8 * 2;
+
+/// Copy of the column statistics, returned by `Db::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ColumnStatsSnapshot {
+	/// Number of values per size bucket. Bucket `i` counts values of `i * 32 .. (i + 1) * 32` bytes.
+	pub value_histogram: Vec<u32>,
+	/// Number of queries that found the value, per size tier.
+	pub query_histogram: Vec<u64>,
+	/// Number and total size of values that are too large for the value histogram.
+	pub oversized: u64,
+	pub oversized_bytes: u64,
+	pub total_values: u64,
+	/// Stored bytes, after compression.
+	pub total_bytes: u64,
+	pub commits: u64,
+	pub inserted_new: u64,
+	pub inserted_overwrite: u64,
+	pub removed_hit: u64,
+	pub removed_miss: u64,
+	pub queries_miss: u64,
+	pub uncompressed_bytes: u64,
+	/// Bytes saved by compression, per value histogram bucket.
+	pub compression_delta: Vec<i64>,
+	/// Latency histograms. Bucket `i` counts operations that took `2^(i - 1) .. 2^i` microseconds,
+	/// the last bucket counts anything longer.
+	///
+	/// `Db::get` calls.
+	pub get_latency: Vec<u64>,
+	/// From queueing a commit until it is written to the log.
+	pub logged_latency: Vec<u64>,
+	/// From writing a commit to the log until the log is flushed to disk.
+	pub durable_latency: Vec<u64>,
+	/// From flushing a commit to disk until it is enacted into the tables.
+	pub enacted_latency: Vec<u64>,
+	/// Value table file reads.
+	pub read_latency: Vec<u64>,
+	/// Value cache lookups, see `ColumnOptions::cache_bytes`.
+	pub cache_hits: u64,
+	pub cache_misses: u64,
+	/// Write pipeline state of the database.
+	pub pipeline: PipelineStats,
+}
+
+/// Write pipeline state.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PipelineStats {
+	/// Size of keys and values in the commit queue.
+	pub commit_queue_bytes: usize,
+	/// Size of log records that are not enacted yet.
+	pub log_queue_bytes: i64,
+	/// Last log record enacted into the tables.
+	pub last_enacted_record: u64,
+}
+
+/// Timed operation.
+#[derive(Clone, Copy, Debug)]
+pub enum Latency {
+	Get,
+	// Commit pipeline stages.
+	Logged,
+	Durable,
+	Enacted,
+}
+
+pub struct LatencyHistogram([AtomicU64; LATENCY_BUCKETS]);
+
+impl LatencyHistogram {
+	fn empty() -> LatencyHistogram {
+		LatencyHistogram(unsafe { std::mem::transmute::<[u64; LATENCY_BUCKETS], [AtomicU64; LATENCY_BUCKETS]>([0u64; LATENCY_BUCKETS]) })
+	}
+
+	fn from_cursor(cursor: &mut Cursor<&[u8]>) -> LatencyHistogram {
+		let mut histogram = Self::empty();
+		for n in 0 .. LATENCY_BUCKETS {
+			histogram.0[n] = read_u64(cursor);
+		}
+		histogram
+	}
+
+	fn to_cursor(&self, cursor: &mut Cursor<&mut [u8]>) {
+		for n in 0 .. LATENCY_BUCKETS {
+			write_u64(cursor, &self.0[n]);
+		}
+	}
+
+	pub fn record(&self, elapsed: Duration) {
+		let micros = elapsed.as_micros() as u64;
+		let bucket = std::cmp::min((64 - micros.leading_zeros()) as usize, LATENCY_BUCKETS - 1);
+		self.0[bucket].fetch_add(1, Ordering::Relaxed);
+	}
+
+	fn snapshot(&self) -> Vec<u64> {
+		self.0.iter().map(|v| v.load(Ordering::Relaxed)).collect()
+	}
+
+	fn clear(&self) {
+		for v in self.0.iter() {
+			v.store(0, Ordering::Relaxed);
+		}
+	}
+
+	fn write(&self, writer: &mut impl std::io::Write, name: &str) -> Result<()> {
+		writeln!(writer, "{} latency:", name)?;
+		for i in 0 .. LATENCY_BUCKETS {
+			let count = self.0[i].load(Ordering::Relaxed);
+			if count != 0 {
+				let from = if i == 0 { 0 } else { 1u64 << (i - 1) };
+				if i == LATENCY_BUCKETS - 1 {
+					writeln!(writer, "    {}us-: {}", from, count)?;
+				} else {
+					writeln!(writer, "    {}-{}us: {}", from, (1u64 << i) - 1, count)?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
 
 pub struct ColumnStats {
 	value_histogram: [AtomicU32; HISTOGRAM_BUCKETS],
@@ -43,6 +166,14 @@ pub struct ColumnStats {
 	queries_miss: AtomicU64,
 	uncompressed_bytes: AtomicU64,
 	compression_delta: [AtomicI64; HISTOGRAM_BUCKETS],
+	get_latency: LatencyHistogram,
+	logged_latency: LatencyHistogram,
+	durable_latency: LatencyHistogram,
+	enacted_latency: LatencyHistogram,
+	// Shared with the value tables.
+	read_latency: Arc<LatencyHistogram>,
+	cache_hits: AtomicU64,
+	cache_misses: AtomicU64,
 }
 
 fn read_u32(cursor: &mut Cursor<&[u8]>) -> AtomicU32 {
@@ -110,10 +241,24 @@ impl ColumnStats {
 			queries_miss: read_u64(&mut cursor),
 			uncompressed_bytes: read_u64(&mut cursor),
 			compression_delta: unsafe { MaybeUninit::uninit().assume_init() },
+			get_latency: LatencyHistogram::empty(),
+			logged_latency: LatencyHistogram::empty(),
+			durable_latency: LatencyHistogram::empty(),
+			enacted_latency: LatencyHistogram::empty(),
+			read_latency: Arc::new(LatencyHistogram::empty()),
+			cache_hits: Default::default(),
+			cache_misses: Default::default(),
 		};
 		for n in 0 .. HISTOGRAM_BUCKETS {
 			stats.compression_delta[n] = read_i64(&mut cursor);
 		}
+		stats.get_latency = LatencyHistogram::from_cursor(&mut cursor);
+		stats.logged_latency = LatencyHistogram::from_cursor(&mut cursor);
+		stats.durable_latency = LatencyHistogram::from_cursor(&mut cursor);
+		stats.enacted_latency = LatencyHistogram::from_cursor(&mut cursor);
+		stats.read_latency = Arc::new(LatencyHistogram::from_cursor(&mut cursor));
+		stats.cache_hits = read_u64(&mut cursor);
+		stats.cache_misses = read_u64(&mut cursor);
 		stats
 	}
 
@@ -135,6 +280,13 @@ impl ColumnStats {
 			queries_miss: Default::default(),
 			uncompressed_bytes: Default::default(),
 			compression_delta: unsafe { std::mem::transmute([0i64; HISTOGRAM_BUCKETS]) },
+			get_latency: LatencyHistogram::empty(),
+			logged_latency: LatencyHistogram::empty(),
+			durable_latency: LatencyHistogram::empty(),
+			enacted_latency: LatencyHistogram::empty(),
+			read_latency: Arc::new(LatencyHistogram::empty()),
+			cache_hits: Default::default(),
+			cache_misses: Default::default(),
 		}
 	}
 
@@ -160,6 +312,13 @@ impl ColumnStats {
 		for n in 0 .. HISTOGRAM_BUCKETS {
 			write_i64(&mut cursor, &self.compression_delta[n]);
 		}
+		self.get_latency.to_cursor(&mut cursor);
+		self.logged_latency.to_cursor(&mut cursor);
+		self.durable_latency.to_cursor(&mut cursor);
+		self.enacted_latency.to_cursor(&mut cursor);
+		self.read_latency.to_cursor(&mut cursor);
+		write_u64(&mut cursor, &self.cache_hits);
+		write_u64(&mut cursor, &self.cache_misses);
 	}
 
 	fn write_stats(&self, writer: &mut impl std::io::Write, col: ColId) -> Result<()> {
@@ -208,10 +367,45 @@ impl ColumnStats {
 				)?;
 			}
 		}
+		self.get_latency.write(writer, "Get")?;
+		self.logged_latency.write(writer, "Commit to logged")?;
+		self.durable_latency.write(writer, "Logged to durable")?;
+		self.enacted_latency.write(writer, "Durable to enacted")?;
+		writeln!(writer, "Cache hits: {}", self.cache_hits.load(Ordering::Relaxed))?;
+		writeln!(writer, "Cache misses: {}", self.cache_misses.load(Ordering::Relaxed))?;
+		self.read_latency.write(writer, "Value table read")?;
 		writeln!(writer, "")?;
 		Ok(())
 	}
 
+	pub fn snapshot(&self, pipeline: PipelineStats) -> ColumnStatsSnapshot {
+		let load = |v: &AtomicU64| v.load(Ordering::Relaxed);
+		ColumnStatsSnapshot {
+			value_histogram: self.value_histogram.iter().map(|v| v.load(Ordering::Relaxed)).collect(),
+			query_histogram: self.query_histogram.iter().map(load).collect(),
+			oversized: load(&self.oversized),
+			oversized_bytes: load(&self.oversized_bytes),
+			total_values: load(&self.total_values),
+			total_bytes: load(&self.total_bytes),
+			commits: load(&self.commits),
+			inserted_new: load(&self.inserted_new),
+			inserted_overwrite: load(&self.inserted_overwrite),
+			removed_hit: load(&self.removed_hit),
+			removed_miss: load(&self.removed_miss),
+			queries_miss: load(&self.queries_miss),
+			uncompressed_bytes: load(&self.uncompressed_bytes),
+			compression_delta: self.compression_delta.iter().map(|v| v.load(Ordering::Relaxed)).collect(),
+			get_latency: self.get_latency.snapshot(),
+			logged_latency: self.logged_latency.snapshot(),
+			durable_latency: self.durable_latency.snapshot(),
+			enacted_latency: self.enacted_latency.snapshot(),
+			read_latency: self.read_latency.snapshot(),
+			cache_hits: load(&self.cache_hits),
+			cache_misses: load(&self.cache_misses),
+			pipeline,
+		}
+	}
+
 	pub fn write_summary(&self, writer: &mut impl std::io::Write, col: ColId) {
 		let _ = self.write_stats(writer, col);
 	}
@@ -273,4 +467,63 @@ impl ColumnStats {
 	pub fn commit(&self) {
 		self.commits.fetch_add(1, Ordering::Relaxed);
 	}
+
+	pub fn latency(&self, latency: Latency, elapsed: Duration) {
+		match latency {
+			Latency::Get => &self.get_latency,
+			Latency::Logged => &self.logged_latency,
+			Latency::Durable => &self.durable_latency,
+			Latency::Enacted => &self.enacted_latency,
+		}.record(elapsed);
+	}
+
+	pub fn cache_hit(&self) {
+		self.cache_hits.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn cache_miss(&self) {
+		self.cache_misses.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub fn read_latency(&self) -> Arc<LatencyHistogram> {
+		self.read_latency.clone()
+	}
+
+	pub fn clear(&self) {
+		for v in self.value_histogram.iter() {
+			v.store(0, Ordering::Relaxed);
+		}
+		for v in self.query_histogram.iter() {
+			v.store(0, Ordering::Relaxed);
+		}
+		for v in self.compression_delta.iter() {
+			v.store(0, Ordering::Relaxed);
+		}
+		for h in [
+			&self.get_latency,
+			&self.logged_latency,
+			&self.durable_latency,
+			&self.enacted_latency,
+			&*self.read_latency,
+		] {
+			h.clear();
+		}
+		for v in [
+			&self.oversized,
+			&self.oversized_bytes,
+			&self.total_values,
+			&self.total_bytes,
+			&self.commits,
+			&self.inserted_new,
+			&self.inserted_overwrite,
+			&self.removed_hit,
+			&self.removed_miss,
+			&self.queries_miss,
+			&self.uncompressed_bytes,
+			&self.cache_hits,
+			&self.cache_misses,
+		] {
+			v.store(0, Ordering::Relaxed);
+		}
+	}
 }
@@ -0,0 +1,226 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::{Arc, mpsc::{channel, sync_channel, Receiver, Sender, SyncSender, TrySendError}};
+use crate::{
+	column::ColId,
+	table::Key,
+	transaction::Operation,
+};
+
+/// A single change in a committed changeset.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+	/// Operation on a key.
+	Key {
+		col: ColId,
+		/// Hashed key, as stored in the index.
+		hash: Key,
+		/// Original key. Only known for commits made with `Db::commit` and `Db::commit_transaction`,
+		/// and for btree columns.
+		key: Option<Vec<u8>>,
+		op: Operation,
+	},
+	/// Value set with `Transaction::set_reader`. Streamed values are not delivered, only their size.
+	Stream {
+		col: ColId,
+		/// Hashed key, as stored in the index.
+		hash: Key,
+		/// Original key. Only known for commits made with `Db::commit_transaction`.
+		key: Option<Vec<u8>>,
+		len: u32,
+	},
+	/// All keys in the column were removed with `Db::clear_column`.
+	ClearColumn(ColId),
+}
+
+impl Change {
+	pub fn col(&self) -> ColId {
+		match self {
+			Change::Key { col, .. } => *col,
+			Change::Stream { col, .. } => *col,
+			Change::ClearColumn(col) => *col,
+		}
+	}
+}
+
+/// Changes of a single commit, delivered to subscribers in commit order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeSet {
+	/// Commit id, as returned by `CommitHandle::id`.
+	pub commit_id: u64,
+	pub changes: Vec<Change>,
+}
+
+/// When changes are delivered to a subscriber.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChangeStage {
+	/// Once the commit is accepted into the commit queue. Commits with conditions are delivered
+	/// once the conditions are checked, rejected commits are not delivered.
+	#[default]
+	Queued,
+	/// Once the commit is enacted into the tables.
+	Enacted,
+}
+
+/// Selects the changes delivered by `Db::subscribe`.
+#[derive(Clone, Debug, Default)]
+pub struct ChangeFilter {
+	/// Only deliver changes to these columns. All columns if `None`.
+	pub columns: Option<Vec<ColId>>,
+	pub stage: ChangeStage,
+	/// Maximum number of changesets waiting in the channel, at least 1. A subscriber that falls
+	/// further behind is disconnected: the receiver returns the queued changesets and then an error.
+	/// Unbounded if `None`.
+	pub capacity: Option<usize>,
+}
+
+impl ChangeFilter {
+	fn accepts(&self, col: ColId) -> bool {
+		self.columns.as_ref().is_none_or(|columns| columns.contains(&col))
+	}
+}
+
+enum ChangeSender {
+	Unbounded(Sender<ChangeSet>),
+	Bounded(SyncSender<ChangeSet>),
+}
+
+struct Subscriber {
+	filter: ChangeFilter,
+	sender: ChangeSender,
+}
+
+impl Subscriber {
+	// Returns `false` if the receiver is dropped or has fallen behind.
+	fn send(&self, commit_id: u64, changes: &[Change]) -> bool {
+		let changes: Vec<Change> = changes.iter().filter(|c| self.filter.accepts(c.col())).cloned().collect();
+		if changes.is_empty() {
+			return true;
+		}
+		let changeset = ChangeSet { commit_id, changes };
+		match &self.sender {
+			ChangeSender::Unbounded(sender) => sender.send(changeset).is_ok(),
+			ChangeSender::Bounded(sender) => match sender.try_send(changeset) {
+				Ok(()) => true,
+				Err(TrySendError::Full(_)) => {
+					log::debug!(target: "parity-db", "Disconnected subscriber at commit {}", commit_id);
+					false
+				},
+				Err(TrySendError::Disconnected(_)) => false,
+			},
+		}
+	}
+}
+
+#[derive(Default)]
+pub(crate) struct Subscribers {
+	queued: Vec<Subscriber>,
+	enacted: Vec<Subscriber>,
+	// Accepted commits that are not enacted yet, if there are any `Enacted` subscribers.
+	pending: VecDeque<(u64, Arc<Vec<Change>>)>,
+	// Queued commits with conditions or streamed values that are not accepted or rejected yet.
+	deferred: BTreeSet<u64>,
+	// Accepted commits queued after a deferred commit, delivered once it is resolved.
+	waiting: BTreeMap<u64, Vec<Change>>,
+}
+
+impl Subscribers {
+	pub fn is_empty(&self) -> bool {
+		self.queued.is_empty() && self.enacted.is_empty()
+	}
+
+	// Drop the commits that were not enacted.
+	pub fn reset(&mut self) {
+		self.pending.clear();
+		self.deferred.clear();
+		self.waiting.clear();
+	}
+
+	// Returns `true` if any subscriber accepts changes to the column.
+	pub fn is_subscribed(&self, col: ColId) -> bool {
+		self.queued.iter().chain(self.enacted.iter()).any(|s| s.filter.accepts(col))
+	}
+
+	pub fn add(&mut self, filter: ChangeFilter) -> Receiver<ChangeSet> {
+		let (sender, receiver) = match filter.capacity {
+			Some(capacity) => {
+				let (sender, receiver) = sync_channel(capacity.max(1));
+				(ChangeSender::Bounded(sender), receiver)
+			},
+			None => {
+				let (sender, receiver) = channel();
+				(ChangeSender::Unbounded(sender), receiver)
+			},
+		};
+		let subscribers = match filter.stage {
+			ChangeStage::Queued => &mut self.queued,
+			ChangeStage::Enacted => &mut self.enacted,
+		};
+		subscribers.push(Subscriber { filter, sender });
+		receiver
+	}
+
+	// The commit is accepted or skipped later. Commits accepted meanwhile are delivered after it.
+	pub fn defer(&mut self, commit_id: u64) {
+		self.deferred.insert(commit_id);
+	}
+
+	// Must be called in commit order, except for deferred commits.
+	pub fn accepted(&mut self, commit_id: u64, changes: Vec<Change>) {
+		self.deferred.remove(&commit_id);
+		self.waiting.insert(commit_id, changes);
+		self.deliver();
+	}
+
+	// The commit is rejected or not published.
+	pub fn skip(&mut self, commit_id: u64) {
+		if self.deferred.remove(&commit_id) {
+			self.deliver();
+		}
+	}
+
+	// Deliver accepted commits in commit order, up to the first deferred one.
+	fn deliver(&mut self) {
+		let first_deferred = self.deferred.first().cloned();
+		while let Some(entry) = self.waiting.first_entry() {
+			if first_deferred.is_some_and(|id| *entry.key() > id) {
+				break;
+			}
+			let (commit_id, changes) = entry.remove_entry();
+			self.queued.retain(|s| s.send(commit_id, &changes));
+			if !self.enacted.is_empty() {
+				// Only keep the changes that are delivered.
+				let enacted = &self.enacted;
+				let changes: Vec<Change> = changes.into_iter()
+					.filter(|c| enacted.iter().any(|s| s.filter.accepts(c.col())))
+					.collect();
+				self.pending.push_back((commit_id, Arc::new(changes)));
+			}
+		}
+	}
+
+	pub fn enacted(&mut self, enacted: u64) {
+		while let Some((commit_id, changes)) = self.pending.front().cloned() {
+			if commit_id > enacted {
+				break;
+			}
+			self.pending.pop_front();
+			self.enacted.retain(|s| s.send(commit_id, &changes));
+		}
+	}
+}
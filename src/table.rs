@@ -32,7 +32,7 @@
 //
 // Partial entry (first part):
 // [MULTIHEAD: 2][NEXT: 8][REFS: 4][KEY: 26][VALUE]
-// MULTIHEAD - Split entry head marker. 0xfffd.
+// MULTIHEAD - Split entry head marker. 0xfffd, or 0xfffc for compressed values since version 6.
 // NEXT - 64-bit index of the entry that holds the next part.
 // take all available space in this entry.
 // REF: 32-bit reference counter (optional).
@@ -69,6 +69,7 @@ use crate::{
 	log::{LogQuery, LogReader, LogWriter},
 	display::hex,
 	options::ColumnOptions as Options,
+	stats::LatencyHistogram,
 };
 
 pub const KEY_LEN: usize = 32;
@@ -88,6 +89,7 @@ const MULTIPART_V4: &[u8] = &[0xff, 0xfe];
 const MULTIHEAD_V4: &[u8] = &[0xff, 0xfd];
 const MULTIPART: &[u8] = &[0xfe, 0xff];
 const MULTIHEAD: &[u8] = &[0xfd, 0xff];
+const MULTIHEAD_COMPRESSED: &[u8] = &[0xfc, 0xff];
 // When a rc reach locked ref, it is locked in db.
 const LOCKED_REF: u32 = u32::MAX;
 
@@ -151,6 +153,8 @@ pub struct ValueTable {
 	multipart: bool,
 	ref_counted: bool,
 	db_version: u32,
+	// Set when collecting stats.
+	read_latency: Option<Arc<LatencyHistogram>>,
 }
 
 #[cfg(target_os = "linux")]
@@ -224,6 +228,7 @@ struct Entry<B: AsRef<[u8]> + AsMut<[u8]>>(usize, B);
 type FullEntry = Entry<[u8; MAX_ENTRY_BUF_SIZE]>;
 type PartialEntry = Entry<[u8; 10]>;
 type PartialKeyEntry = Entry<[u8; 40]>;
+type HeadEntry = Entry<[u8; 48]>;
 
 impl<B: AsRef<[u8]> + AsMut<[u8]>> Entry<B> {
 	#[inline(always)]
@@ -272,15 +277,23 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> Entry<B> {
 	}
 
 	fn is_multihead(&self) -> bool {
-		&self.1.as_ref()[0..SIZE_SIZE] == MULTIHEAD
+		&self.1.as_ref()[0..SIZE_SIZE] == MULTIHEAD || self.is_multihead_compressed()
+	}
+
+	fn is_multihead_compressed(&self) -> bool {
+		&self.1.as_ref()[0..SIZE_SIZE] == MULTIHEAD_COMPRESSED
 	}
 
 	fn is_multihead_v4(&self) -> bool {
 		&self.1.as_ref()[0..SIZE_SIZE] == MULTIHEAD_V4
 	}
 
-	fn write_multihead(&mut self) {
-		self.write_slice(&MULTIHEAD);
+	fn write_multihead(&mut self, compressed: bool) {
+		if compressed {
+			self.write_slice(MULTIHEAD_COMPRESSED);
+		} else {
+			self.write_slice(MULTIHEAD);
+		}
 	}
 
 	fn is_multi(&self, db_version: u32) -> bool {
@@ -364,6 +377,62 @@ impl<B: AsRef<[u8]> + AsMut<[u8]>> std::ops::IndexMut<std::ops::Range<usize>> fo
 	}
 }
 
+// Source of value bytes for `overwrite_chain`.
+pub trait ValueSource {
+	// Number of bytes left to read.
+	fn remaining(&self) -> usize;
+	// Fill `dest` with the next bytes of the value.
+	fn read(&mut self, dest: &mut [u8]) -> Result<()>;
+}
+
+impl ValueSource for &[u8] {
+	fn remaining(&self) -> usize {
+		self.len()
+	}
+
+	fn read(&mut self, dest: &mut [u8]) -> Result<()> {
+		let (head, tail) = self.split_at(dest.len());
+		dest.copy_from_slice(head);
+		*self = tail;
+		Ok(())
+	}
+}
+
+/// Value of a known size that is pulled from a reader as it is written into the table entries,
+/// so the value is never held in memory.
+pub struct ValueStream {
+	len: usize,
+	reader: Box<dyn Read + Send>,
+}
+
+impl ValueStream {
+	pub fn new(len: u32, reader: impl Read + Send + 'static) -> ValueStream {
+		ValueStream { len: len as usize, reader: Box::new(reader) }
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+}
+
+impl std::fmt::Debug for ValueStream {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(f, "ValueStream({} bytes)", self.len)
+	}
+}
+
+impl ValueSource for ValueStream {
+	fn remaining(&self) -> usize {
+		self.len
+	}
+
+	fn read(&mut self, dest: &mut [u8]) -> Result<()> {
+		self.reader.read_exact(dest)?;
+		self.len -= dest.len();
+		Ok(())
+	}
+}
+
 impl ValueTable {
 	pub fn open(
 		path: Arc<std::path::PathBuf>,
@@ -371,6 +440,7 @@ impl ValueTable {
 		entry_size: Option<u16>,
 		options: &Options,
 		db_version: u32,
+		read_latency: Option<Arc<LatencyHistogram>>,
 	) -> Result<ValueTable> {
 		let (multipart, entry_size) = match entry_size {
 			Some(s) => (false, s),
@@ -426,6 +496,40 @@ impl ValueTable {
 			multipart,
 			ref_counted: options.ref_counted,
 			db_version,
+			read_latency,
+		})
+	}
+
+	/// Remove all entries. The file is deleted and created again on the next write.
+	// The table is emptied before the file is deleted, so it may be cleared again if deleting fails.
+	pub fn clear(&self) -> Result<()> {
+		let mut file = self.file.write();
+		file.take();
+		self.capacity.store(1, Ordering::Relaxed);
+		self.filled.store(1, Ordering::Relaxed);
+		self.last_removed.store(0, Ordering::Relaxed);
+		self.dirty_header.store(false, Ordering::Relaxed);
+		self.dirty.store(false, Ordering::Relaxed);
+		let mut path = std::path::PathBuf::clone(&*self.path);
+		path.push(self.id.file_name());
+		match std::fs::remove_file(path.as_path()) {
+			// The file is only created on the first write.
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+			r => r?,
+		}
+		log::debug!(target: "parity-db", "{}: Cleared value table", self.id);
+		Ok(())
+	}
+
+	#[cfg(feature = "metrics")]
+	pub fn metrics(&self) -> Option<crate::metrics::TableMetrics> {
+		let file_bytes = self.file.read().as_ref()?.metadata().map_or(0, |m| m.len());
+		Some(crate::metrics::TableMetrics {
+			size_tier: self.id.size_tier(),
+			entry_size: self.entry_size,
+			filled: self.filled.load(Ordering::Relaxed),
+			capacity: self.capacity.load(Ordering::Relaxed),
+			file_bytes,
 		})
 	}
 
@@ -442,8 +546,20 @@ impl ValueTable {
 		self.entry_size - SIZE_SIZE as u16 - self.ref_size() as u16 - PARTIAL_SIZE as u16
 	}
 
-	#[cfg(unix)]
 	fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+		match &self.read_latency {
+			Some(latency) => {
+				let start = std::time::Instant::now();
+				let result = self.read_file_at(buf, offset);
+				latency.record(start.elapsed());
+				result
+			},
+			None => self.read_file_at(buf, offset),
+		}
+	}
+
+	#[cfg(unix)]
+	fn read_file_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
 		use std::os::unix::fs::FileExt;
 		Ok(self.file.read().as_ref().unwrap().read_exact_at(buf, offset)?)
 	}
@@ -462,7 +578,7 @@ impl ValueTable {
 	}
 
 	#[cfg(windows)]
-	fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+	fn read_file_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
 		use std::os::windows::fs::FileExt;
 		self.file.read().as_ref().unwrap().seek_read(buf, offset)?;
 		Ok(())
@@ -573,10 +689,14 @@ impl ValueTable {
 	}
 
 	pub fn get(&self, key: &Key, index: u64, log: &impl LogQuery) -> Result<Option<(Value, bool)>> {
+		Ok(self.get_with_rc(key, index, log)?.map(|(value, _, compressed)| (value, compressed)))
+	}
+
+	pub fn get_with_rc(&self, key: &Key, index: u64, log: &impl LogQuery) -> Result<Option<(Value, u32, bool)>> {
 		let mut result = Vec::new();
 		let (rc, _, compressed) = self.for_parts(Some(key), index, log, |buf| result.extend_from_slice(buf))?;
 		if rc > 0 {
-			return Ok(Some((result, compressed)));
+			return Ok(Some((result, rc, compressed)));
 		}
 		Ok(None)
 	}
@@ -591,16 +711,171 @@ impl ValueTable {
 	}
 
 
-	pub fn size(&self, key: &Key, index: u64, log: &impl LogQuery) -> Result<Option<(u32, bool)>> {
-		let mut result = 0;
-		let (rc, _, compressed) = self.for_parts(Some(key), index, log, |buf| result += buf.len() as u32)? ;
-		if rc > 0 {
-			return Ok(Some((result, compressed)));
+	// Read the header of the entry part at `index` into `buf`.
+	// Return the end of the payload in the entry, the next part index and if the value is compressed.
+	fn read_head(&self, index: u64, log: &impl LogQuery, buf: &mut HeadEntry) -> Result<Option<(usize, u64, bool)>> {
+		let entry_size = self.entry_size as usize;
+		let read_size = buf.as_ref().len().min(entry_size);
+		if !log.value(self.id, index, &mut buf.as_mut()[0..read_size]) {
+			self.read_at(&mut buf.as_mut()[0..read_size], index * self.entry_size as u64)?;
 		}
-		Ok(None)
+		buf.set_offset(0);
+
+		if buf.is_tombstone() {
+			return Ok(None);
+		}
+
+		Ok(Some(if self.multipart && buf.is_multi(self.db_version) {
+			let compressed = buf.is_multihead_compressed();
+			buf.skip_size();
+			let next = buf.read_next();
+			(entry_size, next, compressed)
+		} else {
+			let (size, compressed) = buf.read_size();
+			(buf.offset() + size as usize, 0, compressed)
+		}))
 	}
 
-	pub fn has_key_at(&self, index: u64, key: &Key, log: &LogWriter) -> Result<bool> {
+	// Check reference counter and partial key in the first part header.
+	fn check_first_part<B: AsRef<[u8]> + AsMut<[u8]>>(&self, key: &Key, index: u64, buf: &mut Entry<B>) -> bool {
+		if self.ref_counted && buf.read_rc() == 0 {
+			return false;
+		}
+		if buf.read_partial() != partial_key(key) {
+			log::debug!(
+				target: "parity-db",
+				"{}: Key mismatch at {}. Expected {}, got {}",
+				self.id,
+				index,
+				hex(partial_key(key)),
+				hex(&buf[buf.offset() - PARTIAL_SIZE..buf.offset()]),
+			);
+			return false;
+		}
+		true
+	}
+
+	// Return stored value size and if it was compressed. Only entry headers are read.
+	// `head` is filled with the first bytes of the stored value.
+	pub fn size(
+		&self,
+		key: &Key,
+		mut index: u64,
+		log: &impl LogQuery,
+		head: &mut [u8],
+	) -> Result<Option<(u32, bool)>> {
+		let mut buf = HeadEntry::new_uninit();
+		let read_size = buf.as_ref().len().min(self.entry_size as usize);
+		let mut part = 0;
+		let mut size = 0;
+		loop {
+			let (entry_end, next, compressed) = match self.read_head(index, log, &mut buf)? {
+				Some(head) => head,
+				None => return Ok(None),
+			};
+			if part == 0 {
+				if !self.check_first_part(key, index, &mut buf) {
+					return Ok(None);
+				}
+				let start = buf.offset();
+				let len = head.len().min(entry_end.min(read_size).saturating_sub(start));
+				head[0..len].copy_from_slice(&buf[start..start + len]);
+			}
+			size += (entry_end - buf.offset()) as u32;
+			if next == 0 {
+				return Ok(Some((size, compressed)));
+			}
+			part += 1;
+			index = next;
+		}
+	}
+
+	// Read a byte range of the stored value. Only the parts that cover the range are read in full.
+	// Compressed values can't be sliced, so they are returned whole along with the compression flag.
+	pub fn get_range(
+		&self,
+		key: &Key,
+		index: u64,
+		log: &impl LogQuery,
+		range: std::ops::Range<usize>,
+	) -> Result<Option<(Value, bool)>> {
+		let mut head = HeadEntry::new_uninit();
+		let mut buf = FullEntry::new_uninit();
+		let entry_size = self.entry_size as usize;
+		let read_size = head.as_ref().len().min(entry_size);
+		// Before version 6 compression is only indicated in the last part.
+		let early_exit = self.db_version >= 6;
+		let mut result = Vec::new();
+		let mut part_index = index;
+		let mut part = 0;
+		let mut position = 0;
+		loop {
+			let (entry_end, next, compressed) = match self.read_head(part_index, log, &mut head)? {
+				Some(head) => head,
+				None => return Ok(None),
+			};
+			if part == 0 && !self.check_first_part(key, part_index, &mut head) {
+				return Ok(None);
+			}
+			if compressed {
+				return self.get(key, index, log);
+			}
+			let start = head.offset();
+			let end = position + entry_end - start;
+			let from = range.start.max(position);
+			let to = range.end.min(end);
+			if from < to {
+				let data = if entry_end <= read_size {
+					&head[start..entry_end]
+				} else {
+					if !log.value(self.id, part_index, &mut buf.as_mut()[0..entry_size]) {
+						self.read_at(&mut buf[0..entry_size], part_index * self.entry_size as u64)?;
+					}
+					&buf[start..entry_end]
+				};
+				result.extend_from_slice(&data[from - position..to - position]);
+			}
+			position = end;
+			if next == 0 || (early_exit && position >= range.end) {
+				return Ok(Some((result, false)));
+			}
+			part += 1;
+			part_index = next;
+		}
+	}
+
+	// Read a single part of the value at `index` into `dest`. The key is only checked for the first part.
+	// Return the index of the next part, or zero for the last part.
+	pub fn read_part(&self, key: Option<&Key>, index: u64, log: &impl LogQuery, dest: &mut Vec<u8>) -> Result<Option<u64>> {
+		let mut buf = FullEntry::new_uninit();
+		let entry_size = self.entry_size as usize;
+		if !log.value(self.id, index, &mut buf.as_mut()[0..entry_size]) {
+			self.read_at(&mut buf[0..entry_size], index * self.entry_size as u64)?;
+		}
+		buf.set_offset(0);
+
+		if buf.is_tombstone() {
+			return Ok(None);
+		}
+
+		let (entry_end, next) = if self.multipart && buf.is_multi(self.db_version) {
+			buf.skip_size();
+			let next = buf.read_next();
+			(entry_size, next)
+		} else {
+			let (size, _) = buf.read_size();
+			(buf.offset() + size as usize, 0)
+		};
+		if let Some(key) = key {
+			if !self.check_first_part(key, index, &mut buf) {
+				return Ok(None);
+			}
+		}
+		dest.extend_from_slice(buf.remaining_to(entry_end));
+		Ok(Some(next))
+	}
+
+	pub fn has_key_at(&self, index: u64, key: &Key, log: &impl LogQuery) -> Result<bool> {
 		Ok(match self.partial_key_at(index, log)? {
 			Some(existing_key) => &existing_key[..] == partial_key(key),
 			None => false,
@@ -681,11 +956,11 @@ impl ValueTable {
 		Ok(index)
 	}
 
-	fn overwrite_chain(&self, key: &Key, value: &[u8], log: &mut LogWriter, at: Option<u64>, compressed: bool) -> Result<u64> {
-		let mut remainder = value.len() + self.ref_size() + PARTIAL_SIZE;
+	fn overwrite_chain(&self, key: &Key, value: &mut impl ValueSource, log: &mut LogWriter, at: Option<u64>, compressed: bool) -> Result<u64> {
+		let mut remainder = value.remaining() + self.ref_size() + PARTIAL_SIZE;
 		let mut offset = 0;
 		let mut start = 0;
-		assert!(self.multipart || value.len() <= self.value_size() as usize);
+		assert!(self.multipart || value.remaining() <= self.value_size() as usize);
 		let (mut index, mut follow) = match at {
 			Some(index) => (index, true),
 			None => (self.next_free(log)?, false)
@@ -717,7 +992,7 @@ impl ValueTable {
 					next_index = self.next_free(log)?
 				}
 				if start == 0 {
-					buf.write_multihead();
+					buf.write_multihead(compressed && self.db_version >= 6);
 				} else {
 					buf.write_multipart();
 				}
@@ -736,7 +1011,9 @@ impl ValueTable {
 				buf.write_slice(partial_key(key));
 			}
 			let written = buf.offset() - init_offset;
-			buf.write_slice(&value[offset..offset + value_len - written]);
+			let data_start = buf.offset();
+			value.read(&mut buf[data_start..data_start + value_len - written])?;
+			buf.set_offset(data_start + value_len - written);
 			offset += value_len - written;
 			log.insert_value(self.id, index, buf[0..buf.offset()].to_vec());
 			remainder -= value_len;
@@ -790,12 +1067,21 @@ impl ValueTable {
 		Ok(())
 	}
 
-	pub fn write_insert_plan(&self, key: &Key, value: &[u8], log: &mut LogWriter, compressed: bool) -> Result<u64> {
-		self.overwrite_chain(key, value, log, None, compressed)
+	pub fn write_insert_plan(&self, key: &Key, mut value: &[u8], log: &mut LogWriter, compressed: bool) -> Result<u64> {
+		self.overwrite_chain(key, &mut value, log, None, compressed)
+	}
+
+	pub fn write_replace_plan(&self, index: u64, key: &Key, mut value: &[u8], log: &mut LogWriter, compressed: bool) -> Result<()> {
+		self.overwrite_chain(key, &mut value, log, Some(index), compressed)?;
+		Ok(())
+	}
+
+	pub fn write_insert_stream_plan(&self, key: &Key, value: &mut ValueStream, log: &mut LogWriter) -> Result<u64> {
+		self.overwrite_chain(key, value, log, None, false)
 	}
 
-	pub fn write_replace_plan(&self, index: u64, key: &Key, value: &[u8], log: &mut LogWriter, compressed: bool) -> Result<()> {
-		self.overwrite_chain(key, value, log, Some(index), compressed)?;
+	pub fn write_replace_stream_plan(&self, index: u64, key: &Key, value: &mut ValueStream, log: &mut LogWriter) -> Result<()> {
+		self.overwrite_chain(key, value, log, Some(index), false)?;
 		Ok(())
 	}
 
@@ -1010,7 +1296,7 @@ mod test {
 
 		fn table(&self, size: Option<u16>, options: &ColumnOptions) -> ValueTable {
 			let id = TableId::new(0, 0);
-			ValueTable::open(self.0.clone(), id, size, options, CURRENT_VERSION).unwrap()
+			ValueTable::open(self.0.clone(), id, size, options, CURRENT_VERSION, None).unwrap()
 		}
 
 		fn log(&self) -> Log {
@@ -1039,7 +1325,7 @@ mod test {
 		let mut reader = log.read_next(false).unwrap().unwrap();
 		loop {
 			match reader.next().unwrap() {
-				LogAction::BeginRecord | LogAction::InsertIndex { .. } | LogAction::DropTable { .. } => {
+				LogAction::BeginRecord | LogAction::InsertIndex { .. } | LogAction::DropTable { .. } | LogAction::ClearColumn(_) => {
 					panic!("Unexpected log entry");
 				},
 				LogAction::EndRecord => {
@@ -0,0 +1,148 @@
+// Copyright 2015-2020 Parity Technologies (UK) Ltd.
+// This file is part of Parity.
+
+// Parity is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// Parity is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with Parity.  If not, see <http://www.gnu.org/licenses/>.
+
+use crate::{
+	column::ColId,
+	db::Value,
+	table::ValueStream,
+};
+
+/// Operation on a single key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operation {
+	/// Insert or replace the value. For reference counted columns, inserting an existing
+	/// value increments its reference count.
+	Set(Value),
+	/// Remove the value. For reference counted columns, this decrements the reference count.
+	Delete,
+	/// Increment the reference count of an existing value.
+	Reference,
+	/// Decrement the reference count. The value is removed once the count drops to zero.
+	Dereference,
+}
+
+impl Operation {
+	/// New value, as seen by the readers.
+	pub(crate) fn value(&self) -> Option<&Value> {
+		match self {
+			Operation::Set(value) => Some(value),
+			_ => None,
+		}
+	}
+}
+
+/// Precondition on the current state of a key. See `Transaction::require`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Condition {
+	/// The key must have this value.
+	Equals(Value),
+	/// The key must not exist.
+	Missing,
+	/// The key must have this reference count, with zero meaning that the key does not exist.
+	/// Only allowed for `ref_counted` columns.
+	RefCount(u32),
+}
+
+impl From<Option<Value>> for Operation {
+	fn from(value: Option<Value>) -> Operation {
+		match value {
+			Some(value) => Operation::Set(value),
+			None => Operation::Delete,
+		}
+	}
+}
+
+/// A set of changes that is committed atomically with `Db::commit_transaction`.
+/// All operations are validated when the transaction is committed.
+#[derive(Debug, Default)]
+pub struct Transaction {
+	pub(crate) ops: Vec<(ColId, Vec<u8>, Operation)>,
+	pub(crate) conditions: Vec<(ColId, Vec<u8>, Condition)>,
+	pub(crate) streams: Vec<(ColId, Vec<u8>, ValueStream)>,
+	pub(crate) sync: bool,
+}
+
+impl Transaction {
+	pub fn new() -> Transaction {
+		Default::default()
+	}
+
+	/// Insert or replace a value.
+	pub fn set(self, col: ColId, key: impl AsRef<[u8]>, value: Value) -> Self {
+		self.push(col, key, Operation::Set(value))
+	}
+
+	/// Remove a value.
+	pub fn delete(self, col: ColId, key: impl AsRef<[u8]>) -> Self {
+		self.push(col, key, Operation::Delete)
+	}
+
+	/// Increment the reference count of an existing value. Only allowed for `ref_counted` columns.
+	/// Missing values are ignored.
+	pub fn reference(self, col: ColId, key: impl AsRef<[u8]>) -> Self {
+		self.push(col, key, Operation::Reference)
+	}
+
+	/// Decrement the reference count of a value. Only allowed for `ref_counted` columns.
+	pub fn dereference(self, col: ColId, key: impl AsRef<[u8]>) -> Self {
+		self.push(col, key, Operation::Dereference)
+	}
+
+	/// Insert or replace a value of `len` bytes, reading it from `reader`. The reader is pulled
+	/// by the log worker as the value is written, so the value is never held in memory. A failed
+	/// or short read fails the commit and puts the database in the error state, see `Db::recover`.
+	/// Streamed values are not compressed and become visible to readers once the commit is logged.
+	/// Not allowed for `ref_counted` and `btree_index` columns.
+	pub fn set_reader(mut self, col: ColId, key: impl AsRef<[u8]>, len: u32, reader: impl std::io::Read + Send + 'static) -> Self {
+		self.streams.push((col, key.as_ref().to_vec(), ValueStream::new(len, reader)));
+		self
+	}
+
+	/// Only apply the transaction if the key satisfies the condition when the commit is processed.
+	/// If any condition fails, none of the operations are applied and waiting on the commit handle
+	/// returns `Error::ConditionFailed`.
+	pub fn require(mut self, col: ColId, key: impl AsRef<[u8]>, condition: Condition) -> Self {
+		self.conditions.push((col, key.as_ref().to_vec(), condition));
+		self
+	}
+
+	/// Write the commit to disk as soon as it is processed, instead of waiting for
+	/// more commits to be collected into the same log file.
+	pub fn sync(mut self, sync: bool) -> Self {
+		self.sync = sync;
+		self
+	}
+
+	/// Add an operation.
+	pub fn push(mut self, col: ColId, key: impl AsRef<[u8]>, op: Operation) -> Self {
+		self.ops.push((col, key.as_ref().to_vec(), op));
+		self
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.ops.is_empty() && self.streams.is_empty()
+	}
+
+	pub fn len(&self) -> usize {
+		self.ops.len() + self.streams.len()
+	}
+}
+
+impl<K: AsRef<[u8]>> std::iter::FromIterator<(ColId, K, Option<Value>)> for Transaction {
+	fn from_iter<I: IntoIterator<Item=(ColId, K, Option<Value>)>>(iter: I) -> Transaction {
+		iter.into_iter().fold(Transaction::new(), |transaction, (c, k, v)| transaction.push(c, k, v.into()))
+	}
+}